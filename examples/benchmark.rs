@@ -0,0 +1,16 @@
+//! Measure how fast the soundpack's patterns match every line of a gamelog,
+//! comparing the RegexSet matcher with testing every pattern in turn.
+//!
+//! `cargo run --release --example benchmark -- SOUNDPACK_DIR GAMELOG_FILE [IGNORE_FILE]`
+
+use soundsense_rs::sound::benchmark;
+use std::{env, error::Error, path::PathBuf};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<PathBuf> = env::args_os().skip(1).map(PathBuf::from).collect();
+    match args.as_slice() {
+        [soundpack, gamelog] => benchmark::run(soundpack, gamelog, None),
+        [soundpack, gamelog, ignore] => benchmark::run(soundpack, gamelog, Some(ignore)),
+        _ => Err("Usage: benchmark SOUNDPACK_DIR GAMELOG_FILE [IGNORE_FILE]".into()),
+    }
+}
@@ -1,13 +1,27 @@
-use crate::message::{SoundMessage, Threshold, UIMessage};
+use crate::message::{CacheStats, NowPlaying, SoundMessage, Threshold, ThresholdNames, UIMessage};
+use crate::profile;
+use crate::sound::{Recovery, SoundError};
 use crate::util::StatefulList;
 use crossbeam::channel::{Receiver, Sender};
-use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the sound thread to save the settings when quitting.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+/// Delay before loading the soundpack again when no device could play it.
+/// Doubles with every failed attempt, up to MAX_RETRY_DELAY.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 pub struct Channel {
     pub name: String,
     pub volume: f64,
     pub paused: bool,
+    pub muted: bool,
+    pub soloed: bool,
     pub threshold: Threshold,
+    /// The track played by the channel's loop.
+    pub now_playing: Option<NowPlaying>,
 }
 
 impl Channel {
@@ -16,7 +30,61 @@ impl Channel {
             name,
             volume,
             paused: false,
+            muted: false,
+            soloed: false,
             threshold: Threshold::Everything,
+            now_playing: None,
+        }
+    }
+}
+
+/// The output devices to choose from, while the device picker is open.
+pub struct DevicePicker {
+    /// Device names. The first one stands for the default device of the system.
+    pub names: Vec<String>,
+    pub selected: usize,
+}
+
+/// Name shown for the default output device of the system.
+const DEFAULT_DEVICE: &str = "(default device)";
+
+/// What the path prompt asks for.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PathKind {
+    Gamelog,
+    Soundpack,
+}
+
+/// A path being typed to replace one that failed to load, while the path prompt is open.
+pub struct PathPrompt {
+    pub kind: PathKind,
+    pub text: String,
+}
+
+/// A soundpack to load again once the devices may have changed.
+struct Retry {
+    soundpack: PathBuf,
+    at: Instant,
+}
+
+/// What the user can do about an error of the sound thread.
+fn recovery_hint(error: &SoundError) -> &'static str {
+    match (error.recovery(), error) {
+        (Recovery::NewPath, SoundError::Gamelog { .. }) => {
+            "Type the path of the gamelog, or fix it and press Enter to load it again."
+        }
+        (Recovery::NewPath, SoundError::Soundpack { .. }) => {
+            "Type the path of another soundpack, or fix it and press Enter to load it again."
+        }
+        (Recovery::NewPath, SoundError::Config { .. }) => {
+            "Fix or remove the file, then restart SoundSense-RS."
+        }
+        (Recovery::NewPath, _) => "Convert or replace the file, then restart SoundSense-RS.",
+        (Recovery::Retry, _) => {
+            "Plug in an output device, or press 'd' to choose another one. The soundpack will load once one plays."
+        }
+        (Recovery::Restart, _) => {
+            "The sound thread will restart, and load the soundpack and gamelog again."
         }
     }
 }
@@ -27,6 +95,24 @@ pub struct App {
     ui_rx: Receiver<UIMessage>,
     pub channels: StatefulList<Channel>,
     pub items: Vec<String>,
+    /// Name of the last saved or applied mixer profile.
+    pub profile: Option<String>,
+    /// Profile name being typed, while the save prompt is open.
+    pub prompt: Option<String>,
+    /// Names of the threshold levels in the loaded soundpack.
+    pub threshold_names: ThresholdNames,
+    /// Usage of the sound cache.
+    pub cache_stats: Option<CacheStats>,
+    /// Main output device, or None for the default device.
+    pub output_device: Option<String>,
+    /// The device picker, while it is open.
+    pub device_picker: Option<DevicePicker>,
+    /// The prompt for a path that failed to load, while it is open.
+    pub path_prompt: Option<PathPrompt>,
+    /// The soundpack to load again, when no device could play it.
+    retry: Option<Retry>,
+    /// Delay before the next retry. Reset once a soundpack loads.
+    retry_delay: Duration,
 }
 
 impl App {
@@ -37,14 +123,31 @@ impl App {
             ui_rx,
             channels: StatefulList::new(),
             items: Vec::new(),
+            profile: None,
+            prompt: None,
+            threshold_names: ThresholdNames::default(),
+            cache_stats: None,
+            output_device: None,
+            device_picker: None,
+            path_prompt: None,
+            retry: None,
+            retry_delay: RETRY_DELAY,
         }
     }
 
     pub fn on_up(&mut self) {
+        if let Some(picker) = self.device_picker.as_mut() {
+            picker.selected = picker.selected.saturating_sub(1);
+            return;
+        }
         self.channels.previous()
     }
 
     pub fn on_down(&mut self) {
+        if let Some(picker) = self.device_picker.as_mut() {
+            picker.selected = (picker.selected + 1).min(picker.names.len() - 1);
+            return;
+        }
         self.channels.next()
     }
 
@@ -77,22 +180,180 @@ impl App {
     }
 
     fn save_config(&mut self) {
-        // Save volumes to config file before quitting
-        let mut conf_path = dirs::config_dir().expect("Failed to get configuration directory.");
-        conf_path.push("soundsense-rs");
-        if !conf_path.is_dir() {
-            fs::create_dir(&conf_path).expect("Failed to create soundsense-rs config directory.");
+        // The sound thread waits for the volume to settle before writing the config file.
+        self.sound_tx
+            .send(SoundMessage::SetCurrentVolumesAsDefault)
+            .unwrap();
+    }
+
+    /// Make the sound thread write any unsaved settings, and wait until it's done.
+    /// Called before quitting.
+    pub fn flush_settings(&mut self) {
+        if self.sound_tx.send(SoundMessage::FlushSettings).is_err() {
+            return;
+        }
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match self.ui_rx.recv_timeout(timeout) {
+                Ok(UIMessage::SettingsFlushed) | Err(_) => return,
+                Ok(UIMessage::SettingsSaveFailed(text)) => {
+                    error!("Failed to save settings: {}", text);
+                }
+                Ok(_) => (),
+            }
+        }
+    }
+
+    pub fn on_backspace(&mut self) {
+        if let Some(prompt) = self.prompt.as_mut() {
+            prompt.pop();
+        } else if let Some(prompt) = self.path_prompt.as_mut() {
+            prompt.text.pop();
         }
-        conf_path.push("default-volumes.ini");
-        let conf_file =
-            fs::File::create(conf_path).expect("Failed to create default-volumes.ini file.");
+    }
+
+    pub fn on_escape(&mut self) {
+        self.prompt = None;
+        self.device_picker = None;
+        self.path_prompt = None;
+    }
 
+    /// Handle a key while the device picker is open.
+    fn on_picker_key(&mut self, c: char) {
+        if c != '\n' {
+            return;
+        }
+        if let Some(picker) = self.device_picker.take() {
+            let name = match picker.selected {
+                0 => None,
+                idx => Some(picker.names[idx].as_str().into()),
+            };
+            self.sound_tx
+                .send(SoundMessage::SetOutputDevice(name))
+                .unwrap();
+        }
+    }
+
+    /// Handle a key while the save prompt is open.
+    fn on_prompt_key(&mut self, c: char) {
+        let prompt = match self.prompt.as_mut() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        match c {
+            '\n' => {
+                let name = self.prompt.take().unwrap_or_default();
+                if profile::is_valid_name(&name) {
+                    self.sound_tx
+                        .send(SoundMessage::SaveProfile(name.into()))
+                        .unwrap();
+                } else {
+                    self.items.push(format!(
+                        "\"{}\" is not a valid profile name. Use letters, digits, '_' and '-'.",
+                        name
+                    ));
+                }
+            }
+            c if !c.is_control() => prompt.push(c),
+            _ => (),
+        }
+    }
+
+    /// Handle a key while the path prompt is open.
+    fn on_path_prompt_key(&mut self, c: char) {
+        let prompt = match self.path_prompt.as_mut() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        match c {
+            '\n' => {
+                let PathPrompt { kind, text } = self.path_prompt.take().unwrap();
+                let path = PathBuf::from(text);
+                let message = match kind {
+                    PathKind::Gamelog => SoundMessage::ChangeGamelog(path),
+                    PathKind::Soundpack => {
+                        // The typed soundpack replaces the one waiting for a device.
+                        self.retry = None;
+                        SoundMessage::ChangeSoundpack(path)
+                    }
+                };
+                self.sound_tx.send(message).unwrap();
+            }
+            c if !c.is_control() => prompt.text.push(c),
+            _ => (),
+        }
+    }
+
+    /// Act on an error of the sound thread: ask for a new path, or try again later.
+    fn recover(&mut self, error: &SoundError) {
+        match (error.recovery(), error) {
+            (Recovery::NewPath, SoundError::Gamelog { path, .. }) => {
+                self.open_path_prompt(PathKind::Gamelog, path);
+            }
+            (Recovery::NewPath, SoundError::Soundpack { path, .. }) => {
+                self.open_path_prompt(PathKind::Soundpack, path);
+            }
+            (Recovery::Retry, SoundError::Device { soundpack, .. }) => {
+                // A failed restart reports the same error again, which doesn't need another retry.
+                if self.retry.is_some() {
+                    return;
+                }
+                self.items.push(format!(
+                    "Will load the soundpack again in {} seconds.",
+                    self.retry_delay.as_secs()
+                ));
+                self.retry = Some(Retry {
+                    soundpack: soundpack.clone(),
+                    at: Instant::now() + self.retry_delay,
+                });
+                self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY_DELAY);
+            }
+            _ => (),
+        }
+    }
+
+    /// Ask for a path to replace `path`, unless the user is already typing into a prompt.
+    fn open_path_prompt(&mut self, kind: PathKind, path: &std::path::Path) {
+        if self.prompt.is_none() && self.path_prompt.is_none() {
+            self.device_picker = None;
+            self.path_prompt = Some(PathPrompt {
+                kind,
+                text: path.display().to_string(),
+            });
+        }
+    }
+
+    /// Switch to the saved profile that comes after the current one.
+    fn next_profile(&mut self) {
+        let names = profile::list_profiles();
+        if names.is_empty() {
+            self.items
+                .push("There are no saved profiles. Press 'w' to save one.".to_string());
+            return;
+        }
+        let idx = self
+            .profile
+            .as_ref()
+            .and_then(|current| names.iter().position(|name| name == current))
+            .map_or(0, |idx| (idx + 1) % names.len());
         self.sound_tx
-            .send(SoundMessage::SetCurrentVolumesAsDefault(conf_file))
+            .send(SoundMessage::SwitchProfile(names[idx].as_str().into()))
             .unwrap();
     }
 
     pub fn on_key(&mut self, c: char) {
+        if self.prompt.is_some() {
+            self.on_prompt_key(c);
+            return;
+        }
+        if self.path_prompt.is_some() {
+            self.on_path_prompt_key(c);
+            return;
+        }
+        if self.device_picker.is_some() {
+            self.on_picker_key(c);
+            return;
+        }
         match c {
             'q' => {
                 self.should_quit = true;
@@ -118,8 +379,39 @@ impl App {
                             self.channels.items[i].threshold,
                         ))
                         .unwrap();
+                    self.save_config();
+                }
+            }
+            'm' => {
+                // Mute selected channel
+                if let Some(i) = self.channels.state.selected() {
+                    let channel_name: Box<str> = self.channels.items[i].name.to_string().into();
+                    self.sound_tx
+                        .send(SoundMessage::Mute(channel_name))
+                        .unwrap();
+                }
+            }
+            'o' => {
+                // Solo selected channel
+                if let Some(i) = self.channels.state.selected() {
+                    let channel_name: Box<str> = self.channels.items[i].name.to_string().into();
+                    self.sound_tx
+                        .send(SoundMessage::Solo(channel_name))
+                        .unwrap();
                 }
             }
+            'w' => {
+                // Open the prompt to save the current mix as a profile
+                self.prompt = Some(self.profile.clone().unwrap_or_default());
+            }
+            'p' => {
+                // Switch to the next saved profile
+                self.next_profile();
+            }
+            'd' => {
+                // Open the device picker once the devices are listed
+                self.sound_tx.send(SoundMessage::ListOutputDevices).unwrap();
+            }
             ' ' => {
                 // Pause selected channel
                 if let Some(i) = self.channels.state.selected() {
@@ -134,9 +426,18 @@ impl App {
     }
 
     pub fn update(&mut self) {
-        for ui_message in self.ui_rx.try_iter() {
+        if let Some(retry) = self.retry.take_if(|retry| retry.at <= Instant::now()) {
+            self.sound_tx
+                .send(SoundMessage::ChangeSoundpack(retry.soundpack))
+                .unwrap();
+        }
+        while let Ok(ui_message) = self.ui_rx.try_recv() {
             match ui_message {
                 UIMessage::LoadedSoundpack(channel_names) => {
+                    self.retry = None;
+                    self.retry_delay = RETRY_DELAY;
+                    // The channels of the previous soundpack are gone.
+                    self.channels.items.clear();
                     for channel in &channel_names {
                         let new_channel = Channel::new(channel.to_string(), 0.0);
                         self.channels.items.push(new_channel);
@@ -151,6 +452,21 @@ impl App {
                     );
                     self.items.push(value)
                 }
+                UIMessage::LoadedThresholdNames(names) => {
+                    self.threshold_names = names;
+                }
+                UIMessage::LoadedThresholdSettings(entries) => {
+                    for (name, threshold) in &entries {
+                        if let Some(channel) = self
+                            .channels
+                            .items
+                            .iter_mut()
+                            .find(|x| x.name.as_str() == name.as_ref())
+                        {
+                            channel.threshold = *threshold;
+                        }
+                    }
+                }
                 UIMessage::LoadedVolumeSettings(entries) => {
                     for (name, volume) in &entries {
                         self.items.push(format!("{}: {}", name, volume));
@@ -159,7 +475,7 @@ impl App {
                             .channels
                             .items
                             .iter_mut()
-                            .find(|x| x.name == name.to_string())
+                            .find(|x| x.name.as_str() == name.as_ref())
                         {
                             channel.volume = *volume as f64;
                         }
@@ -178,7 +494,7 @@ impl App {
                         .channels
                         .items
                         .iter()
-                        .find(|&x| x.name == name.to_string())
+                        .find(|&x| x.name.as_str() == name.as_ref())
                     {
                         Some(channel) => format!("Channel {} sound skipped.", channel.name),
                         None => "Channel could not be found when trying to skip sound.".to_string(),
@@ -190,10 +506,10 @@ impl App {
                         .channels
                         .items
                         .iter_mut()
-                        .find(|x| x.name == name.to_string())
+                        .find(|x| x.name.as_str() == name.as_ref())
                     {
                         Some(channel) => {
-                            channel.paused = !channel.paused;
+                            channel.paused = is_paused;
                             format!("Channel {} is paused: {}.", channel.name, is_paused)
                         }
                         None => {
@@ -202,26 +518,168 @@ impl App {
                     };
                     self.items.push(log_message)
                 }
+                UIMessage::ChannelWasMuted(name, is_muted) => {
+                    let log_message = match self
+                        .channels
+                        .items
+                        .iter_mut()
+                        .find(|x| x.name.as_str() == name.as_ref())
+                    {
+                        Some(channel) => {
+                            channel.muted = is_muted;
+                            format!("Channel {} is muted: {}.", channel.name, is_muted)
+                        }
+                        None => {
+                            "Channel could not be found when trying to mute channel.".to_string()
+                        }
+                    };
+                    self.items.push(log_message)
+                }
+                UIMessage::SoloWasChanged(solo) => {
+                    for channel in self.channels.items.iter_mut() {
+                        channel.soloed = solo.as_deref() == Some(channel.name.as_str());
+                    }
+                    let log_message = match solo {
+                        Some(name) => format!("Channel {} is soloed.", name),
+                        None => "No channel is soloed.".to_string(),
+                    };
+                    self.items.push(log_message)
+                }
                 UIMessage::ChannelThresholdWasChanged(name, threshold) => {
                     let log_message = match self
                         .channels
                         .items
                         .iter_mut()
-                        .find(|x| x.name == name.to_string())
+                        .find(|x| x.name.as_str() == name.as_ref())
                     {
-                        Some(channel) => format!(
-                            "Channel {} threshold was changed to {}.",
-                            channel.name, threshold
-                        ),
+                        Some(channel) => {
+                            channel.threshold = threshold;
+                            format!(
+                                "Channel {} threshold was changed to {}.",
+                                channel.name,
+                                self.threshold_names.get(threshold)
+                            )
+                        }
                         None => "Channel could not be found when trying to change threshold."
                             .to_string(),
                     };
                     self.items.push(log_message)
                 }
-                UIMessage::SoundThreadPanicked(name, text) => {
-                    let value = format!("Error: {} {}", &name, &text);
-                    self.items.push(value)
+                UIMessage::SavedProfile(name) => {
+                    self.items
+                        .push(format!("Saved the current mix as profile {}.", name));
+                    self.profile = Some(name.to_string());
+                }
+                UIMessage::SwitchedProfile(name, mixes) => {
+                    for mix in &mixes {
+                        if let Some(channel) = self
+                            .channels
+                            .items
+                            .iter_mut()
+                            .find(|x| x.name.as_str() == mix.name.as_ref())
+                        {
+                            channel.volume = mix.volume as f64;
+                            channel.threshold = mix.threshold;
+                            channel.paused = mix.is_paused;
+                        }
+                    }
+                    self.items.push(format!("Switched to profile {}.", name));
+                    self.profile = Some(name.to_string());
+                }
+                UIMessage::ProfileFailed(name, text) => {
+                    self.items
+                        .push(format!("Error in profile {}: {}", name, text));
+                }
+                UIMessage::SettingsSaveFailed(text) => {
+                    self.items
+                        .push(format!("Error: failed to save settings: {}", text));
+                }
+                UIMessage::SettingsFlushed => (),
+                UIMessage::CacheStatsUpdated(stats) => {
+                    self.cache_stats = Some(stats);
+                }
+                UIMessage::NowPlayingUpdated(name, now_playing) => {
+                    if let Some(channel) = self
+                        .channels
+                        .items
+                        .iter_mut()
+                        .find(|x| x.name.as_str() == name.as_ref())
+                    {
+                        channel.now_playing = now_playing;
+                    }
+                }
+                UIMessage::OutputDevices(devices) => {
+                    let mut names = vec![DEFAULT_DEVICE.to_string()];
+                    names.extend(devices.iter().map(|name| name.to_string()));
+                    let selected = self
+                        .output_device
+                        .as_ref()
+                        .and_then(|current| names.iter().position(|name| name == current))
+                        .unwrap_or(0);
+                    self.device_picker = Some(DevicePicker { names, selected });
+                }
+                UIMessage::OutputDeviceChanged(name) => {
+                    self.output_device = name.map(|name| name.to_string());
+                    self.items.push(format!(
+                        "Playing on {}.",
+                        self.output_device.as_deref().unwrap_or(DEFAULT_DEVICE)
+                    ));
+                }
+                UIMessage::OutputDeviceUnavailable(name) => {
+                    self.items.push(format!(
+                        "Output device {} isn't available. Playing on another device instead.",
+                        name
+                    ));
+                }
+                UIMessage::OutputDeviceLost(name) => {
+                    self.items.push(format!(
+                        "Output device {} was lost. Playing on another device until it returns.",
+                        name
+                    ));
+                }
+                UIMessage::OutputDeviceRecovered(name) => {
+                    self.items
+                        .push(format!("Output device {} is playing again.", name));
+                }
+                UIMessage::UndecodableFiles(files) => {
+                    self.items.push(format!(
+                        "Warning: {} sound files can't be decoded:",
+                        files.len()
+                    ));
+                    for error in files {
+                        self.items.push(format!("  {}", error));
+                    }
+                }
+                UIMessage::UnresolvedPlaylistEntries(entries) => {
+                    self.items.push(format!(
+                        "Warning: {} playlist entries can't be played:",
+                        entries.len()
+                    ));
+                    for error in entries {
+                        self.items.push(format!("  {}", error));
+                    }
+                }
+                UIMessage::SoundThreadPanicked(error) => {
+                    self.items.push(format!("Error: {}", error));
+                    self.items.push(recovery_hint(&error).to_string());
+                    self.recover(&error);
+                }
+                UIMessage::SoundThreadRecovered { soundpack, gamelog } => {
+                    if soundpack.is_none() {
+                        self.channels.items.clear();
+                        self.channels.state.select(None);
+                    }
+                    let loaded = |path: Option<std::path::PathBuf>| {
+                        path.map_or("nothing".to_string(), |path| path.display().to_string())
+                    };
+                    self.items.push(format!(
+                        "Sound thread recovered. Soundpack: {}. Gamelog: {}.",
+                        loaded(soundpack),
+                        loaded(gamelog)
+                    ));
                 }
+                // UIMessage is #[non_exhaustive], so other messages are ignored until the UI handles them.
+                _ => (),
             }
         }
     }
@@ -0,0 +1,13 @@
+//! The sound engine of SoundSense-RS.
+//! Used by the application, and by the examples.
+
+#[macro_use]
+extern crate num_derive;
+extern crate num_traits;
+
+#[macro_use]
+extern crate log;
+
+pub mod message;
+pub mod profile;
+pub mod sound;
@@ -2,23 +2,18 @@
 #![cfg_attr(debug_assertions, windows_subsystem = "console")]
 
 mod app;
-mod message;
-mod sound;
 mod ui;
 mod util;
 
 use app::App;
+use soundsense_rs::{message, profile, sound};
 
-use crate::util::event::{Event, Events};
-
-#[macro_use]
-extern crate num_derive;
-extern crate num_traits;
+use crate::util::event::{Config, Event, Events};
 
 #[macro_use]
 extern crate log;
 use crate::message::SoundMessage;
-use crossbeam::channel::unbounded as channel;
+use crossbeam::channel::{unbounded as channel, Select};
 
 use tui::{backend::TermionBackend, Terminal};
 
@@ -35,7 +30,6 @@ use termion::{event::Key, input::MouseTerminal, raw::IntoRawMode, screen::Altern
 /// All the while the UI thread handles user input and sends SoundMessage to the SoundThread
 /// through a Sender<SoundMessage>, while the Sound thread sends UIMessages to the UI through
 /// a Sender<UIMessage>.
-
 fn main() -> Result<(), Box<dyn Error>> {
     // Setup and initialize the env_logger.
     let env = env_logger::Env::default()
@@ -69,11 +63,41 @@ fn main() -> Result<(), Box<dyn Error>> {
         "Path to the ignore.txt file. (Default: .\\ignore.txt)",
         "IGNORE_FILE",
     )
+    .optopt(
+        "",
+        "profile",
+        "Name of the mixer profile to apply on start.",
+        "PROFILE",
+    )
     .optflag(
         "",
         "no-config",
         "Don't read config files on start. Will use the given paths, or soundsense-rs defaults.",
     )
+    .optopt(
+        "",
+        "stereo-width",
+        "Stereo width in percent. 0 plays every sound in the center. (Default: 100)",
+        "WIDTH",
+    )
+    .optflag(
+        "",
+        "mono",
+        "Mix every sound down to mono, for setups with a single speaker.",
+    )
+    .optopt(
+        "",
+        "cache-size",
+        "Memory for caching sound files, in megabytes. 0 disables the cache. (Default: 64)",
+        "MB",
+    )
+    .optopt(
+        "",
+        "device",
+        "Name of the output device to play on, or a part of it. (Default: the system's default device)",
+        "DEVICE",
+    )
+    .optflag("", "list-devices", "List the available output devices, then quit.")
     .optflag("", "cli", "Use the command line interface.");
 
     // If there are errors in the arguments, print the usage of SoundSense-RS and quit.
@@ -128,7 +152,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             config.as_ref().and_then(|config_txt| {
                 Regex::new("gamelog=(.+)")
                     .unwrap()
-                    .captures(&config_txt)
+                    .captures(config_txt)
                     .and_then(|c| c.get(1))
                     .map(|m| PathBuf::from(m.as_str()))
                     .filter(|p| p.is_file())
@@ -161,7 +185,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             config.as_ref().and_then(|config_txt| {
                 Regex::new("soundpack=(.+)")
                     .unwrap()
-                    .captures(&config_txt)
+                    .captures(config_txt)
                     .and_then(|c| c.get(1))
                     .map(|m| PathBuf::from(m.as_str()))
                     .filter(|p| p.is_dir())
@@ -194,7 +218,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             config.as_ref().and_then(|config_txt| {
                 Regex::new("ignore=(.+)")
                     .unwrap()
-                    .captures(&config_txt)
+                    .captures(config_txt)
                     .and_then(|c| c.get(1))
                     .map(|m| PathBuf::from(m.as_str()))
                     .filter(|p| p.is_file())
@@ -212,13 +236,54 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         });
 
+    if matches.opt_present("list-devices") {
+        let devices = sound::output::list_devices();
+        if devices.is_empty() {
+            println!("No output devices found.");
+        }
+        for name in devices {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
+    let stereo = sound::StereoSetting {
+        width: match matches.opt_str("stereo-width") {
+            Some(width) => (width.parse::<f32>()? / 100.0).clamp(0.0, 1.0),
+            None => 1.0,
+        },
+        mono: matches.opt_present("mono"),
+    };
+
+    // If an output device is given, use it; else use the one in the config file, if any.
+    let device: Option<Box<str>> = matches
+        .opt_str("device")
+        .or_else(|| {
+            config.as_ref().and_then(|config_txt| {
+                Regex::new("device=(.+)")
+                    .unwrap()
+                    .captures(config_txt)
+                    .and_then(|c| c.get(1))
+                    .map(|m| m.as_str().trim().to_string())
+            })
+        })
+        .map(Box::from);
+
+    let cache_size = match matches.opt_str("cache-size") {
+        Some(size) => size.parse::<usize>()? * 1024 * 1024,
+        None => 64 * 1024 * 1024,
+    };
+
     let (sound_tx, sound_rx) = channel();
     let (ui_tx, ui_rx) = channel();
 
     // Build and spawn the Sound thread.
-    std::thread::Builder::new()
+    let sound_thread = std::thread::Builder::new()
         .name("sound_thread".to_string())
-        .spawn(move || sound::run(sound_rx, ui_tx))
+        .spawn({
+            let device = device.clone();
+            move || sound::run(sound_rx, ui_tx, stereo, cache_size, device)
+        })
         .unwrap();
 
     if let Some(path) = &soundpack_path {
@@ -236,6 +301,12 @@ fn main() -> Result<(), Box<dyn Error>> {
             .send(SoundMessage::ChangeIgnoreList(path.clone()))
             .unwrap();
     }
+    let profile = matches.opt_str("profile");
+    if let Some(name) = &profile {
+        sound_tx
+            .send(SoundMessage::SwitchProfile(name.as_str().into()))
+            .unwrap();
+    }
 
     let gamelog_path = Mutex::new(gamelog_path);
     let soundpack_path = Mutex::new(soundpack_path);
@@ -250,6 +321,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     if let Some(path) = ignore_path.lock().unwrap().as_ref() {
         println!("ignore={}", path.to_string_lossy());
     };
+    if let Some(name) = &device {
+        println!("device={}", name);
+    };
 
     // Terminal initialization
     let stdout = io::stdout().into_raw_mode()?;
@@ -259,12 +333,29 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut terminal = Terminal::new(backend)?;
     terminal.hide_cursor()?;
 
-    let mut app = App::new(sound_tx, ui_rx);
-    let events = Events::new();
+    let mut app = App::new(sound_tx, ui_rx.clone());
+    app.profile = profile;
+    // Redraw only when a key is pressed or the sound thread sends a message, instead of ticking.
+    let mut events = Events::with_config(Config {
+        tick_rate: None,
+        ..Config::default()
+    });
+    // 'q' may be typed into a prompt, so the App decides when to quit.
+    events.disable_exit_key();
 
     loop {
         terminal.draw(|mut f| ui::draw(&app, &mut f))?;
 
+        let mut select = Select::new();
+        let input = select.recv(events.receiver());
+        // The channel of a finished sound thread is always ready, so stop waiting on it.
+        if !sound_thread.is_finished() {
+            select.recv(&ui_rx);
+        }
+        if select.ready() != input {
+            app.update();
+            continue;
+        }
         match events.next()? {
             Event::Input(key) => match key {
                 Key::Char(c) => {
@@ -282,6 +373,12 @@ fn main() -> Result<(), Box<dyn Error>> {
                 Key::Right => {
                     app.on_right();
                 }
+                Key::Backspace => {
+                    app.on_backspace();
+                }
+                Key::Esc => {
+                    app.on_escape();
+                }
                 _ => {}
             },
             Event::Tick => {
@@ -292,5 +389,6 @@ fn main() -> Result<(), Box<dyn Error>> {
             break;
         }
     }
+    app.flush_settings();
     Ok(())
 }
@@ -1,6 +1,12 @@
+// `num_derive` 0.3 expands `FromPrimitive` into a non-local impl.
+#![allow(non_local_definitions)]
+
+use crate::sound::SoundError;
 use num_traits::FromPrimitive;
 
-#[derive(Debug, Copy, Clone, FromPrimitive)]
+/// Playback threshold levels.
+/// A sound plays if its level is at most the threshold of its channel and of the total.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, FromPrimitive)]
 pub enum Threshold {
     Nothing = 0,
     Critical = 1,
@@ -10,9 +16,120 @@ pub enum Threshold {
 }
 
 impl Threshold {
+    pub const ALL: [Threshold; 5] = [
+        Threshold::Nothing,
+        Threshold::Critical,
+        Threshold::Important,
+        Threshold::Fluff,
+        Threshold::Everything,
+    ];
+
     pub fn next_threshold(t: Threshold) -> Threshold {
         FromPrimitive::from_u8((t as u8 + 1) % 5).unwrap()
     }
+
+    /// The built-in name, used in config files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Threshold::Nothing => "nothing",
+            Threshold::Critical => "critical",
+            Threshold::Important => "important",
+            Threshold::Fluff => "fluff",
+            Threshold::Everything => "everything",
+        }
+    }
+
+    /// Parse a level from its number or its built-in name.
+    pub fn parse(text: &str) -> Option<Threshold> {
+        let text = text.trim();
+        match text.parse::<u8>() {
+            Ok(level) => Threshold::from_u8(level),
+            Err(_) => Threshold::ALL
+                .iter()
+                .copied()
+                .find(|t| t.name().eq_ignore_ascii_case(text)),
+        }
+    }
+}
+
+/// Display names of the threshold levels. Soundpacks can rename the levels.
+#[derive(Debug, Clone)]
+pub struct ThresholdNames([Box<str>; 5]);
+
+impl Default for ThresholdNames {
+    fn default() -> Self {
+        Self(Threshold::ALL.map(|t| t.name().into()))
+    }
+}
+
+impl ThresholdNames {
+    #[inline]
+    pub fn get(&self, threshold: Threshold) -> &str {
+        &self.0[threshold as usize]
+    }
+
+    #[inline]
+    pub fn set(&mut self, threshold: Threshold, name: &str) {
+        self.0[threshold as usize] = name.into();
+    }
+
+    /// Parse a level from its display name, its built-in name, or its number.
+    pub fn parse(&self, text: &str) -> Option<Threshold> {
+        let text = text.trim();
+        Threshold::ALL
+            .iter()
+            .copied()
+            .find(|&t| self.get(t).eq_ignore_ascii_case(text))
+            .or_else(|| Threshold::parse(text))
+    }
+}
+
+/// The mixer state of a single channel, as stored in a profile.
+/// "total" is the total mix, other values are specific channels.
+#[derive(Debug, Clone)]
+pub struct ChannelMix {
+    pub name: Box<str>,
+    /// Volume, from 0 to 100.
+    pub volume: f32,
+    pub threshold: Threshold,
+    pub is_paused: bool,
+}
+
+impl ChannelMix {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            volume: 100.0,
+            threshold: Threshold::Everything,
+            is_paused: false,
+        }
+    }
+}
+
+/// Usage of the sound cache.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    /// Number of cached files.
+    pub files: usize,
+    /// Memory used by the cached files, in bytes.
+    pub used: usize,
+    /// Memory budget of the cache, in bytes.
+    pub budget: usize,
+    /// Number of times a sound was opened from the cache.
+    pub hits: usize,
+    /// Number of times a sound had to be read from the disk.
+    pub misses: usize,
+}
+
+/// The track played by a channel's loop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NowPlaying {
+    /// Title from the playlist, or else the file name.
+    pub title: Box<str>,
+    /// How much of the track has played.
+    pub position: std::time::Duration,
+    /// Length of the track, if the playlist gives it.
+    pub duration: Option<std::time::Duration>,
 }
 
 /// Messages sent from the UI thread to the Sound thread.
@@ -34,8 +151,26 @@ pub enum SoundMessage {
     SkipCurrentSound(Box<str>),
     /// Play/Pause channel
     PlayPause(Box<str>),
-    /// Store the current channels volumes to a config file.
-    SetCurrentVolumesAsDefault(std::fs::File),
+    /// Mute/Unmute channel. Muted channels keep playing silently.
+    /// "total" mutes all channels.
+    Mute(Box<str>),
+    /// Solo/Unsolo channel, muting all other channels.
+    /// "total" clears the solo.
+    Solo(Box<str>),
+    /// Store the current channels volumes to the config file.
+    /// The write is delayed, so consecutive changes are saved at once.
+    SetCurrentVolumesAsDefault,
+    /// Immediately write any settings that haven't been saved yet.
+    /// Answered with UIMessage::SettingsFlushed.
+    FlushSettings,
+    /// Save the current mix as a named profile.
+    SaveProfile(Box<str>),
+    /// Apply the named profile to the current mix.
+    SwitchProfile(Box<str>),
+    /// List the output devices. Answered with UIMessage::OutputDevices.
+    ListOutputDevices,
+    /// Play on this output device, or on the default device if None.
+    SetOutputDevice(Option<Box<str>>),
 }
 
 /// Message sent from the Sound thread to the UI thread.
@@ -46,16 +181,59 @@ pub enum UIMessage {
     /// The soundpack finished loading.
     /// Contains the names of the loaded channels.
     LoadedSoundpack(Vec<Box<str>>),
+    /// The names of the threshold levels in the loaded soundpack.
+    LoadedThresholdNames(ThresholdNames),
     /// The ignore list finished loading.
     LoadedIgnoreList,
     /// Loaded the default volumes from config.
     LoadedVolumeSettings(Vec<(Box<str>, f32)>),
+    /// Loaded the default thresholds from config.
+    LoadedThresholdSettings(Vec<(Box<str>, Threshold)>),
     /// The Channel IsPause had been set.
     ChannelWasPlayPaused(Box<str>, bool),
+    /// The Channel mute had been set.
+    ChannelWasMuted(Box<str>, bool),
+    /// The soloed channel had been changed. None if no channel is soloed.
+    SoloWasChanged(Option<Box<str>>),
     /// Sound was skipped on channel
     ChannelSoundWasSkipped(Box<str>),
     /// Channel threshold was changed
-    ChannelThresholdWasChanged(Box<str>, u8),
-    /// There was an error in the Sound thread.
-    SoundThreadPanicked(String, String),
+    ChannelThresholdWasChanged(Box<str>, Threshold),
+    /// The current mix was saved as a profile.
+    SavedProfile(Box<str>),
+    /// A profile was applied. Contains the new mix of each channel in the profile.
+    SwitchedProfile(Box<str>, Vec<ChannelMix>),
+    /// A profile couldn't be saved or applied.
+    ProfileFailed(Box<str>, String),
+    /// The settings couldn't be saved.
+    SettingsSaveFailed(String),
+    /// All pending settings were written.
+    SettingsFlushed,
+    /// The usage of the sound cache changed.
+    CacheStatsUpdated(CacheStats),
+    /// The track played by the channel's loop, or None if it stopped.
+    NowPlayingUpdated(Box<str>, Option<NowPlaying>),
+    /// The names of the available output devices.
+    OutputDevices(Vec<Box<str>>),
+    /// The main output device changed. None is the default device.
+    OutputDeviceChanged(Option<Box<str>>),
+    /// An output device isn't available, so the default or main device is used instead.
+    OutputDeviceUnavailable(Box<str>),
+    /// An output device stopped playing, as when it was unplugged.
+    /// Its channels play on another device until it comes back.
+    OutputDeviceLost(Box<str>),
+    /// A lost or unavailable output device plays again.
+    OutputDeviceRecovered(Box<str>),
+    /// Sound files of the soundpack that can't be decoded, with the reason.
+    UndecodableFiles(Vec<SoundError>),
+    /// Playlist entries and playlists of the soundpack that can't be played, with the reason.
+    UnresolvedPlaylistEntries(Vec<SoundError>),
+    /// There was an error in the Sound thread, which restarted.
+    SoundThreadPanicked(SoundError),
+    /// The Sound thread restarted after an error,
+    /// and loaded the soundpack and the gamelog again, if they still load.
+    SoundThreadRecovered {
+        soundpack: Option<std::path::PathBuf>,
+        gamelog: Option<std::path::PathBuf>,
+    },
 }
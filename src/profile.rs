@@ -0,0 +1,148 @@
+//! Named mixer profiles.
+//!
+//! A profile is a snapshot of every channel's volume, threshold and pause state,
+//! stored as `soundsense-rs/profiles/<name>.ini` in the user's config directory.
+//! Entries without a section are read as volumes, so `default-volumes.ini` is also a valid profile.
+
+use crate::message::{ChannelMix, Threshold};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The directory containing the saved profiles.
+pub fn profile_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|mut p| {
+        p.push("soundsense-rs/profiles");
+        p
+    })
+}
+
+/// Profile names are used as file names, so only allow word characters and dashes.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Path of the profile with the given name.
+pub fn profile_path(name: &str) -> Option<PathBuf> {
+    if !is_valid_name(name) {
+        return None;
+    }
+    profile_dir().map(|mut p| {
+        p.push(format!("{}.ini", name));
+        p
+    })
+}
+
+/// Names of all the saved profiles, sorted alphabetically.
+pub fn list_profiles() -> Vec<String> {
+    let mut names: Vec<String> = profile_dir()
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "ini"))
+                .filter_map(|path| {
+                    path.file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned())
+                })
+                .filter(|name| is_valid_name(name))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// Read a profile file.
+/// Channels are returned in the order they first appear in the file.
+pub fn read_profile(path: &Path) -> io::Result<Vec<ChannelMix>> {
+    lazy_static! {
+        static ref SECTION: Regex = Regex::new(r"^\[([[:word:]]+)\]$").unwrap();
+        static ref INI_ENTRY: Regex = Regex::new("([[:word:]]+)=(.+)").unwrap();
+    }
+    let text = fs::read_to_string(path)?;
+    let mut mixes: Vec<ChannelMix> = Vec::new();
+    let mut section = String::from("volume");
+    for line in text.lines().map(str::trim) {
+        if let Some(cap) = SECTION.captures(line) {
+            section = cap[1].to_string();
+            continue;
+        }
+        let cap = match INI_ENTRY.captures(line) {
+            Some(cap) => cap,
+            None => continue,
+        };
+        let (name, value) = (&cap[1], cap[2].trim());
+        let idx = match mixes.iter().position(|m| &*m.name == name) {
+            Some(idx) => idx,
+            None => {
+                mixes.push(ChannelMix::new(name));
+                mixes.len() - 1
+            }
+        };
+        let mix = &mut mixes[idx];
+        let parsed = match section.as_str() {
+            "volume" => value.parse().map(|v| mix.volume = v).is_ok(),
+            "threshold" => Threshold::parse(value).map(|t| mix.threshold = t).is_some(),
+            "paused" => value.parse().map(|p| mix.is_paused = p).is_ok(),
+            _ => true,
+        };
+        if !parsed {
+            warn!(
+                "Invalid {} value for {} in {}: {}",
+                section,
+                name,
+                path.display(),
+                value
+            );
+            warn!("Will ignore this value.");
+        }
+    }
+    Ok(mixes)
+}
+
+/// Write a profile file, creating the profile directory if needed.
+pub fn write_profile(path: &Path, mixes: &[ChannelMix]) -> io::Result<()> {
+    let mut text = String::from("[volume]\n");
+    for mix in mixes {
+        let _ = writeln!(text, "{}={}", mix.name, mix.volume as u32);
+    }
+    text.push_str("[threshold]\n");
+    for mix in mixes {
+        let _ = writeln!(text, "{}={}", mix.name, mix.threshold.name());
+    }
+    text.push_str("[paused]\n");
+    for mix in mixes {
+        let _ = writeln!(text, "{}={}", mix.name, mix.is_paused);
+    }
+    write_atomic(path, &text)
+}
+
+/// Replace the file at `path` with `contents`, creating its directory if needed.
+/// The contents are written to a temporary file that is then renamed over the target,
+/// so a crash mid-write leaves either the old or the new file, never a truncated one.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
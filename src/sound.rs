@@ -5,13 +5,14 @@ use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, AtomicUsize, Ordering},
-    Arc,
+    Arc, PoisonError,
 };
 use std::time::{Duration, Instant};
 
 use crate::message::*;
 use crossbeam::{
-    channel::{Receiver, Sender},
+    channel::{after, bounded, never, Receiver, SendError, Sender},
+    select,
     sync::ShardedLock,
 };
 use lazy_static::lazy_static;
@@ -20,12 +21,36 @@ use rand::prelude::*;
 use regex::Regex;
 use rodio::*;
 
+pub mod benchmark;
+mod device_stream;
+use device_stream::DeviceStream;
+mod error;
+pub use error::{Recovery, SoundError};
+mod formats;
+use formats::SoundSource;
+mod game_state;
+use game_state::{GameState, StateChange, StateCondition, StateUpdate, StateValue, StateVariable};
+mod master_bus;
+use master_bus::MasterBus;
+pub mod output;
+use output::{DeviceEvent, Outputs};
+mod pattern_set;
+mod playlist;
+use pattern_set::PatternSet;
+use playlist::{parse_playlist, PlaylistEntry};
+mod sound_cache;
+use sound_cache::{SoundCache, SoundReader};
+mod soundpack;
+use soundpack::{load_soundpack, Soundpack};
 mod sound_manager;
-use sound_manager::SoundManager;
+use sound_manager::{MixerState, SoundManager};
 mod sound_channel;
 use sound_channel::SoundChannel;
 
-pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+pub type Result<T> = std::result::Result<T, SoundError>;
+/// The result of reading, parsing or decoding something,
+/// whose errors the caller turns into a SoundError about it.
+type AnyResult<T> = std::result::Result<T, Box<dyn Error>>;
 
 lazy_static! {
     static ref FAULTY_ESCAPE: Regex = Regex::new(r"\\([^\.\+\*\?\(\)\|\[\]\{\}\^\$])").unwrap();
@@ -37,8 +62,20 @@ lazy_static! {
 pub enum SoundFileType {
     /// Contains a single file path.
     IsPath(PathBuf),
-    /// Contains multiple file paths.
-    IsPlaylist(Vec<PathBuf>),
+    /// Contains the tracks of a playlist.
+    IsPlaylist(Vec<PlaylistEntry>),
+}
+
+impl SoundFileType {
+    /// All the file paths, in order.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        let (path, entries) = match self {
+            SoundFileType::IsPath(path) => (Some(path.as_path()), &[][..]),
+            SoundFileType::IsPlaylist(entries) => (None, entries.as_slice()),
+        };
+        path.into_iter()
+            .chain(entries.iter().map(|entry| entry.path.as_path()))
+    }
 }
 
 /// A struct containing all the information about a SoundFile.
@@ -53,15 +90,173 @@ pub struct SoundFile {
     /// If set to true will randomply distribute sound between stereo channels.
     pub random_balance: bool,
     /// number, delay before sound is played. In miliseconds, default 0.
+    #[allow(dead_code)]
     pub delay: usize,
     /// Adjusts stereo channel, can range for -1 (full left) to 1 (full right).
     pub balance: f32,
+    /// Condition on the values captured by the Sound's pattern. The file is only chosen when it holds.
+    pub condition: Option<FileCondition>,
+    /// Intensity range over which the file fades in, when it is a stem of a layered loop.
+    /// Silent below the first value, full above the second one. From 0 to 1.
+    pub intensity: (f32, f32),
+    /// Oneshots are played at a random speed (and pitch) within this fraction of the normal one.
+    pub pitch_variation: f32,
+    /// Oneshots get a random volume change within this many decibels.
+    pub volume_variation: f32,
+    /// Order of the playlist's tracks. Defaults to the channel's mode, or sequential.
+    pub playlist_mode: Option<PlaylistMode>,
+    /// Whether a loop with this file continues where it left off, when it returns.
+    pub resume: bool,
+}
+
+impl SoundFile {
+    /// Gain of this file as a stem of a layered loop, at the given intensity.
+    pub fn stem_gain(&self, intensity: f32) -> f32 {
+        let (low, high) = self.intensity;
+        if high <= low {
+            (intensity >= low) as u8 as f32
+        } else {
+            ((intensity - low) / (high - low)).clamp(0.0, 1.0)
+        }
+    }
+}
+
+/// Number of recent tracks avoided by "shuffleNoRepeat", unless given.
+const DEFAULT_NO_REPEAT: usize = 3;
+
+/// Order in which a loop plays the tracks of a playlist.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PlaylistMode {
+    /// In the order of the playlist.
+    Sequential,
+    /// In random order. A track can play again right away.
+    Shuffle,
+    /// In random order, without repeating any of this many recent tracks.
+    ShuffleNoRepeat(usize),
+}
+
+impl PlaylistMode {
+    /// Parse a `playlistMode` value, with the `noRepeat` value if given.
+    pub fn parse(text: &str, no_repeat: Option<usize>) -> Option<Self> {
+        match text {
+            "sequential" => Some(PlaylistMode::Sequential),
+            "shuffle" => Some(PlaylistMode::Shuffle),
+            "shuffleNoRepeat" => Some(PlaylistMode::ShuffleNoRepeat(
+                no_repeat.unwrap_or(DEFAULT_NO_REPEAT),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A capture group of a Sound's logPattern, by index or by name.
+#[derive(Clone)]
+pub enum CaptureGroup {
+    Index(usize),
+    Name(Box<str>),
+}
+
+impl CaptureGroup {
+    /// Parse `1`, `$1`, `name`, `$name` or `${name}`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let group = text.trim();
+        let group = group.strip_prefix('$').unwrap_or(group);
+        let group = group
+            .strip_prefix('{')
+            .and_then(|g| g.strip_suffix('}'))
+            .unwrap_or(group);
+        if group.is_empty() {
+            return None;
+        }
+        Some(match group.parse() {
+            Ok(idx) => CaptureGroup::Index(idx),
+            Err(_) => CaptureGroup::Name(group.into()),
+        })
+    }
+
+    /// Whether the group exists in the pattern.
+    pub fn is_valid_for(&self, pattern: &Regex) -> bool {
+        match self {
+            CaptureGroup::Index(idx) => *idx < pattern.captures_len(),
+            CaptureGroup::Name(name) => pattern.capture_names().flatten().any(|n| n == &**name),
+        }
+    }
+
+    /// The captured value, if the group participated in the match.
+    pub fn get<'t>(&self, captures: &regex::Captures<'t>) -> Option<regex::Match<'t>> {
+        match self {
+            CaptureGroup::Index(idx) => captures.get(*idx),
+            CaptureGroup::Name(name) => captures.name(name),
+        }
+    }
+}
+
+/// A condition on a captured value, written as `$1=dragon`, `creature=dragon|wyvern`, or `$2!=iron`.
+/// Values are compared case-insensitively.
+#[derive(Clone)]
+pub struct FileCondition {
+    pub group: CaptureGroup,
+    /// The captured value must be one of these. Or none of these, if negated.
+    pub values: Vec<Box<str>>,
+    pub negated: bool,
+}
+
+impl FileCondition {
+    /// Parse a `when` attribute.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (group, values, negated) = match text.find("!=") {
+            Some(idx) => (&text[..idx], &text[idx + 2..], true),
+            None => {
+                let idx = text.find('=')?;
+                (&text[..idx], &text[idx + 1..], false)
+            }
+        };
+        let group = CaptureGroup::parse(group)?;
+        let values = values.split('|').map(|v| v.trim().into()).collect();
+        Some(Self {
+            group,
+            values,
+            negated,
+        })
+    }
+
+    /// Whether the condition holds for the captured values.
+    /// A group that didn't participate in the match is equal to no value.
+    pub fn is_met(&self, captures: &regex::Captures) -> bool {
+        let is_equal = self.group.get(captures).is_some_and(|m| {
+            self.values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case(m.as_str()))
+        });
+        is_equal != self.negated
+    }
+}
+
+/// Choose the SoundFiles that can be played for a log line, from the values captured by the pattern.
+/// Files whose condition holds take precedence; files without a condition are the fallback.
+pub fn select_files(files: &[SoundFile], captures: Option<&regex::Captures>) -> Vec<SoundFile> {
+    let conditional: Vec<SoundFile> = files
+        .iter()
+        .filter(|file| match (&file.condition, captures) {
+            (Some(condition), Some(captures)) => condition.is_met(captures),
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    if !conditional.is_empty() {
+        return conditional;
+    }
+    files
+        .iter()
+        .filter(|file| file.condition.is_none())
+        .cloned()
+        .collect()
 }
 
 /// A thread-safe wrapper around a volume(f32) volume.
 /// Intended to be used by LoopPlayers and OneshotPlayers.
 #[derive(Clone)]
-pub struct VolumeLock(Arc<ShardedLock<f32>>);
+pub(crate) struct VolumeLock(Arc<ShardedLock<f32>>);
 impl VolumeLock {
     #[inline]
     pub fn new() -> Self {
@@ -69,16 +264,16 @@ impl VolumeLock {
     }
     #[inline]
     pub fn get(&self) -> f32 {
-        *self.0.read().unwrap()
+        *self.0.read().unwrap_or_else(PoisonError::into_inner)
     }
     #[inline]
     pub fn set(&self, volume: f32) {
-        *self.0.write().unwrap() = volume;
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = volume;
     }
 }
 
 #[derive(Clone)]
-pub struct IsPausedLock(Arc<AtomicBool>);
+pub(crate) struct IsPausedLock(Arc<AtomicBool>);
 impl IsPausedLock {
     #[inline]
     pub fn new() -> Self {
@@ -90,12 +285,38 @@ impl IsPausedLock {
         self.0.load(Ordering::Relaxed)
     }
 
+    #[inline]
+    pub fn set(&self, is_paused: bool) {
+        self.0.store(is_paused, Ordering::SeqCst);
+    }
+
     #[inline]
     pub fn flip(&self) -> bool {
         self.0.fetch_nand(true, Ordering::SeqCst)
     }
 }
 
+/// A thread-safe flag that silences a channel without pausing it,
+/// so loops keep their position and timing while muted.
+#[derive(Clone)]
+pub(crate) struct IsMutedLock(Arc<AtomicBool>);
+impl IsMutedLock {
+    #[inline]
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    #[inline]
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set(&self, is_muted: bool) {
+        self.0.store(is_muted, Ordering::SeqCst);
+    }
+}
+
 /// A struct containing all the information about a Sound, such as regex patterns, channel, loopability, etc.
 pub struct SoundEntry {
     /// regular expression matching log line
@@ -108,6 +329,8 @@ pub struct SoundEntry {
     /// number of councured sounds allowed to be played besides this sound.
     /// If currenty playing more than that, sound is ignored. In miliseconds, default unlimited.
     pub concurency: Option<usize>,
+    /// What to do when the concurency limit is hit. Defaults to the channel's policy.
+    pub steal_policy: Option<StealPolicy>,
     /// number, timeout during which is sound going to be prevented from playing again. In miliseconds default 0.
     pub timeout: Option<usize>,
     /// percentage, Propablity that sound will be played. Default is always played.
@@ -118,13 +341,26 @@ pub struct SoundEntry {
     /// Default false
     pub halt_on_match: bool,
     /// boolean, if set to true will randomply distribute sound betweem stereo channels.
+    #[allow(dead_code)]
     pub random_balance: bool,
-    /// number, threashold used when filtering sound depending on level (currently not used)
-    pub playback_threshold: u8,
+    /// Level used when filtering sounds with the channel and total thresholds.
+    pub playback_threshold: Threshold,
+    /// Conditions on the game state, all of which have to hold for the sound to be played.
+    pub state_conditions: Vec<StateCondition>,
+    /// Numeric game state variable added to the probability, in percentage points.
+    pub probability_boost: Option<Box<str>>,
+    /// Where the sound comes from. Overrides the balance of the SoundFiles.
+    pub position: Option<Position>,
+    /// Capture group whose value decides where the sound comes from, through the `<position>`s.
+    pub position_group: Option<CaptureGroup>,
+    /// Whether the SoundFiles are read into the cache when the soundpack loads.
+    pub preload: bool,
     /// Collection of SoundFiles
     pub files: Vec<SoundFile>,
     /// Collection of each SoundFile's weight.
     pub weights: Vec<f32>,
+    /// Whether any SoundFile has a condition, so the pattern's captures are needed.
+    pub has_conditions: bool,
     /// Timeout. While timeout, can't be played.
     pub current_timeout: usize,
     /// Number of times this SoundEntry has been called.
@@ -139,12 +375,396 @@ pub enum ChannelPlayType {
     SingleLazy,
 }
 
+/// What to do with a new oneshot when its Sound's concurency limit is hit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StealPolicy {
+    /// Don't play the new oneshot.
+    RejectNewest,
+    /// Stop the oldest oneshot playing on the channel.
+    StealOldest,
+    /// Stop the quietest oneshot playing on the channel.
+    StealQuietest,
+    /// Stop the oneshot with the highest playback threshold on the channel,
+    /// if it is higher than the new oneshot's.
+    StealLowestPriority,
+}
+
+impl StealPolicy {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "rejectNewest" => Some(StealPolicy::RejectNewest),
+            "stealOldest" => Some(StealPolicy::StealOldest),
+            "stealQuietest" => Some(StealPolicy::StealQuietest),
+            "stealLowestPriority" => Some(StealPolicy::StealLowestPriority),
+            _ => None,
+        }
+    }
+}
+
+/// Fade durations of a channel's loops, in milliseconds. 0 means no fade.
+#[derive(Copy, Clone, Default)]
+pub struct FadeSetting {
+    /// Fade-in when a loop starts, resumes from pause, or returns from ducking.
+    pub fade_in: usize,
+    /// Fade-out when a loop is stopped, skipped, paused, or ducked.
+    pub fade_out: usize,
+    /// Fade-out of the old loop and fade-in of the new loop, when a loop replaces another one.
+    pub crossfade: usize,
+}
+
+/// Makes a channel play all the files of its loops together, as stems.
+/// The stems fade in and out following the intensity of the recent events.
+#[derive(Clone)]
+pub struct LayerSetting {
+    /// Channels whose Sounds raise the intensity. All channels if empty.
+    pub sources: Vec<Box<str>>,
+    /// Number of recent Sound calls at which the intensity is full.
+    pub full_intensity: usize,
+    /// Time for a stem to fade fully in or out, in milliseconds.
+    pub fade: usize,
+}
+
 pub struct ChannelSetting {
     play_type: ChannelPlayType,
+    fade: FadeSetting,
+    /// What to do when a Sound's concurency limit is hit, unless the Sound decides.
+    steal_policy: StealPolicy,
+    /// Gain applied to the channel's playing oneshots when a new oneshot starts.
+    oneshot_ducking: f32,
+    /// Gain applied to the channel's loop while a oneshot plays.
+    loop_ducking: f32,
+    /// Conditions on the game state, all of which have to hold for the channel to play.
+    state_conditions: Vec<StateCondition>,
+    /// Layered stems, if the channel plays its loops that way.
+    layers: Option<LayerSetting>,
+    /// Where the channel's sounds come from, unless the Sound decides.
+    position: Option<Position>,
+    /// Order of the channel's loops and of their playlists, unless a SoundFile decides.
+    playlist_mode: Option<PlaylistMode>,
+    /// Whether the channel's loops continue where they left off, when they return.
+    resume: bool,
+    /// Name of the output device the channel plays on, instead of the main device.
+    device: Option<Box<str>>,
+}
+
+/// Lowers the volume of a channel while another channel is playing sounds.
+pub struct DuckingRule {
+    /// Channel whose sounds trigger the ducking.
+    trigger: Box<str>,
+    /// Channel that is ducked.
+    target: Box<str>,
+    /// Volume change of the target channel, in decibels.
+    amount: f32,
+    /// Time to reach the full amount, in milliseconds.
+    attack: usize,
+    /// Time to return to full volume after the trigger channel goes quiet, in milliseconds.
+    release: usize,
+}
+
+/// Distance at which a positioned sound is played at half volume.
+const REFERENCE_DISTANCE: f32 = 10.0;
+
+/// Where a sound comes from, relative to the listener.
+#[derive(Copy, Clone, Debug)]
+pub struct Position {
+    /// Direction in degrees. -90 is full left, 0 is in front, 90 is full right.
+    pub azimuth: f32,
+    /// Distance from the listener. 0 is full volume.
+    pub distance: f32,
+}
+
+impl Position {
+    /// Stereo balance, from -1 (full left) to 1 (full right).
+    #[inline]
+    pub fn balance(&self) -> f32 {
+        self.azimuth.to_radians().sin()
+    }
+
+    /// Gain from the distance. Halves at REFERENCE_DISTANCE.
+    #[inline]
+    pub fn attenuation(&self) -> f32 {
+        REFERENCE_DISTANCE / (REFERENCE_DISTANCE + self.distance.max(0.0))
+    }
+
+    /// A position for a captured value that has no `<position>`.
+    /// The same value always gets the same direction.
+    pub fn from_hash(value: &str, distance: f32) -> Self {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        let mut hasher = DefaultHasher::new();
+        value.to_ascii_lowercase().hash(&mut hasher);
+        Self {
+            azimuth: (hasher.finish() % 181) as f32 - 90.0,
+            distance,
+        }
+    }
+}
+
+/// A `<position>`: where the sounds whose captured value is one of `values` come from.
+pub struct PositionRule {
+    /// Compared case-insensitively.
+    pub values: Vec<Box<str>>,
+    pub position: Position,
+}
+
+/// How sounds are placed between the speakers, chosen by the user.
+#[derive(Copy, Clone, Debug)]
+pub struct StereoSetting {
+    /// Scales every balance. 0 plays everything in the center, 1 is full width.
+    pub width: f32,
+    /// Mix every sound down to the same mono signal on all speakers.
+    pub mono: bool,
+}
+
+impl Default for StereoSetting {
+    fn default() -> Self {
+        Self {
+            width: 1.0,
+            mono: false,
+        }
+    }
+}
+
+/// Settings of the compressor/limiter on the master output, and of the voice cap.
+#[derive(Copy, Clone, Debug)]
+pub struct MasterSetting {
+    /// Level above which the mix is compressed, in decibels.
+    pub threshold: f32,
+    /// How much the level above the threshold is reduced. A high ratio makes a limiter.
+    pub ratio: f32,
+    /// Width of the soft knee around the threshold, in decibels.
+    pub knee: f32,
+    /// Time to react to a louder mix, in milliseconds.
+    pub attack: usize,
+    /// Time to recover once the mix gets quieter, in milliseconds.
+    pub release: usize,
+    /// Gain applied after the compression, in decibels.
+    pub makeup: f32,
+    /// Maximum number of oneshots playing at once, over all channels.
+    pub max_voices: usize,
+}
+
+impl Default for MasterSetting {
+    fn default() -> Self {
+        Self {
+            threshold: -1.0,
+            ratio: 20.0,
+            knee: 6.0,
+            attack: 5,
+            release: 150,
+            makeup: 0.0,
+            max_voices: 48,
+        }
+    }
+}
+
+/// Convert a volume change in decibels into a linear gain.
+#[inline]
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Read an ignore list. Each line is a pattern; invalid patterns are skipped.
+pub fn load_ignore_list(path: &Path) -> Result<Vec<Regex>> {
+    let buf = &mut Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(buf))
+        .map_err(|e| SoundError::config(path, &e))?;
+    let list = String::from_utf8_lossy(buf)
+        .lines()
+        .filter_map(|expr| {
+            let processed = FAULTY_ESCAPE.replace_all(expr, "$1");
+            let processed = EMPTY_EXPR.replace_all(&processed, ")?");
+            Regex::new(&processed).ok()
+        })
+        .collect();
+    Ok(list)
+}
+
+/// How often the gamelog is checked for new lines while the game writes to it.
+const MIN_POLL_PERIOD: Duration = Duration::from_millis(10);
+/// The checks slow down to this while the gamelog is quiet.
+/// Kept short, since it delays the sounds of the first line after a quiet spell.
+const MAX_POLL_PERIOD: Duration = Duration::from_millis(50);
+
+/// Delay before the first restart of the sound thread after an error.
+/// Doubles with every restart that fails again soon, up to MAX_RESTART_DELAY.
+const RESTART_DELAY: Duration = Duration::from_millis(250);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// A sound thread that ran this long before failing restarts without delay growth.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// What the sound thread had loaded, so it can load it again after a restart.
+#[derive(Default)]
+struct Session {
+    soundpack: Option<PathBuf>,
+    gamelog: Option<PathBuf>,
+    /// Position in the gamelog, so no lines are skipped or played twice.
+    gamelog_position: Option<u64>,
+    ignore_list: Option<PathBuf>,
+    mixer: Option<MixerState>,
+}
+
+/// Open the gamelog, at the given position or else at its end.
+/// A gamelog shorter than the position, as when a new game started, is read from its end.
+fn open_gamelog(path: &Path, position: Option<u64>) -> Result<BufReader<File>> {
+    let gamelog_error = |e: std::io::Error| SoundError::Gamelog {
+        path: path.into(),
+        message: e.to_string(),
+    };
+    let mut file = File::open(path).map_err(gamelog_error)?;
+    let len = file.metadata().map_err(gamelog_error)?.len();
+    let position = position.map_or(len, |position| position.min(len));
+    file.seek(SeekFrom::Start(position))
+        .map_err(gamelog_error)?;
+    Ok(BufReader::new(file))
+}
+
+/// Read the lines the game added to the gamelog, and play their sounds.
+/// A line the game is still writing is left for the next call. Returns whether there were any lines.
+fn read_gamelog(
+    path: &Path,
+    buf_reader: &mut BufReader<File>,
+    manager: &mut SoundManager,
+) -> Result<bool> {
+    let mut line = Vec::new();
+    let mut has_lines = false;
+    loop {
+        line.clear();
+        match buf_reader.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(has_lines),
+            Ok(len) if line.last() != Some(&b'\n') => {
+                buf_reader
+                    .seek_relative(-(len as i64))
+                    .map_err(|e| SoundError::Gamelog {
+                        path: path.into(),
+                        message: e.to_string(),
+                    })?;
+                return Ok(has_lines);
+            }
+            Ok(_) => {
+                has_lines = true;
+                let text = decode_gamelog_line(&line);
+                manager.process_log(text.trim_end_matches(&['\r', '\n'][..]))?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                return Err(SoundError::Gamelog {
+                    path: path.into(),
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// The characters of the bytes 0x80 to 0xFF in code page 437.
+#[rustfmt::skip]
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// DF writes the gamelog in CP437, though some tools rewrite it as UTF-8.
+/// A line that isn't valid UTF-8 is read as CP437.
+fn decode_gamelog_line(line: &[u8]) -> std::borrow::Cow<'_, str> {
+    match std::str::from_utf8(line) {
+        Ok(text) => text.into(),
+        Err(_) => line
+            .iter()
+            .map(|&b| {
+                if b < 0x80 {
+                    b as char
+                } else {
+                    CP437_HIGH[b as usize - 0x80]
+                }
+            })
+            .collect::<String>()
+            .into(),
+    }
+}
+
+/// Leave out a part of the session that failed to load again, and let the UI deal with it.
+/// The UI asks for a new path, or tries again later, so the sound thread doesn't retry it too.
+/// Errors that only a restart fixes are returned.
+fn skip_failed(error: SoundError, ui_tx: &Sender<UIMessage>) -> Result<()> {
+    if error.recovery() == Recovery::Restart {
+        return Err(error);
+    }
+    warn!("{}", error);
+    warn!("Will restart without it.");
+    ui_tx.send(UIMessage::SoundThreadPanicked(error))?;
+    Ok(())
+}
+
+/// Load the session again after a restart, skipping the parts that can't be loaded anymore.
+fn restore_session(
+    session: &mut Session,
+    ui_tx: &Sender<UIMessage>,
+    stereo: StereoSetting,
+    cache: &SoundCache,
+    device: Option<&str>,
+) -> Result<(Option<SoundManager>, Option<BufReader<File>>)> {
+    let mut manager = None;
+    if let Some(path) = session.soundpack.clone() {
+        match SoundManager::new(&path, ui_tx.clone(), stereo, cache.clone(), device) {
+            Ok(new_manager) => manager = Some(new_manager),
+            Err(e) => {
+                skip_failed(e, ui_tx)?;
+                session.soundpack = None;
+            }
+        }
+    }
+    if let Some(manager) = manager.as_mut() {
+        if let Some(path) = session.ignore_list.clone() {
+            let result =
+                load_ignore_list(&path).and_then(|list| manager.set_ignore_list(&path, list));
+            if let Err(e) = result {
+                skip_failed(e, ui_tx)?;
+                session.ignore_list = None;
+            }
+        }
+        if let Some(mixer) = session.mixer.as_ref() {
+            manager.restore_mixer_state(mixer)?;
+        }
+    }
+    let mut buf_reader = None;
+    if let Some(path) = session.gamelog.clone() {
+        match open_gamelog(&path, session.gamelog_position) {
+            Ok(reader) => buf_reader = Some(reader),
+            Err(e) => {
+                skip_failed(e, ui_tx)?;
+                session.gamelog = None;
+            }
+        }
+    }
+    ui_tx.send(UIMessage::SoundThreadRecovered {
+        soundpack: session.soundpack.clone(),
+        gamelog: session.gamelog.clone(),
+    })?;
+    Ok((manager, buf_reader))
 }
 
 /// The sound thread function.
-pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
+pub fn run(
+    sound_rx: Receiver<SoundMessage>,
+    ui_tx: Sender<UIMessage>,
+    stereo: StereoSetting,
+    cache_size: usize,
+    mut device: Option<Box<str>>,
+) {
+    // The cache outlives the managers, so a reloaded soundpack starts with its files cached.
+    let cache = SoundCache::new(cache_size);
+    // What is loaded, to load it again after a restart.
+    let mut session = Session::default();
+    // Number of restarts in a row that failed soon after.
+    let mut failures: u32 = 0;
     // Outer loop. Restarts the inner loop if an error occured, but didn't panic.
     loop {
         info!("(Re)Starting sound thread.");
@@ -154,27 +774,110 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
         let mut buf_reader: Option<BufReader<File>> = None;
         // Current time for delta time calculation.
         let mut prev = Instant::now();
+        let started = Instant::now();
+        // When to check the gamelog for new lines next, and how long to wait after that.
+        let mut next_poll = Instant::now();
+        let mut poll_period = MIN_POLL_PERIOD;
 
         // Arguably the most front-heavy if statement I ever wrote.
         if let Err(error) = || -> Result<()> {
-            // Inner loop. Will return an Error if something wrong happens.
+            if failures > 0 {
+                let (restored_manager, restored_reader) =
+                    restore_session(&mut session, &ui_tx, stereo, &cache, device.as_deref())?;
+                manager = restored_manager;
+                buf_reader = restored_reader;
+            }
+            // Inner loop. Will return an Error if something wrong happens,
+            // or Ok if the UI is gone.
             loop {
-                // Read SoundMessages sent from the UI.
-                for message in sound_rx.try_iter() {
+                // Without a soundpack, nothing would play, so the gamelog waits.
+                let poll = if manager.is_some() && buf_reader.is_some() {
+                    after(next_poll.saturating_duration_since(Instant::now()))
+                } else {
+                    never()
+                };
+                let (maintenance, wakeups) = match manager.as_ref() {
+                    Some(manager) => {
+                        let delay = Duration::from_millis(manager.maintenance_delay() as u64);
+                        (
+                            after(delay.saturating_sub(prev.elapsed())),
+                            manager.wakeups(),
+                        )
+                    }
+                    None => (never(), never()),
+                };
+                // Sleep until the UI sends a message, or the gamelog or the sounds need attention.
+                let message = select! {
+                    recv(sound_rx) -> message => match message {
+                        Ok(message) => Some(message),
+                        Err(_) => return Ok(()),
+                    },
+                    recv(poll) -> _ => {
+                        if let (Some(manager), Some(buf_reader)) = (&mut manager, &mut buf_reader) {
+                            let path = session.gamelog.as_deref().unwrap_or_else(|| Path::new(""));
+                            let has_lines = read_gamelog(path, buf_reader, manager)?;
+                            // Poll quickly while the game writes, and back off while it's quiet.
+                            poll_period = if has_lines {
+                                MIN_POLL_PERIOD
+                            } else {
+                                (poll_period * 2).min(MAX_POLL_PERIOD)
+                            };
+                        }
+                        next_poll = Instant::now() + poll_period;
+                        None
+                    },
+                    recv(maintenance) -> _ => None,
+                    recv(wakeups) -> _ => None,
+                };
+                // Handle a SoundMessage sent from the UI.
+                if let Some(message) = message {
                     use SoundMessage::*;
                     match message {
                         ChangeGamelog(path) => {
-                            let mut file0 = File::open(&path)?;
-                            file0.seek(SeekFrom::End(0))?;
-                            buf_reader = Some(BufReader::new(file0));
+                            buf_reader = Some(open_gamelog(&path, None)?);
+                            next_poll = Instant::now();
+                            session.gamelog = Some(path);
+                            session.gamelog_position = None;
                             ui_tx.send(UIMessage::LoadedGamelog)?;
                         }
 
+                        FlushSettings => {
+                            if let Some(manager) = manager.as_mut() {
+                                manager.flush_settings()?;
+                            }
+                            ui_tx.send(UIMessage::SettingsFlushed)?;
+                        }
+
                         ChangeSoundpack(path) => {
                             if let Some(prev_manager) = manager.take() {
+                                // Kept in case the new soundpack fails, and the previous one is restored.
+                                session.mixer = Some(prev_manager.mixer_state());
                                 prev_manager.finish();
                             }
-                            manager.replace(SoundManager::new(&path, ui_tx.clone())?);
+                            manager.replace(SoundManager::new(
+                                &path,
+                                ui_tx.clone(),
+                                stereo,
+                                cache.clone(),
+                                device.as_deref(),
+                            )?);
+                            session.soundpack = Some(path);
+                            session.mixer = None;
+                        }
+
+                        ListOutputDevices => {
+                            ui_tx.send(UIMessage::OutputDevices(output::list_devices()))?;
+                        }
+
+                        SetOutputDevice(name) => {
+                            trace!("Set output device to {:?}", name);
+                            if let Some(manager) = manager.as_mut() {
+                                manager.set_output_device(name.as_deref())?;
+                                device = manager.output_device().map(Box::from);
+                            } else {
+                                ui_tx.send(UIMessage::OutputDeviceChanged(name.clone()))?;
+                                device = name;
+                            }
                         }
 
                         // These types of messages require a manager.
@@ -182,20 +885,8 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
                             if let Some(manager) = manager.as_mut() {
                                 match message {
                                     ChangeIgnoreList(path) => {
-                                        let file = &mut File::open(&path)?;
-                                        let buf = &mut Vec::new();
-                                        file.read_to_end(buf)?;
-                                        let list: Vec<Regex> = String::from_utf8_lossy(&buf)
-                                            .lines()
-                                            .filter_map(|expr| {
-                                                let processed =
-                                                    FAULTY_ESCAPE.replace_all(expr, "$1");
-                                                let processed =
-                                                    EMPTY_EXPR.replace_all(&processed, ")?");
-                                                Regex::new(&processed).ok()
-                                            })
-                                            .collect();
-                                        manager.set_ignore_list(list)?;
+                                        manager.set_ignore_list(&path, load_ignore_list(&path)?)?;
+                                        session.ignore_list = Some(path);
                                     }
 
                                     VolumeChange(channel, volume) => {
@@ -204,11 +895,11 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
 
                                     ThresholdChange(channel, threshold) => {
                                         trace!(
-                                            "Set channel {} threshold to {}",
+                                            "Set channel {} threshold to {:?}",
                                             channel,
-                                            threshold as u8
+                                            threshold
                                         );
-                                        manager.set_threshold(&channel, threshold as u8)?;
+                                        manager.set_threshold(&channel, threshold)?;
                                     }
 
                                     SkipCurrentSound(channel) => {
@@ -221,8 +912,28 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
                                         manager.play_pause(&channel)?;
                                     }
 
-                                    SetCurrentVolumesAsDefault(file) => {
-                                        manager.set_current_volumes_as_default(file)?;
+                                    Mute(channel) => {
+                                        trace!("Mute {}", channel);
+                                        manager.mute(&channel)?;
+                                    }
+
+                                    Solo(channel) => {
+                                        trace!("Solo {}", channel);
+                                        manager.solo(&channel)?;
+                                    }
+
+                                    SetCurrentVolumesAsDefault => {
+                                        manager.set_current_volumes_as_default();
+                                    }
+
+                                    SaveProfile(name) => {
+                                        trace!("Save profile {}", name);
+                                        manager.save_profile(&name)?;
+                                    }
+
+                                    SwitchProfile(name) => {
+                                        trace!("Switch to profile {}", name);
+                                        manager.switch_profile(&name)?;
                                     }
                                     _ => (),
                                 }
@@ -230,32 +941,51 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
                         }
                     }
                 }
-                let current = Instant::now();
-                if let Some(manager) = &mut manager {
-                    if let Some(buf_reader) = &mut buf_reader {
-                        let dt = current.duration_since(prev).as_millis() as usize;
-                        for log in buf_reader.lines().filter_map(|l| l.ok()) {
-                            manager.process_log(&log)?;
-                        }
+                // The fraction of a millisecond left over counts towards the next dt,
+                // so frequent wakeups don't lose time.
+                let dt = prev.elapsed().as_millis() as usize;
+                prev += Duration::from_millis(dt as u64);
+                if let Some(manager) = manager.as_mut() {
+                    if buf_reader.is_some() {
                         manager.maintain(dt)?;
                     }
+                    manager.maintain_settings(dt)?;
                 }
-                prev = current;
-                std::thread::sleep(std::time::Duration::from_millis(10));
             }
         }() {
             // LOOK, A BUTTERFLY!
             // If an error occurred and was caught, send the error message to the UI.
             // Return to the outer loop, which will then restart the inner loop.
-            let mut error_message = "The soundthread restarted due to this error:\n".to_string();
-            error_message.push_str(&error.to_string());
-            ui_tx
-                .send(UIMessage::SoundThreadPanicked(
-                    "SoundThread Error".to_string(),
-                    error_message,
-                ))
-                .unwrap();
             error!("SoundThreadError:\n{:?}", error);
+            // Without a UI, there's no one left to play for.
+            if ui_tx.send(UIMessage::SoundThreadPanicked(error)).is_err() {
+                return;
+            }
+            if let Some(manager) = manager.as_ref() {
+                session.mixer = Some(manager.mixer_state());
+            }
+            if let Some(buf_reader) = buf_reader.as_mut() {
+                session.gamelog_position = buf_reader.stream_position().ok();
+            }
+            // Stop the sounds before the delay, instead of letting them play on unattended.
+            if let Some(manager) = manager.take() {
+                manager.finish();
+            }
+            // Back off if the restarts keep failing, instead of spinning on the same error.
+            failures = if started.elapsed() >= STABLE_RUN {
+                1
+            } else {
+                failures + 1
+            };
+            let delay = (RESTART_DELAY * 2u32.pow((failures - 1).min(7))).min(MAX_RESTART_DELAY);
+            info!("Restarting the sound thread in {:?}.", delay);
+            std::thread::sleep(delay);
+        } else {
+            info!("The UI is gone, so the sound thread ends.");
+            if let Some(manager) = manager.take() {
+                manager.finish();
+            }
+            return;
         }
     }
 }
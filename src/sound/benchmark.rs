@@ -0,0 +1,125 @@
+//! Matching throughput benchmark, run with `cargo run --release --example benchmark`.
+//! Compares the RegexSet matcher used by the SoundManager
+//! with testing every pattern in turn, as SoundSense-RS used to.
+
+use super::*;
+
+/// Number of times the whole gamelog is processed by each matcher.
+const PASSES: usize = 5;
+
+/// Match one log line by testing every pattern in turn.
+/// Outputs the indices of the matched Sounds, up to and including the first one that halts on match.
+fn sequential(sounds: &[SoundEntry], ignore_list: &[Regex], log: &str, out: &mut Vec<usize>) {
+    out.clear();
+    if ignore_list.iter().any(|pattern| pattern.is_match(log)) {
+        return;
+    }
+    for (i, sound) in sounds.iter().enumerate() {
+        if sound.pattern.is_match(log) {
+            out.push(i);
+            if sound.halt_on_match {
+                return;
+            }
+        }
+    }
+}
+
+/// Match one log line with the PatternSets, like the SoundManager does.
+fn with_sets(
+    sounds: &[SoundEntry],
+    sound_patterns: &PatternSet,
+    ignore_list: &PatternSet,
+    log: &str,
+    out: &mut Vec<usize>,
+) {
+    out.clear();
+    if ignore_list.is_match(log) {
+        return;
+    }
+    sound_patterns.matches_into(log, out);
+    if let Some(halt) = out.iter().position(|&i| sounds[i].halt_on_match) {
+        out.truncate(halt + 1);
+    }
+}
+
+/// Time `PASSES` runs of the matcher over all the lines.
+/// Returns the elapsed time and the matches of the last pass.
+fn time_passes(
+    lines: &[String],
+    mut matcher: impl FnMut(&str, &mut Vec<usize>),
+) -> (Duration, Vec<Vec<usize>>) {
+    let mut results = vec![Vec::new(); lines.len()];
+    let start = Instant::now();
+    for _ in 0..PASSES {
+        for (line, out) in lines.iter().zip(results.iter_mut()) {
+            matcher(line, out);
+        }
+    }
+    (start.elapsed(), results)
+}
+
+/// Run the benchmark over every line of the gamelog, and print the results.
+pub fn run(
+    sound_dir: &Path,
+    gamelog_path: &Path,
+    ignore_path: Option<&Path>,
+) -> std::result::Result<(), Box<dyn Error>> {
+    let Soundpack { sounds, .. } = load_soundpack(sound_dir)?;
+    let ignore_list = match ignore_path {
+        Some(path) => load_ignore_list(path)?,
+        None => Vec::new(),
+    };
+    let mut buf = Vec::new();
+    File::open(gamelog_path)?.read_to_end(&mut buf)?;
+    let lines: Vec<String> = String::from_utf8_lossy(&buf)
+        .lines()
+        .map(String::from)
+        .collect();
+
+    let start = Instant::now();
+    let sound_patterns = PatternSet::new(sounds.iter().map(|s| s.pattern.as_str()))?;
+    let ignore_set = PatternSet::new(ignore_list.iter().map(|r| r.as_str()))?;
+    let compile_time = start.elapsed();
+
+    let (sequential_time, expected) = time_passes(&lines, |log, out| {
+        sequential(&sounds, &ignore_list, log, out)
+    });
+    let (set_time, actual) = time_passes(&lines, |log, out| {
+        with_sets(&sounds, &sound_patterns, &ignore_set, log, out)
+    });
+
+    let processed = (lines.len() * PASSES) as f64;
+    println!(
+        "{} patterns, {} ignore patterns, {} lines x {} passes",
+        sounds.len(),
+        ignore_list.len(),
+        lines.len(),
+        PASSES
+    );
+    println!("RegexSet compile time: {:?}", compile_time);
+    println!(
+        "sequential: {:>10.0} lines/s ({:?})",
+        processed / sequential_time.as_secs_f64(),
+        sequential_time
+    );
+    println!(
+        "RegexSet:   {:>10.0} lines/s ({:?})",
+        processed / set_time.as_secs_f64(),
+        set_time
+    );
+    println!(
+        "speedup:    {:>10.2}x",
+        sequential_time.as_secs_f64() / set_time.as_secs_f64()
+    );
+    let mismatches = expected
+        .iter()
+        .zip(actual.iter())
+        .filter(|(e, a)| e != a)
+        .count();
+    if mismatches == 0 {
+        println!("Both matchers agree on every line.");
+        Ok(())
+    } else {
+        Err(format!("The matchers disagree on {} lines!", mismatches).into())
+    }
+}
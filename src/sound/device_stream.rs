@@ -0,0 +1,187 @@
+use super::*;
+use cpal::traits::{EventLoopTrait, HostTrait};
+use cpal::{
+    EventLoop, Format, Sample as CpalSample, StreamData, StreamId, UnknownTypeOutputBuffer,
+};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::collections::HashMap;
+
+/// Whether a stream still plays.
+#[derive(Default)]
+struct StreamHealth {
+    /// Set when the backend reports an error, as when the device is unplugged.
+    failed: AtomicBool,
+    /// Number of samples written to the device.
+    samples: AtomicUsize,
+}
+
+/// A source played by a stream.
+struct StreamSlot {
+    source: Box<dyn Source<Item = f32> + Send>,
+    health: Arc<StreamHealth>,
+}
+
+/// Changes to the streams, sent to the audio output thread.
+enum StreamCommand {
+    Add(StreamId, StreamSlot),
+    Remove(StreamId),
+}
+
+/// Feeds the streams from a background thread.
+/// rodio's own engine can't tell when a device is lost, and keeps the streams of lost devices
+/// forever, so a device that comes back would get its dead stream again.
+/// The slots belong to the output thread, and are changed through `commands`,
+/// so opening or closing a stream never blocks the output.
+struct Engine {
+    event_loop: EventLoop,
+    commands: Sender<StreamCommand>,
+}
+
+lazy_static! {
+    static ref ENGINE: Arc<Engine> = {
+        let (commands, command_rx) = unbounded();
+        let engine = Arc::new(Engine {
+            event_loop: cpal::default_host().event_loop(),
+            commands,
+        });
+        let run_engine = engine.clone();
+        // Without the thread nothing plays, but that's better than a panic.
+        if let Err(e) = std::thread::Builder::new()
+            .name("audio_output".to_string())
+            .spawn(move || {
+                let mut streams = HashMap::new();
+                run_engine.event_loop.run(move |id, result| {
+                    fill_stream(&mut streams, &command_rx, id, result)
+                })
+            })
+        {
+            error!("Failed to start the audio output thread: {}", e);
+        }
+        engine
+    };
+}
+
+fn fill_stream(
+    streams: &mut HashMap<StreamId, StreamSlot>,
+    command_rx: &Receiver<StreamCommand>,
+    id: StreamId,
+    result: cpal::StreamDataResult,
+) {
+    for command in command_rx.try_iter() {
+        match command {
+            StreamCommand::Add(id, slot) => {
+                streams.insert(id, slot);
+            }
+            StreamCommand::Remove(id) => {
+                streams.remove(&id);
+            }
+        }
+    }
+    let slot = match streams.get_mut(&id) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let source = &mut slot.source;
+    let len = match result {
+        Ok(StreamData::Output {
+            buffer: UnknownTypeOutputBuffer::U16(mut buffer),
+        }) => {
+            for d in buffer.iter_mut() {
+                *d = source.next().unwrap_or(0.0).to_u16();
+            }
+            buffer.len()
+        }
+        Ok(StreamData::Output {
+            buffer: UnknownTypeOutputBuffer::I16(mut buffer),
+        }) => {
+            for d in buffer.iter_mut() {
+                *d = source.next().unwrap_or(0.0).to_i16();
+            }
+            buffer.len()
+        }
+        Ok(StreamData::Output {
+            buffer: UnknownTypeOutputBuffer::F32(mut buffer),
+        }) => {
+            for d in buffer.iter_mut() {
+                *d = source.next().unwrap_or(0.0);
+            }
+            buffer.len()
+        }
+        Ok(StreamData::Input { .. }) => 0,
+        Err(e) => {
+            if !slot.health.failed.swap(true, Ordering::Relaxed) {
+                warn!("Output stream failed: {}", e);
+            }
+            0
+        }
+    };
+    slot.health.samples.fetch_add(len, Ordering::Relaxed);
+}
+
+/// A stream playing a source on a device. The stream ends when this is dropped.
+pub struct DeviceStream {
+    id: StreamId,
+    health: Arc<StreamHealth>,
+    /// Samples written when the health was last checked.
+    checked_samples: usize,
+}
+
+impl DeviceStream {
+    /// Open a stream on the device, and play the source made for its channels and sample rate.
+    pub fn new<F, S>(device: &Device, make_source: F) -> AnyResult<Self>
+    where
+        F: FnOnce(u16, u32) -> S,
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let (id, format) = build_stream(device)?;
+        let health = Arc::new(StreamHealth::default());
+        let slot = StreamSlot {
+            source: Box::new(make_source(format.channels, format.sample_rate.0)),
+            health: health.clone(),
+        };
+        // The slot is added before the stream plays, so the output thread has it when first asked.
+        let _ = ENGINE.commands.send(StreamCommand::Add(id.clone(), slot));
+        if let Err(e) = ENGINE.event_loop.play_stream(id.clone()) {
+            let _ = ENGINE.commands.send(StreamCommand::Remove(id.clone()));
+            ENGINE.event_loop.destroy_stream(id);
+            return Err(e.into());
+        }
+        Ok(Self {
+            id,
+            health,
+            checked_samples: 0,
+        })
+    }
+
+    /// Whether the stream stopped playing: it failed, or wrote nothing since the last check.
+    pub fn is_lost(&mut self) -> bool {
+        let samples = self.health.samples.load(Ordering::Relaxed);
+        let is_stalled = samples == self.checked_samples;
+        self.checked_samples = samples;
+        is_stalled || self.health.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for DeviceStream {
+    fn drop(&mut self) {
+        let _ = ENGINE.commands.send(StreamCommand::Remove(self.id.clone()));
+        ENGINE.event_loop.destroy_stream(self.id.clone());
+    }
+}
+
+/// Build a stream in the device's default format,
+/// or else in the first supported format that works.
+fn build_stream(device: &Device) -> AnyResult<(StreamId, Format)> {
+    let format = device.default_output_format()?;
+    let error = match ENGINE.event_loop.build_output_stream(device, &format) {
+        Ok(id) => return Ok((id, format)),
+        Err(e) => e,
+    };
+    for supported in device.supported_output_formats()? {
+        let format = supported.with_max_sample_rate();
+        if let Ok(id) = ENGINE.event_loop.build_output_stream(device, &format) {
+            return Ok((id, format));
+        }
+    }
+    Err(error.into())
+}
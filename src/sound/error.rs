@@ -0,0 +1,124 @@
+use super::*;
+use std::fmt;
+
+/// An error of the sound thread, by what went wrong.
+/// Sent to the UI, which tells the user what to do about it.
+#[derive(Debug, Clone)]
+pub enum SoundError {
+    /// The gamelog couldn't be opened or read.
+    Gamelog { path: PathBuf, message: String },
+    /// The soundpack couldn't be loaded.
+    /// The line is known for errors in the XML files.
+    Soundpack {
+        path: PathBuf,
+        line: Option<usize>,
+        message: String,
+    },
+    /// No output device can play the soundpack at `soundpack`.
+    Device { soundpack: PathBuf, message: String },
+    /// A sound file can't be decoded.
+    Decode { path: PathBuf, message: String },
+    /// An entry of a playlist, or the whole playlist, can't be played.
+    Playlist { path: PathBuf, message: String },
+    /// A config file, like the ignore list or the default volumes, couldn't be read.
+    Config { path: PathBuf, message: String },
+    /// The UI stopped listening, so there's no one left to play for.
+    Disconnected,
+    /// The sound thread got into a state it shouldn't be in.
+    Internal(String),
+}
+
+/// What fixes the cause of a SoundError.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    /// Restarting the sound thread is enough.
+    Restart,
+    /// Trying again once the system changed, as when a device is plugged in.
+    Retry,
+    /// Choosing another file or directory, or fixing the current one.
+    NewPath,
+}
+
+impl SoundError {
+    /// An error of the soundpack at `path`, from any error.
+    pub fn soundpack(path: &Path, line: Option<usize>, error: &dyn Error) -> Self {
+        SoundError::Soundpack {
+            path: path.into(),
+            line,
+            message: error.to_string(),
+        }
+    }
+
+    /// An error of the config file at `path`, from any error.
+    pub fn config(path: &Path, error: &dyn Error) -> Self {
+        SoundError::Config {
+            path: path.into(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            SoundError::Gamelog { .. }
+            | SoundError::Soundpack { .. }
+            | SoundError::Decode { .. }
+            | SoundError::Playlist { .. }
+            | SoundError::Config { .. } => Recovery::NewPath,
+            SoundError::Device { .. } => Recovery::Retry,
+            SoundError::Disconnected | SoundError::Internal(_) => Recovery::Restart,
+        }
+    }
+}
+
+impl fmt::Display for SoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoundError::Gamelog { path, message } => {
+                write!(f, "Can't read the gamelog {}: {}", path.display(), message)
+            }
+            SoundError::Soundpack {
+                path,
+                line: Some(line),
+                message,
+            } => write!(
+                f,
+                "Can't load the soundpack: {} line {}: {}",
+                path.display(),
+                line,
+                message
+            ),
+            SoundError::Soundpack {
+                path,
+                line: None,
+                message,
+            } => write!(
+                f,
+                "Can't load the soundpack {}: {}",
+                path.display(),
+                message
+            ),
+            SoundError::Device { message, .. } => {
+                write!(f, "No output device can play: {}", message)
+            }
+            SoundError::Decode { path, message } => {
+                write!(f, "Can't decode {}: {}", path.display(), message)
+            }
+            SoundError::Playlist { path, message } => {
+                write!(f, "Playlist {}: {}", path.display(), message)
+            }
+            SoundError::Config { path, message } => {
+                write!(f, "Can't read {}: {}", path.display(), message)
+            }
+            SoundError::Disconnected => write!(f, "The UI stopped listening to the sound thread"),
+            SoundError::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl Error for SoundError {}
+
+impl<T> From<SendError<T>> for SoundError {
+    fn from(_: SendError<T>) -> Self {
+        SoundError::Disconnected
+    }
+}
@@ -0,0 +1,625 @@
+use super::*;
+
+/// A decoded sound, whatever its format.
+pub type SoundSource = Box<dyn Source<Item = i16> + Send>;
+
+/// Number of bytes read from the start of a file to find its format.
+const PROBE_LEN: usize = 64;
+
+/// An audio format that sound files can be decoded from.
+pub trait AudioFormat: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Whether the start of a file looks like this format.
+    fn probe(&self, header: &[u8]) -> bool;
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource>;
+}
+
+lazy_static! {
+    /// The supported formats, in the order they are probed.
+    static ref FORMATS: Vec<Box<dyn AudioFormat>> = vec![
+        Box::new(Wav),
+        Box::new(Flac),
+        Box::new(Vorbis),
+        Box::new(Opus),
+        Box::new(Aiff),
+        Box::new(Mp3),
+    ];
+}
+
+/// Decode a sound, choosing the decoder by the contents of the file.
+pub fn decode(reader: SoundReader) -> AnyResult<SoundSource> {
+    let (format, reader) = probe(reader)?;
+    match format {
+        Some(format) => format.decode(reader),
+        // Let rodio try anything that isn't recognized, like MP3s without ID3 tags
+        // that start with junk.
+        None => match Decoder::new(reader) {
+            Ok(source) => Ok(Box::new(source)),
+            Err(_) => Err("unrecognized format".into()),
+        },
+    }
+}
+
+/// Check that a file can be decoded. Returns the name of its format.
+/// A cached file is read from the cache, but files aren't cached for this.
+pub fn check(path: &Path, cache: &SoundCache) -> Result<&'static str> {
+    let check = || -> AnyResult<&'static str> {
+        let reader = cache.open_uncached(path)?;
+        let (format, reader) = probe(reader)?;
+        match format {
+            Some(format) => format.decode(reader).map(|_| format.name()),
+            None => match Decoder::new(reader) {
+                Ok(_) => Ok("unknown"),
+                Err(_) => Err("unrecognized format".into()),
+            },
+        }
+    };
+    check().map_err(|e| SoundError::Decode {
+        path: path.into(),
+        message: e.to_string(),
+    })
+}
+
+/// Find the format matching the start of the file, then rewind it.
+fn probe(mut reader: SoundReader) -> AnyResult<(Option<&'static dyn AudioFormat>, SoundReader)> {
+    let mut header = [0u8; PROBE_LEN];
+    let mut len = 0;
+    while len < PROBE_LEN {
+        match reader.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    let header = &header[..len];
+    let format = FORMATS
+        .iter()
+        .find(|format| format.probe(header))
+        .map(|format| format.as_ref());
+    Ok((format, reader))
+}
+
+/// Formats decoded by rodio's decoder, which looks for the format itself.
+fn decode_with_rodio(reader: SoundReader, name: &str) -> AnyResult<SoundSource> {
+    match Decoder::new(reader) {
+        Ok(source) => Ok(Box::new(source)),
+        Err(e) => Err(format!("invalid {} file: {}", name, e).into()),
+    }
+}
+
+struct Wav;
+
+impl AudioFormat for Wav {
+    fn name(&self) -> &'static str {
+        "WAV"
+    }
+    fn probe(&self, header: &[u8]) -> bool {
+        header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE"
+    }
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource> {
+        decode_with_rodio(reader, self.name())
+    }
+}
+
+struct Flac;
+
+impl AudioFormat for Flac {
+    fn name(&self) -> &'static str {
+        "FLAC"
+    }
+    fn probe(&self, header: &[u8]) -> bool {
+        header.starts_with(b"fLaC")
+    }
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource> {
+        decode_with_rodio(reader, self.name())
+    }
+}
+
+/// Whether the file is an Ogg stream whose first packet contains the given signature.
+fn is_ogg_with(header: &[u8], signature: &[u8]) -> bool {
+    header.starts_with(b"OggS")
+        && header
+            .windows(signature.len())
+            .any(|window| window == signature)
+}
+
+struct Vorbis;
+
+impl AudioFormat for Vorbis {
+    fn name(&self) -> &'static str {
+        "Vorbis"
+    }
+    fn probe(&self, header: &[u8]) -> bool {
+        is_ogg_with(header, b"\x01vorbis")
+    }
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource> {
+        decode_with_rodio(reader, self.name())
+    }
+}
+
+struct Opus;
+
+impl AudioFormat for Opus {
+    fn name(&self) -> &'static str {
+        "Opus"
+    }
+    fn probe(&self, header: &[u8]) -> bool {
+        is_ogg_with(header, b"OpusHead")
+    }
+    #[cfg(feature = "opus")]
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource> {
+        Ok(Box::new(OpusDecoder::new(reader)?))
+    }
+    #[cfg(not(feature = "opus"))]
+    fn decode(&self, _reader: SoundReader) -> AnyResult<SoundSource> {
+        Err("this build can't decode Opus files, it was built without the opus feature".into())
+    }
+}
+
+/// Opus always decodes at 48 kHz, whatever the rate of the original audio.
+#[cfg(feature = "opus")]
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// Longest Opus packet, 120 ms, in samples per channel.
+#[cfg(feature = "opus")]
+const OPUS_MAX_FRAME: usize = 5760;
+
+/// Decoder of mono and stereo Ogg Opus files.
+#[cfg(feature = "opus")]
+struct OpusDecoder {
+    packets: ogg::PacketReader<SoundReader>,
+    decoder: audiopus::coder::Decoder,
+    channels: u16,
+    /// Samples decoded from the current packet.
+    buffer: Vec<i16>,
+    /// Index of the next sample in the buffer.
+    index: usize,
+    /// Samples per channel left to drop from the start of the stream, the "pre-skip".
+    skip: usize,
+    /// Samples per channel decoded so far, to trim the end of the stream.
+    decoded: u64,
+}
+
+#[cfg(feature = "opus")]
+impl OpusDecoder {
+    fn new(reader: SoundReader) -> AnyResult<Self> {
+        let mut packets = ogg::PacketReader::new(reader);
+        let head = packets
+            .read_packet()?
+            .ok_or("invalid Opus file: no OpusHead packet")?;
+        let head = &head.data;
+        if head.len() < 19 || !head.starts_with(b"OpusHead") {
+            return Err("invalid Opus file: bad OpusHead packet".into());
+        }
+        let channels = head[9] as u16;
+        let skip = u16::from_le_bytes([head[10], head[11]]) as usize;
+        let mapping_family = head[18];
+        let opus_channels = match (channels, mapping_family) {
+            (1, 0) => audiopus::Channels::Mono,
+            (2, 0) => audiopus::Channels::Stereo,
+            _ => {
+                return Err(
+                    format!("Opus files with {} channels aren't supported", channels).into(),
+                )
+            }
+        };
+        // The comment header isn't needed.
+        packets
+            .read_packet()?
+            .ok_or("invalid Opus file: no OpusTags packet")?;
+        let decoder = audiopus::coder::Decoder::new(audiopus::SampleRate::Hz48000, opus_channels)?;
+        Ok(Self {
+            packets,
+            decoder,
+            channels,
+            buffer: vec![0; OPUS_MAX_FRAME * channels as usize],
+            index: OPUS_MAX_FRAME * channels as usize,
+            skip,
+            decoded: 0,
+        })
+    }
+
+    /// Decode the next packet into the buffer. Returns false at the end of the stream.
+    fn decode_packet(&mut self) -> bool {
+        use std::convert::TryFrom;
+        let channels = self.channels as usize;
+        loop {
+            let packet = match self.packets.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return false,
+                Err(e) => {
+                    warn!("Error while reading an Opus packet: {}", e);
+                    return false;
+                }
+            };
+            self.buffer.resize(OPUS_MAX_FRAME * channels, 0);
+            let input = match audiopus::packet::Packet::try_from(packet.data.as_slice()) {
+                Ok(input) => input,
+                // Empty packets hold no audio.
+                Err(_) => continue,
+            };
+            let output = match audiopus::MutSignals::try_from(self.buffer.as_mut_slice()) {
+                Ok(output) => output,
+                Err(_) => return false,
+            };
+            let mut len = match self.decoder.decode(Some(input), output, false) {
+                Ok(len) => len,
+                Err(e) => {
+                    warn!("Error while decoding an Opus packet: {}", e);
+                    return false;
+                }
+            };
+            // The granule position of the last page is where the stream ends,
+            // so the padding of the last packet is dropped.
+            self.decoded += len as u64;
+            if packet.last_in_stream() && self.decoded > packet.absgp_page() {
+                let extra = (self.decoded - packet.absgp_page()).min(len as u64) as usize;
+                len -= extra;
+            }
+            let skipped = self.skip.min(len);
+            self.skip -= skipped;
+            self.buffer.truncate(len * channels);
+            self.index = skipped * channels;
+            if self.index < self.buffer.len() {
+                return true;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "opus")]
+impl Iterator for OpusDecoder {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        if self.index >= self.buffer.len() && !self.decode_packet() {
+            return None;
+        }
+        let sample = self.buffer[self.index];
+        self.index += 1;
+        Some(sample)
+    }
+}
+
+#[cfg(feature = "opus")]
+impl Source for OpusDecoder {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+struct Mp3;
+
+impl AudioFormat for Mp3 {
+    fn name(&self) -> &'static str {
+        "MP3"
+    }
+    fn probe(&self, header: &[u8]) -> bool {
+        header.starts_with(b"ID3")
+            || (header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0)
+    }
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource> {
+        decode_with_rodio(reader, self.name())
+    }
+}
+
+struct Aiff;
+
+impl AudioFormat for Aiff {
+    fn name(&self) -> &'static str {
+        "AIFF"
+    }
+    fn probe(&self, header: &[u8]) -> bool {
+        header.len() >= 12
+            && &header[0..4] == b"FORM"
+            && (&header[8..12] == b"AIFF" || &header[8..12] == b"AIFC")
+    }
+    fn decode(&self, reader: SoundReader) -> AnyResult<SoundSource> {
+        Ok(Box::new(AiffDecoder::new(reader)?))
+    }
+}
+
+/// Decoder of uncompressed AIFF and AIFF-C files.
+struct AiffDecoder {
+    reader: SoundReader,
+    channels: u16,
+    sample_rate: u32,
+    /// Size of a sample, in bytes.
+    sample_len: usize,
+    /// Whether the samples are little-endian ("sowt" AIFF-C files).
+    little_endian: bool,
+    /// Bytes of sample data left.
+    remaining: u64,
+    /// Total number of sample frames.
+    frames: u64,
+}
+
+impl AiffDecoder {
+    fn new(mut reader: SoundReader) -> AnyResult<Self> {
+        let mut form = [0u8; 12];
+        reader.read_exact(&mut form)?;
+        let is_aifc = &form[8..12] == b"AIFC";
+        // (channels, frames, sample bits, sample rate, little endian)
+        let mut comm: Option<(u16, u64, u16, u32, bool)> = None;
+        // (position, length) of the sample data.
+        let mut ssnd: Option<(u64, u64)> = None;
+        loop {
+            let mut chunk = [0u8; 8];
+            if reader.read_exact(&mut chunk).is_err() {
+                break;
+            }
+            let len = u32::from_be_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+            let start = reader.stream_position()?;
+            match &chunk[0..4] {
+                b"COMM" => {
+                    let mut data = vec![0u8; len.min(64) as usize];
+                    reader.read_exact(&mut data)?;
+                    if data.len() < 18 {
+                        return Err("invalid AIFF file: COMM chunk is too short".into());
+                    }
+                    let channels = u16::from_be_bytes([data[0], data[1]]);
+                    let frames = u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as u64;
+                    let bits = u16::from_be_bytes([data[6], data[7]]);
+                    let sample_rate = extended_to_f64(&data[8..18]) as u32;
+                    let little_endian = if is_aifc && data.len() >= 22 {
+                        match &data[18..22] {
+                            b"NONE" | b"twos" => false,
+                            b"sowt" => true,
+                            other => {
+                                return Err(format!(
+                                    "compressed AIFF-C files aren't supported: {}",
+                                    String::from_utf8_lossy(other)
+                                )
+                                .into())
+                            }
+                        }
+                    } else {
+                        false
+                    };
+                    comm = Some((channels, frames, bits, sample_rate, little_endian));
+                }
+                b"SSND" => {
+                    let mut offset = [0u8; 8];
+                    reader.read_exact(&mut offset)?;
+                    let offset =
+                        u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]) as u64;
+                    ssnd = Some((start + 8 + offset, len.saturating_sub(8 + offset)));
+                }
+                _ => (),
+            }
+            // Chunks are padded to an even length.
+            reader.seek(SeekFrom::Start(start + len + len % 2))?;
+        }
+        let (channels, frames, bits, sample_rate, little_endian) =
+            comm.ok_or("invalid AIFF file: no COMM chunk")?;
+        let (position, len) = ssnd.ok_or("invalid AIFF file: no SSND chunk")?;
+        if channels == 0 || sample_rate == 0 || !(1..=32).contains(&bits) {
+            return Err("invalid AIFF file: bad format".into());
+        }
+        let sample_len = (bits as usize).div_ceil(8);
+        reader.seek(SeekFrom::Start(position))?;
+        Ok(Self {
+            reader,
+            channels,
+            sample_rate,
+            sample_len,
+            little_endian,
+            remaining: len.min(frames * channels as u64 * sample_len as u64),
+            frames,
+        })
+    }
+}
+
+/// Convert an 80-bit IEEE extended float, which AIFF uses for the sample rate.
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let sign = if bytes[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent = (((bytes[0] & 0x7F) as i32) << 8) | bytes[1] as i32;
+    let mut mantissa = [0u8; 8];
+    mantissa.copy_from_slice(&bytes[2..10]);
+    let mantissa = u64::from_be_bytes(mantissa);
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    sign * mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+impl Iterator for AiffDecoder {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.remaining < self.sample_len as u64 {
+            return None;
+        }
+        let mut bytes = [0u8; 4];
+        let bytes = &mut bytes[..self.sample_len];
+        self.reader.read_exact(bytes).ok()?;
+        self.remaining -= self.sample_len as u64;
+        if self.little_endian {
+            bytes.reverse();
+        }
+        // Keep the 16 most significant bits.
+        Some(match bytes.len() {
+            1 => (bytes[0] as i8 as i16) << 8,
+            _ => i16::from_be_bytes([bytes[0], bytes[1]]),
+        })
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let samples = (self.remaining / self.sample_len as u64) as usize;
+        (samples, Some(samples))
+    }
+}
+
+impl Source for AiffDecoder {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(
+            self.frames as f64 / self.sample_rate as f64,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// 44100 as an 80-bit extended float.
+    const RATE_44100: [u8; 10] = [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0];
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn comm(channels: u16, frames: u32, bits: u16, compression: Option<&[u8; 4]>) -> Vec<u8> {
+        let mut data = channels.to_be_bytes().to_vec();
+        data.extend_from_slice(&frames.to_be_bytes());
+        data.extend_from_slice(&bits.to_be_bytes());
+        data.extend_from_slice(&RATE_44100);
+        if let Some(compression) = compression {
+            data.extend_from_slice(compression);
+        }
+        chunk(b"COMM", &data)
+    }
+
+    /// The sample data, after `offset` bytes of padding.
+    fn ssnd(offset: u32, samples: &[u8]) -> Vec<u8> {
+        let mut data = offset.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.resize(data.len() + offset as usize, 0xAA);
+        data.extend_from_slice(samples);
+        chunk(b"SSND", &data)
+    }
+
+    fn form(kind: &[u8; 4], chunks: &[Vec<u8>]) -> SoundReader {
+        let body: Vec<u8> = chunks.concat();
+        let mut file = b"FORM".to_vec();
+        file.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+        file.extend_from_slice(kind);
+        file.extend(body);
+        SoundReader::Memory(Cursor::new(file.into()))
+    }
+
+    #[test]
+    fn extended_floats() {
+        assert_eq!(extended_to_f64(&RATE_44100), 44100.0);
+        assert_eq!(
+            extended_to_f64(&[0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0]),
+            48000.0
+        );
+        assert_eq!(
+            extended_to_f64(&[0x40, 0x0B, 0xFA, 0, 0, 0, 0, 0, 0, 0]),
+            8000.0
+        );
+        assert_eq!(
+            extended_to_f64(&[0x3F, 0xFF, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            1.0
+        );
+        assert_eq!(
+            extended_to_f64(&[0xC0, 0x00, 0xA0, 0, 0, 0, 0, 0, 0, 0]),
+            -2.5
+        );
+        assert_eq!(extended_to_f64(&[0; 10]), 0.0);
+    }
+
+    #[test]
+    fn aiff_big_endian() {
+        let samples = [0x01, 0x02, 0xFF, 0xFE, 0x7F, 0xFF, 0x80, 0x00];
+        let reader = form(
+            b"AIFF",
+            &[
+                // An odd-length chunk is padded, and unknown chunks are skipped.
+                chunk(b"NAME", b"odd"),
+                comm(2, 2, 16, None),
+                ssnd(4, &samples),
+            ],
+        );
+        let decoder = AiffDecoder::new(reader).unwrap();
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.sample_rate(), 44100);
+        assert_eq!(
+            decoder.total_duration(),
+            Some(Duration::from_secs_f64(2.0 / 44100.0))
+        );
+        let decoded: Vec<i16> = decoder.collect();
+        assert_eq!(decoded, vec![0x0102, -2, i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn aifc_little_endian_and_narrow_samples() {
+        let reader = form(
+            b"AIFC",
+            &[
+                comm(1, 2, 16, Some(b"sowt")),
+                ssnd(0, &[0x02, 0x01, 0xFE, 0xFF]),
+            ],
+        );
+        let decoded: Vec<i16> = AiffDecoder::new(reader).unwrap().collect();
+        assert_eq!(decoded, vec![0x0102, -2]);
+        // 8-bit samples are scaled up to 16 bits.
+        let reader = form(b"AIFF", &[comm(1, 2, 8, None), ssnd(0, &[0x40, 0xC0])]);
+        let decoded: Vec<i16> = AiffDecoder::new(reader).unwrap().collect();
+        assert_eq!(decoded, vec![0x4000, -0x4000]);
+        // The frame count limits the samples read, even if the chunk has more.
+        let reader = form(b"AIFF", &[comm(1, 1, 16, None), ssnd(0, &[0, 1, 0, 2])]);
+        let decoded: Vec<i16> = AiffDecoder::new(reader).unwrap().collect();
+        assert_eq!(decoded, vec![1]);
+    }
+
+    #[test]
+    fn aiff_errors() {
+        let reader = form(b"AIFC", &[comm(1, 1, 16, Some(b"ima4")), ssnd(0, &[0, 0])]);
+        assert!(AiffDecoder::new(reader).is_err());
+        let reader = form(b"AIFF", &[comm(1, 1, 16, None)]);
+        assert!(AiffDecoder::new(reader).is_err());
+        let reader = form(b"AIFF", &[ssnd(0, &[0, 0])]);
+        assert!(AiffDecoder::new(reader).is_err());
+        let reader = form(b"AIFF", &[comm(0, 1, 16, None), ssnd(0, &[0, 0])]);
+        assert!(AiffDecoder::new(reader).is_err());
+    }
+
+    #[test]
+    fn decode_probes_aiff() {
+        let reader = form(b"AIFF", &[comm(1, 1, 16, None), ssnd(0, &[0, 7])]);
+        let decoded: Vec<i16> = decode(reader).unwrap().collect();
+        assert_eq!(decoded, vec![7]);
+    }
+}
@@ -0,0 +1,294 @@
+use super::*;
+use std::collections::HashMap;
+
+/// The value of a game state variable.
+#[derive(Clone, Debug)]
+pub enum StateValue {
+    Text(Box<str>),
+    Number(f32),
+}
+
+impl StateValue {
+    /// Numbers are parsed as such, anything else is text.
+    pub fn parse(text: &str) -> Self {
+        match text.trim().parse() {
+            Ok(number) => StateValue::Number(number),
+            Err(_) => StateValue::Text(text.into()),
+        }
+    }
+
+    fn as_number(&self) -> Option<f32> {
+        match self {
+            StateValue::Number(number) => Some(*number),
+            StateValue::Text(text) => text.trim().parse().ok(),
+        }
+    }
+
+    fn equals(&self, text: &str) -> bool {
+        match self {
+            StateValue::Text(value) => value.eq_ignore_ascii_case(text.trim()),
+            StateValue::Number(number) => text.trim().parse() == Ok(*number),
+        }
+    }
+}
+
+/// A variable declared by `<stateVariable>`.
+#[derive(Clone)]
+pub struct StateVariable {
+    pub name: Box<str>,
+    /// The initial value.
+    pub default: StateValue,
+    /// How much a numeric variable falls back towards its default per second.
+    pub decay: f32,
+    /// The highest value of a numeric variable.
+    pub max: Option<f32>,
+}
+
+/// What a `<stateUpdate>` does to its variable.
+#[derive(Clone)]
+pub enum StateChange {
+    /// Set the variable. `$1` and `${name}` are replaced by the captured values.
+    Set(Box<str>),
+    /// Add to a numeric variable.
+    Add(f32),
+}
+
+/// A `<stateUpdate>`: when the pattern matches a log line, change the variable.
+pub struct StateUpdate {
+    pub variable: Box<str>,
+    pub pattern: Regex,
+    pub change: StateChange,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A condition on a game state variable, like `siege!=true`, `season=winter|spring`, or `intensity>=20`.
+#[derive(Clone, Debug)]
+pub struct StateCondition {
+    pub variable: Box<str>,
+    pub comparison: Comparison,
+    /// Alternatives separated by `|`. Only the first one is used by ordering comparisons.
+    pub values: Vec<Box<str>>,
+}
+
+impl StateCondition {
+    /// Parse a comma separated list of conditions, all of which have to hold.
+    pub fn parse_all(text: &str) -> Option<Vec<Self>> {
+        text.split(',')
+            .filter(|part| !part.trim().is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    /// Parse a single condition.
+    pub fn parse(text: &str) -> Option<Self> {
+        let idx = text.find(['=', '!', '<', '>'])?;
+        let (variable, rest) = text.split_at(idx);
+        let (comparison, values) = [
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("=", Comparison::Equal),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ]
+        .iter()
+        .find_map(|(op, comparison)| rest.strip_prefix(op).map(|values| (*comparison, values)))?;
+        let variable = variable.trim();
+        if variable.is_empty() {
+            return None;
+        }
+        Some(Self {
+            variable: variable.into(),
+            comparison,
+            values: values.split('|').map(|v| v.trim().into()).collect(),
+        })
+    }
+
+    fn is_met(&self, value: &StateValue) -> bool {
+        let ordering = || {
+            let value = value.as_number()?;
+            let other: f32 = self.values.first()?.parse().ok()?;
+            value.partial_cmp(&other)
+        };
+        use std::cmp::Ordering::*;
+        match self.comparison {
+            Comparison::Equal => self.values.iter().any(|v| value.equals(v)),
+            Comparison::NotEqual => !self.values.iter().any(|v| value.equals(v)),
+            Comparison::Less => ordering() == Some(Less),
+            Comparison::LessOrEqual => matches!(ordering(), Some(Less) | Some(Equal)),
+            Comparison::Greater => ordering() == Some(Greater),
+            Comparison::GreaterOrEqual => matches!(ordering(), Some(Greater) | Some(Equal)),
+        }
+    }
+}
+
+/// Variables describing the state of the game, kept up to date from the log lines.
+pub struct GameState {
+    variables: Vec<StateVariable>,
+    values: Vec<StateValue>,
+    indices: HashMap<Box<str>, usize>,
+    /// The updates, with the index of their variable.
+    updates: Vec<(usize, StateUpdate)>,
+    update_patterns: PatternSet,
+    /// Buffer for the indices of the updates matching the current log line.
+    matched: Vec<usize>,
+}
+
+impl GameState {
+    pub fn new(variables: Vec<StateVariable>, updates: Vec<StateUpdate>) -> AnyResult<Self> {
+        let mut indices = HashMap::new();
+        for (i, variable) in variables.iter().enumerate() {
+            if indices.insert(variable.name.clone(), i).is_some() {
+                warn!(
+                    "The StateVariable {} was declared more than once.",
+                    variable.name
+                );
+                warn!("Will use the last declaration.");
+            }
+        }
+        let updates: Vec<(usize, StateUpdate)> = updates
+            .into_iter()
+            .filter_map(|update| match indices.get(&update.variable) {
+                Some(&i) => match (&update.change, &variables[i].default) {
+                    (StateChange::Add(_), StateValue::Text(_)) => {
+                        warn!(
+                            "A StateUpdate adds to the non-numeric variable: {}",
+                            update.variable
+                        );
+                        warn!("Will ignore this StateUpdate.");
+                        None
+                    }
+                    _ => Some((i, update)),
+                },
+                None => {
+                    warn!(
+                        "A StateUpdate refers to an undeclared variable: {}",
+                        update.variable
+                    );
+                    warn!("Will ignore this StateUpdate.");
+                    None
+                }
+            })
+            .collect();
+        let update_patterns = PatternSet::new(updates.iter().map(|(_, u)| u.pattern.as_str()))?;
+        let values = variables.iter().map(|v| v.default.clone()).collect();
+        Ok(Self {
+            variables,
+            values,
+            indices,
+            updates,
+            update_patterns,
+            matched: Vec::new(),
+        })
+    }
+
+    /// Warn about conditions on variables that aren't declared.
+    pub fn check_conditions(&self, conditions: &[StateCondition]) {
+        for condition in conditions {
+            if !self.indices.contains_key(&condition.variable) {
+                warn!(
+                    "A condition refers to an undeclared StateVariable: {}",
+                    condition.variable
+                );
+                warn!("Will treat it as never met.");
+            }
+        }
+    }
+
+    /// The current value of a variable.
+    pub fn get(&self, name: &str) -> Option<&StateValue> {
+        self.indices.get(name).map(|&i| &self.values[i])
+    }
+
+    /// The current value of a variable, as a number.
+    pub fn get_number(&self, name: &str) -> Option<f32> {
+        self.get(name).and_then(StateValue::as_number)
+    }
+
+    /// Whether all the conditions hold.
+    pub fn all_met(&self, conditions: &[StateCondition]) -> bool {
+        conditions.iter().all(|condition| {
+            self.get(&condition.variable)
+                .is_some_and(|value| condition.is_met(value))
+        })
+    }
+
+    /// Apply the updates whose pattern matches the log line.
+    pub fn process_log(&mut self, log: &str) {
+        if self.updates.is_empty() {
+            return;
+        }
+        self.update_patterns.matches_into(log, &mut self.matched);
+        for &i in self.matched.iter() {
+            let (idx, update) = &self.updates[i];
+            let variable = &self.variables[*idx];
+            let value = &mut self.values[*idx];
+            match &update.change {
+                StateChange::Set(template) => {
+                    let mut text = String::new();
+                    if let Some(captures) = update.pattern.captures(log) {
+                        captures.expand(template, &mut text);
+                    }
+                    *value = match variable.default {
+                        StateValue::Number(_) => match text.trim().parse() {
+                            Ok(number) => StateValue::Number(clamp(number, variable.max)),
+                            Err(_) => {
+                                trace!("  can't set {} to {:?}: not a number", variable.name, text);
+                                continue;
+                            }
+                        },
+                        StateValue::Text(_) => StateValue::Text(text.into()),
+                    };
+                }
+                StateChange::Add(amount) => {
+                    if let StateValue::Number(number) = value {
+                        *number = clamp(*number + amount, variable.max);
+                    }
+                }
+            }
+            trace!(" state: {} = {:?}", variable.name, value);
+        }
+    }
+
+    /// Whether the numeric variables are done decaying towards their defaults.
+    pub fn is_settled(&self) -> bool {
+        self.variables
+            .iter()
+            .zip(self.values.iter())
+            .all(|(variable, value)| match (&variable.default, value) {
+                (StateValue::Number(default), StateValue::Number(number)) => {
+                    variable.decay == 0.0 || number == default
+                }
+                _ => true,
+            })
+    }
+
+    /// Decay the numeric variables towards their defaults.
+    pub fn maintain(&mut self, dt: usize) {
+        for (variable, value) in self.variables.iter().zip(self.values.iter_mut()) {
+            if let (StateValue::Number(default), StateValue::Number(number)) =
+                (&variable.default, value)
+            {
+                let step = variable.decay * dt as f32 / 1000.0;
+                if *number > *default {
+                    *number = (*number - step).max(*default);
+                } else {
+                    *number = (*number + step).min(*default);
+                }
+            }
+        }
+    }
+}
+
+fn clamp(number: f32, max: Option<f32>) -> f32 {
+    max.map_or(number, |max| number.min(max))
+}
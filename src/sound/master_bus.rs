@@ -0,0 +1,157 @@
+use super::*;
+use rodio::dynamic_mixer::{self, DynamicMixerController};
+
+/// Every source is mixed here before reaching the device,
+/// so the whole mix goes through a single compressor/limiter.
+pub struct MasterBus {
+    controller: Arc<DynamicMixerController<f32>>,
+    /// Plays the mix on the device, until the bus is dropped.
+    stream: DeviceStream,
+}
+
+impl MasterBus {
+    /// Start mixing into the device.
+    /// Fails if the device can't be used, as when it was unplugged.
+    pub fn new(device: &Device, setting: MasterSetting) -> AnyResult<Self> {
+        let mut controller = None;
+        let stream = DeviceStream::new(device, |channels, sample_rate| {
+            let (mixer_controller, mixer) = dynamic_mixer::mixer(channels, sample_rate);
+            // The mixer ends when it runs out of sources, so keep a silent one in it.
+            mixer_controller.add(source::Zero::<f32>::new(channels, sample_rate));
+            controller = Some(mixer_controller);
+            Compressor::new(mixer, setting)
+        })?;
+        Ok(Self {
+            controller: controller.ok_or_else(|| {
+                SoundError::Internal("the master bus was opened without a mixer".to_string())
+            })?,
+            stream,
+        })
+    }
+
+    /// Play a source through the master bus.
+    #[inline]
+    pub fn add<S>(&self, source: S)
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        self.controller.add(source);
+    }
+
+    /// Whether the device stopped playing the bus since the last check.
+    #[inline]
+    pub fn is_lost(&mut self) -> bool {
+        self.stream.is_lost()
+    }
+}
+
+/// Feed-forward compressor with a soft knee.
+/// The envelope follows the peaks of all the channels together, so the stereo image doesn't shift.
+struct Compressor<S> {
+    input: S,
+    setting: MasterSetting,
+    /// Smoothing coefficients of the envelope, per sample.
+    attack: f32,
+    release: f32,
+    /// Current peak level, linear.
+    envelope: f32,
+}
+
+impl<S> Compressor<S>
+where
+    S: Source<Item = f32>,
+{
+    fn new(input: S, setting: MasterSetting) -> Self {
+        let rate = input.sample_rate() as f32 * input.channels() as f32;
+        let coefficient = |ms: usize| {
+            if ms == 0 {
+                0.0
+            } else {
+                (-1000.0 / (ms as f32 * rate)).exp()
+            }
+        };
+        Self {
+            attack: coefficient(setting.attack),
+            release: coefficient(setting.release),
+            input,
+            setting,
+            envelope: 0.0,
+        }
+    }
+
+    /// Gain change in decibels for a level in decibels.
+    fn gain_db(&self, level: f32) -> f32 {
+        let MasterSetting {
+            threshold,
+            ratio,
+            knee,
+            ..
+        } = self.setting;
+        let over = level - threshold;
+        let output = if 2.0 * over < -knee {
+            level
+        } else if knee > 0.0 && 2.0 * over.abs() <= knee {
+            level + (1.0 / ratio - 1.0) * (over + knee / 2.0).powi(2) / (2.0 * knee)
+        } else {
+            threshold + over / ratio
+        };
+        output - level
+    }
+}
+
+impl<S> Iterator for Compressor<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        let level = sample.abs();
+        let coefficient = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        self.envelope = coefficient * self.envelope + (1.0 - coefficient) * level;
+        let gain = if self.envelope > 0.0 {
+            let level_db = 20.0 * self.envelope.log10();
+            db_to_gain(self.gain_db(level_db) + self.setting.makeup)
+        } else {
+            db_to_gain(self.setting.makeup)
+        };
+        // Whatever the attack missed is clipped here.
+        Some((sample * gain).clamp(-1.0, 1.0))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<S> Source for Compressor<S>
+where
+    S: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
@@ -0,0 +1,269 @@
+use super::*;
+use std::collections::HashMap;
+
+/// Names of the available output devices.
+pub fn list_devices() -> Vec<Box<str>> {
+    match output_devices() {
+        Ok(devices) => devices
+            .filter_map(|device| device.name().ok())
+            .map(Box::from)
+            .collect(),
+        Err(e) => {
+            warn!("Failed to list the output devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// The output devices with their names, or None if they can't be listed.
+fn named_devices() -> Option<Vec<(String, Device)>> {
+    let devices = output_devices().ok()?;
+    Some(
+        devices
+            .filter_map(|device| device.name().ok().map(|n| (n, device)))
+            .collect(),
+    )
+}
+
+/// Find an output device by its name, or by a part of it, ignoring case.
+/// An exact match wins over a partial one.
+/// The devices are listed into `devices` when first needed, so several can be found with one listing.
+fn find_device(name: &str, devices: &mut Option<Vec<(String, Device)>>) -> Option<Device> {
+    if devices.is_none() {
+        *devices = named_devices();
+    }
+    let devices = devices.as_mut()?;
+    let lowercase = name.to_lowercase();
+    let idx = devices.iter().position(|(n, _)| n == name).or_else(|| {
+        devices
+            .iter()
+            .position(|(n, _)| n.to_lowercase().contains(&lowercase))
+    })?;
+    Some(devices.swap_remove(idx).1)
+}
+
+/// Open a bus on the named device, or on the default device of the system.
+fn open_bus(
+    name: Option<&str>,
+    setting: MasterSetting,
+    devices: &mut Option<Vec<(String, Device)>>,
+) -> AnyResult<MasterBus> {
+    let device = match name {
+        Some(name) => find_device(name, devices)
+            .ok_or_else(|| format!("Output device {} isn't available.", name))?,
+        None => default_output_device().ok_or("Failed to get default audio output device.")?,
+    };
+    MasterBus::new(&device, setting)
+}
+
+/// Name shown for the default output device of the system.
+const DEFAULT_DEVICE: &str = "default device";
+
+/// Most checks skipped between attempts to open the devices that are missing.
+/// Opening them lists every device, so it is tried less often the longer they stay away.
+const MAX_REOPEN_INTERVAL: u32 = 16;
+
+/// What happened to an output device when the outputs were checked.
+pub enum DeviceEvent {
+    /// The device stopped playing, as when it was unplugged.
+    Lost(Box<str>),
+    /// The device plays again, or became available.
+    Recovered(Box<str>),
+}
+
+/// What changed when the outputs were checked.
+#[derive(Default)]
+pub struct DeviceCheck {
+    /// Whether the main bus was replaced.
+    pub main_changed: bool,
+    /// Routed devices whose channels moved, to the main bus or back.
+    pub routes_changed: Vec<Box<str>>,
+    pub events: Vec<DeviceEvent>,
+}
+
+/// The buses of the output devices the channels play on.
+pub struct Outputs {
+    setting: MasterSetting,
+    /// The device chosen by the user, or None for the default device of the system.
+    main_name: Option<Box<str>>,
+    /// Channels without a device of their own play here,
+    /// as do channels whose device isn't available.
+    main: MasterBus,
+    /// Whether the main device is lost. The main bus then plays on the default device,
+    /// or doesn't play at all, until the main device can be opened again.
+    main_lost: bool,
+    /// Buses of the devices that channels are routed to, by the name given in their channelSetting.
+    routed: HashMap<Box<str>, MasterBus>,
+    /// Devices that channels are routed to, but aren't available.
+    /// Their channels play on the main device until they are.
+    missing: HashSet<Box<str>>,
+    /// Checks to skip before trying to open the missing devices again.
+    reopen_wait: u32,
+    /// Checks skipped after the next failed attempt. Doubles with every failure.
+    reopen_interval: u32,
+}
+
+impl Outputs {
+    /// Open the main device.
+    /// If it isn't available, falls back to the default device until it is.
+    pub fn new(main_name: Option<&str>, setting: MasterSetting) -> AnyResult<Self> {
+        let (main, main_lost) = match open_bus(main_name, setting, &mut None) {
+            Ok(bus) => (bus, false),
+            Err(e) if main_name.is_some() => {
+                warn!("{}", e);
+                warn!("Will use the default device.");
+                (open_bus(None, setting, &mut None)?, true)
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            setting,
+            main_name: main_name.map(Box::from),
+            main,
+            main_lost,
+            routed: HashMap::new(),
+            missing: HashSet::new(),
+            reopen_wait: 0,
+            reopen_interval: 0,
+        })
+    }
+
+    /// The main device chosen by the user, even while it is lost.
+    #[inline]
+    pub fn main_name(&self) -> Option<&str> {
+        self.main_name.as_deref()
+    }
+
+    /// Whether the main device isn't playing, so another device is used if possible.
+    #[inline]
+    pub fn is_main_lost(&self) -> bool {
+        self.main_lost
+    }
+
+    /// Play on another main device, or on the default device if None.
+    /// Keeps the current device if the new one can't be opened.
+    pub fn set_main(&mut self, name: Option<&str>) -> AnyResult<()> {
+        self.main = open_bus(name, self.setting, &mut None)?;
+        self.main_name = name.map(Box::from);
+        self.main_lost = false;
+        Ok(())
+    }
+
+    /// Open the device a channel is routed to, unless it already is.
+    /// Returns false if it isn't available, so the channel plays on the main device until it is.
+    pub fn route(&mut self, name: &str) -> bool {
+        if self.routed.contains_key(name) {
+            return true;
+        }
+        if self.missing.contains(name) {
+            return false;
+        }
+        match open_bus(Some(name), self.setting, &mut None) {
+            Ok(bus) => {
+                self.routed.insert(name.into(), bus);
+                true
+            }
+            Err(e) => {
+                warn!("{}", e);
+                warn!("Will use the main device until it is available.");
+                self.missing.insert(name.into());
+                false
+            }
+        }
+    }
+
+    /// Whether the channels routed to this device play on it.
+    #[inline]
+    pub fn is_routed(&self, name: &str) -> bool {
+        self.routed.contains_key(name)
+    }
+
+    /// The bus of the given device, or the main bus if None or if it isn't routed.
+    #[inline]
+    pub fn bus(&self, name: Option<&str>) -> &MasterBus {
+        name.and_then(|name| self.routed.get(name))
+            .unwrap_or(&self.main)
+    }
+
+    /// Find the devices that stopped playing, and try to open them again.
+    /// Lost routed devices send their channels to the main device until they come back.
+    /// A lost main device is replaced by the default device until it comes back.
+    /// Devices that stay missing are tried less and less often, up to every MAX_REOPEN_INTERVAL checks.
+    pub fn check(&mut self) -> DeviceCheck {
+        let mut check = DeviceCheck::default();
+        let setting = self.setting;
+
+        let main_display: Box<str> = self.main_name().unwrap_or(DEFAULT_DEVICE).into();
+        let is_main_stalled = self.main.is_lost();
+        if is_main_stalled && !self.main_lost {
+            warn!("Output device {} was lost.", main_display);
+            check.events.push(DeviceEvent::Lost(main_display.clone()));
+            self.main_lost = true;
+        }
+        let lost: Vec<Box<str>> = self
+            .routed
+            .iter_mut()
+            .filter_map(|(name, bus)| bus.is_lost().then(|| name.clone()))
+            .collect();
+        for name in lost {
+            warn!("Output device {} was lost.", name);
+            self.routed.remove(&name);
+            self.missing.insert(name.clone());
+            check.routes_changed.push(name.clone());
+            check.events.push(DeviceEvent::Lost(name));
+        }
+        // A device that was just lost may only have hiccuped, so it is tried again right away.
+        if !check.events.is_empty() {
+            self.reopen_wait = 0;
+            self.reopen_interval = 0;
+        }
+        if !self.main_lost && self.missing.is_empty() {
+            return check;
+        }
+        if self.reopen_wait > 0 {
+            self.reopen_wait -= 1;
+            return check;
+        }
+
+        let mut devices = None;
+        if self.main_lost {
+            match open_bus(self.main_name(), setting, &mut devices) {
+                Ok(bus) => {
+                    info!("Output device {} plays again.", main_display);
+                    self.main = bus;
+                    self.main_lost = false;
+                    check.main_changed = true;
+                    check.events.push(DeviceEvent::Recovered(main_display));
+                }
+                // Keep playing on the default device until the main device is back.
+                Err(_) if is_main_stalled && self.main_name.is_some() => {
+                    if let Ok(bus) = open_bus(None, setting, &mut devices) {
+                        self.main = bus;
+                        check.main_changed = true;
+                    }
+                }
+                Err(_) => (),
+            }
+        }
+        let found: Vec<(Box<str>, MasterBus)> = self
+            .missing
+            .iter()
+            .filter_map(|name| {
+                open_bus(Some(name), setting, &mut devices)
+                    .ok()
+                    .map(|bus| (name.clone(), bus))
+            })
+            .collect();
+        for (name, bus) in found {
+            info!("Output device {} plays again.", name);
+            self.missing.remove(&name);
+            self.routed.insert(name.clone(), bus);
+            check.routes_changed.retain(|changed| *changed != name);
+            check.routes_changed.push(name.clone());
+            check.events.push(DeviceEvent::Recovered(name));
+        }
+        self.reopen_wait = self.reopen_interval;
+        self.reopen_interval = (self.reopen_interval * 2).clamp(1, MAX_REOPEN_INTERVAL);
+        check
+    }
+}
@@ -0,0 +1,103 @@
+use regex::{RegexSet, RegexSetBuilder};
+
+/// Number of patterns compiled into each RegexSet.
+/// Bigger sets scan faster, but build bigger automatons that may hit the size limits.
+const CHUNK_SIZE: usize = 64;
+/// Compiled program size limit of each RegexSet, in bytes.
+const SIZE_LIMIT: usize = 64 << 20;
+/// Lazy DFA cache size limit of each RegexSet, in bytes.
+const DFA_SIZE_LIMIT: usize = 16 << 20;
+
+/// Many regex patterns compiled into chunked RegexSets,
+/// so a line is tested against all of them in a single pass per chunk.
+pub struct PatternSet {
+    chunks: Vec<RegexSet>,
+}
+
+impl PatternSet {
+    /// Compile the patterns. Pattern indices follow the iteration order.
+    pub fn new<'a, I>(patterns: I) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let patterns: Vec<&str> = patterns.into_iter().collect();
+        let mut chunks = Vec::with_capacity(patterns.len().div_ceil(CHUNK_SIZE));
+        for chunk in patterns.chunks(CHUNK_SIZE) {
+            let set = RegexSetBuilder::new(chunk)
+                .size_limit(SIZE_LIMIT)
+                .dfa_size_limit(DFA_SIZE_LIMIT)
+                .build()?;
+            chunks.push(set);
+        }
+        trace!(
+            "Compiled {} patterns into {} RegexSets",
+            patterns.len(),
+            chunks.len()
+        );
+        Ok(Self { chunks })
+    }
+
+    #[inline]
+    pub fn empty() -> Self {
+        Self { chunks: Vec::new() }
+    }
+
+    /// Whether any of the patterns match the text.
+    pub fn is_match(&self, text: &str) -> bool {
+        self.chunks.iter().any(|set| set.is_match(text))
+    }
+
+    /// Replace the contents of `out` with the indices of all the patterns matching the text,
+    /// in ascending order.
+    pub fn matches_into(&self, text: &str, out: &mut Vec<usize>) {
+        out.clear();
+        for (chunk_idx, set) in self.chunks.iter().enumerate() {
+            let offset = chunk_idx * CHUNK_SIZE;
+            out.extend(set.matches(text).into_iter().map(|idx| offset + idx));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_across_chunks() {
+        // Every pattern matches "a", and the last chunk is only partly filled.
+        let count = CHUNK_SIZE * 2 + 3;
+        let patterns: Vec<String> = (0..count).map(|i| format!("^(a|{})$", i)).collect();
+        let set = PatternSet::new(patterns.iter().map(String::as_str)).unwrap();
+        assert_eq!(set.chunks.len(), 3);
+        let mut out = Vec::new();
+        set.matches_into("a", &mut out);
+        assert_eq!(out, (0..count).collect::<Vec<_>>());
+        // Only pattern 130 matches "130", in the last chunk.
+        set.matches_into("130", &mut out);
+        assert_eq!(out, vec![130]);
+        assert!(!set.is_match("b"));
+    }
+
+    #[test]
+    fn matches_in_order_without_duplicates() {
+        let patterns = ["dog", "cat", "^the", "the (cat|dog)", "dog"];
+        let set = PatternSet::new(patterns.iter().copied()).unwrap();
+        let mut out = vec![42];
+        set.matches_into("the dog chased the cat", &mut out);
+        // Each pattern appears once, even if it matches several times, or another pattern is the same.
+        assert_eq!(out, vec![0, 1, 2, 3, 4]);
+        set.matches_into("a cat", &mut out);
+        assert_eq!(out, vec![1]);
+        set.matches_into("nothing", &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn empty_set_matches_nothing() {
+        let set = PatternSet::new(std::iter::empty()).unwrap();
+        let mut out = vec![1];
+        set.matches_into("anything", &mut out);
+        assert!(out.is_empty());
+        assert!(!PatternSet::empty().is_match("anything"));
+    }
+}
@@ -0,0 +1,332 @@
+use super::*;
+use std::collections::BTreeMap;
+
+/// A track of a playlist.
+#[derive(Clone, Debug)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    /// Title from `#EXTINF` or `TitleN`, if given.
+    pub title: Option<Box<str>>,
+    /// Duration from `#EXTINF` or `LengthN`, if given and known.
+    pub duration: Option<Duration>,
+}
+
+/// Convert a playlist into its tracks.
+/// Supports M3U/M3U8 with `#EXTINF` and PLS with `FileN`/`TitleN`/`LengthN`.
+/// Entries that can't be resolved to a file are left out, and added to `unresolved`.
+pub fn parse_playlist(
+    path: &Path,
+    unresolved: &mut Vec<SoundError>,
+) -> AnyResult<Vec<PlaylistEntry>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .filter(|ext| ext == "m3u" || ext == "m3u8" || ext == "pls")
+        .ok_or_else(|| {
+            format!(
+                "Playlist {:?} is not valid! Playlist needs to have either .m3u, .m3u8 or .pls extension.",
+                path
+            )
+        })?;
+    let text = read_text(path)?;
+    let parent_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let raw_entries = if extension == "pls" {
+        parse_pls(path, &text, unresolved)
+    } else {
+        parse_m3u(&text)
+    };
+
+    let mut entries = Vec::with_capacity(raw_entries.len());
+    for (location, title, duration) in raw_entries {
+        match resolve(parent_path, &location) {
+            Ok(entry_path) => {
+                trace!("   Playlist Entry: {:?}", entry_path);
+                entries.push(PlaylistEntry {
+                    path: entry_path,
+                    title,
+                    duration,
+                });
+            }
+            Err(reason) => {
+                warn!(
+                    "Playlist {:?} has an unresolvable entry {}: {}",
+                    path, location, reason
+                );
+                warn!("Will ignore this entry.");
+                unresolved.push(SoundError::Playlist {
+                    path: path.into(),
+                    message: format!("can't play {}: {}", location, reason),
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Read the playlist as UTF-8, falling back to Latin-1 for old M3U files.
+fn read_text(path: &Path) -> AnyResult<String> {
+    let bytes = fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    Ok(match text.strip_prefix('\u{feff}') {
+        Some(text) => text.to_string(),
+        None => text,
+    })
+}
+
+/// Location, title and duration of an entry.
+type RawEntry = (String, Option<Box<str>>, Option<Duration>);
+/// A PLS entry's `FileN`, `TitleN` and `LengthN`, which may come in any order.
+type PlsEntry = (Option<String>, Option<Box<str>>, Option<Duration>);
+
+/// Entries of an M3U playlist.
+/// `#EXTINF:<seconds>[ <attributes>],<title>` describes the entry on the following line.
+/// Other lines starting with `#` are comments.
+fn parse_m3u(text: &str) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut info: Option<(Option<Box<str>>, Option<Duration>)> = None;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let (head, title) = match extinf.find(',') {
+                Some(idx) => (&extinf[..idx], Some(extinf[idx + 1..].trim())),
+                None => (extinf, None),
+            };
+            let duration = head
+                .split_whitespace()
+                .next()
+                .and_then(|seconds| seconds.parse().ok())
+                .and_then(to_duration);
+            let title = title.filter(|t| !t.is_empty()).map(Box::from);
+            info = Some((title, duration));
+        } else if !line.starts_with('#') {
+            let (title, duration) = info.take().unwrap_or((None, None));
+            entries.push((line.to_string(), title, duration));
+        }
+    }
+    entries
+}
+
+/// Entries of a PLS playlist, ordered by their number.
+/// Entries without a `FileN` are left out, and added to `unresolved`.
+fn parse_pls(path: &Path, text: &str, unresolved: &mut Vec<SoundError>) -> Vec<RawEntry> {
+    let mut numbered: BTreeMap<u32, PlsEntry> = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        let (key, value) = match line.find('=') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => continue,
+        };
+        let key = key.to_ascii_lowercase();
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (field, number) = key.split_at(split);
+        let number: u32 = match number.parse() {
+            Ok(number) => number,
+            Err(_) => continue,
+        };
+        let entry = numbered.entry(number).or_default();
+        match field {
+            "file" => entry.0 = Some(value.to_string()),
+            "title" => entry.1 = Some(value.into()),
+            "length" => entry.2 = value.parse().ok().and_then(to_duration),
+            _ => (),
+        }
+    }
+    numbered
+        .into_iter()
+        .filter_map(|(number, (location, title, duration))| match location {
+            Some(location) => Some((location, title, duration)),
+            None => {
+                warn!("Playlist {:?} is missing File{}.", path, number);
+                warn!("Will ignore this entry.");
+                unresolved.push(SoundError::Playlist {
+                    path: path.into(),
+                    message: format!("entry {} is missing File{}", number, number),
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+/// Negative durations mean the length is unknown.
+fn to_duration(seconds: f64) -> Option<Duration> {
+    if seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+/// Find the file an entry refers to.
+/// Relative paths are relative to the playlist's folder. `file://` URLs are local paths;
+/// other URLs can't be played.
+fn resolve(parent_path: &Path, location: &str) -> std::result::Result<PathBuf, String> {
+    let location = if let Some(rest) = location.strip_prefix("file://") {
+        // "file:///C:/..." on Windows, "file:///home/..." elsewhere.
+        let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+        if cfg!(windows) {
+            rest.trim_start_matches('/').to_string()
+        } else {
+            rest.to_string()
+        }
+    } else if is_url(location) {
+        return Err("URLs aren't supported".to_string());
+    } else if cfg!(windows) {
+        location.to_string()
+    } else {
+        // Playlists made on Windows use backslashes.
+        location.replace('\\', "/")
+    };
+    let path = Path::new(&location);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        parent_path.join(path)
+    };
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err("no such file".to_string())
+    }
+}
+
+/// Whether the location starts with a URL scheme, like `http://`.
+/// A single letter is a Windows drive, not a scheme.
+fn is_url(location: &str) -> bool {
+    match location.find("://") {
+        Some(idx) => {
+            idx > 1
+                && location[..idx]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for each test, removed at the end.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "soundsense-rs-playlist-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self, name: &str, contents: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn m3u_extinf() {
+        let entries = parse_m3u(
+            "#EXTM3U\n\
+             #EXTINF:123 tvg-id=\"x\",Artist - Title\n\
+             music/one.ogg\n\
+             # a comment\n\
+             \n\
+             two.ogg\n\
+             #EXTINF:-1,\n\
+             three.ogg\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, "music/one.ogg");
+        assert_eq!(entries[0].1.as_deref(), Some("Artist - Title"));
+        assert_eq!(entries[0].2, Some(Duration::from_secs(123)));
+        // #EXTINF only describes the line that follows it.
+        assert_eq!(entries[1], ("two.ogg".to_string(), None, None));
+        // A negative duration is unknown, and an empty title isn't a title.
+        assert_eq!(entries[2], ("three.ogg".to_string(), None, None));
+    }
+
+    #[test]
+    fn pls_fields() {
+        let mut unresolved = Vec::new();
+        let entries = parse_pls(
+            Path::new("list.pls"),
+            "[playlist]\n\
+             Title2=Second\n\
+             File2=two.ogg\n\
+             file1 = one.ogg\n\
+             Length1=61.5\n\
+             Title3=No file\n\
+             Length2=-1\n\
+             NumberOfEntries=3\n\
+             Version=2\n",
+            &mut unresolved,
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, "one.ogg");
+        assert_eq!(entries[0].1, None);
+        assert_eq!(entries[0].2, Some(Duration::from_secs_f64(61.5)));
+        assert_eq!(entries[1].0, "two.ogg");
+        assert_eq!(entries[1].1.as_deref(), Some("Second"));
+        assert_eq!(entries[1].2, None);
+        // Entry 3 has no File3.
+        assert_eq!(unresolved.len(), 1);
+    }
+
+    #[test]
+    fn text_bom_and_latin1() {
+        let dir = TempDir::new("text");
+        let bom = dir.file("bom.m3u8", "\u{feff}#EXTM3U\nété.ogg\n".as_bytes());
+        assert_eq!(read_text(&bom).unwrap(), "#EXTM3U\nété.ogg\n");
+        // "été" in Latin-1 isn't valid UTF-8.
+        let latin1 = dir.file("latin1.m3u", b"\xe9t\xe9.ogg\n");
+        assert_eq!(read_text(&latin1).unwrap(), "été.ogg\n");
+    }
+
+    #[test]
+    fn resolve_locations() {
+        let dir = TempDir::new("resolve");
+        let track = dir.file("track.ogg", b"");
+        assert_eq!(resolve(&dir.0, "track.ogg"), Ok(track.clone()));
+        assert!(resolve(&dir.0, "missing.ogg").is_err());
+        let url = format!("file://{}", track.display());
+        assert_eq!(resolve(Path::new(""), &url), Ok(track.clone()));
+        let url = format!("file://localhost{}", track.display());
+        assert_eq!(resolve(Path::new(""), &url), Ok(track));
+        assert_eq!(
+            resolve(&dir.0, "http://example.com/track.ogg"),
+            Err("URLs aren't supported".to_string())
+        );
+    }
+
+    #[test]
+    fn playlist_reports_unresolved() {
+        let dir = TempDir::new("unresolved");
+        dir.file("one.ogg", b"");
+        let path = dir.file(
+            "list.m3u",
+            b"#EXTINF:10,One\none.ogg\nmissing.ogg\nhttps://example.com/x.ogg\n",
+        );
+        let mut unresolved = Vec::new();
+        let entries = parse_playlist(&path, &mut unresolved).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].title.as_deref(), Some("One"));
+        assert_eq!(unresolved.len(), 2);
+    }
+}
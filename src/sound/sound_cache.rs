@@ -0,0 +1,346 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Files played on demand are cached if they take at most this fraction of the budget,
+/// so a long music track doesn't evict all the short sounds. Preloaded files may be larger.
+const MAX_ENTRY_FRACTION: usize = 8;
+
+/// Reads a sound file from the cache, or from the disk if it isn't cached.
+pub enum SoundReader {
+    Memory(Cursor<Arc<[u8]>>),
+    Disk(BufReader<File>),
+}
+
+impl Read for SoundReader {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SoundReader::Memory(cursor) => cursor.read(buf),
+            SoundReader::Disk(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for SoundReader {
+    #[inline]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SoundReader::Memory(cursor) => cursor.seek(pos),
+            SoundReader::Disk(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// A cached file, and when it was last opened.
+struct CacheEntry {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: HashMap<PathBuf, CacheEntry>,
+    /// Memory budget, in bytes.
+    budget: usize,
+    /// Memory used by the entries, in bytes.
+    used: usize,
+    /// Counts the accesses, to find the least recently used entry.
+    clock: u64,
+    hits: usize,
+    misses: usize,
+    /// The soundpacks whose files were checked, so they are only checked once.
+    checked: HashSet<PathBuf>,
+}
+
+impl CacheState {
+    /// Remove the least recently used entries until `size` more bytes fit in the budget.
+    fn make_room(&mut self, size: usize) {
+        while self.used + size > self.budget {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|path| self.entries.remove(&path).map(|entry| (path, entry))) {
+                Some((path, entry)) => {
+                    self.used -= entry.data.len();
+                    trace!("cache: evicted {}", path.display());
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// LRU cache of the contents of the sound files, shared by all the channels.
+/// The files are kept as they are on the disk, so the cache saves reading them, not decoding them.
+#[derive(Clone)]
+pub struct SoundCache(Arc<Mutex<CacheState>>);
+
+impl SoundCache {
+    /// Create a cache using at most `budget` bytes. A budget of 0 disables it.
+    pub fn new(budget: usize) -> Self {
+        Self(Arc::new(Mutex::new(CacheState {
+            entries: HashMap::new(),
+            budget,
+            used: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            checked: HashSet::new(),
+        })))
+    }
+
+    /// Open a sound file, from the cache if possible.
+    /// Files small enough for the budget are cached, evicting the least recently used ones.
+    /// The file is read without holding the lock, so other channels aren't kept waiting.
+    pub fn open(&self, path: &Path) -> io::Result<SoundReader> {
+        let max_size = {
+            let mut state = self.lock();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.entries.get_mut(path) {
+                entry.last_used = clock;
+                let data = entry.data.clone();
+                state.hits += 1;
+                return Ok(SoundReader::Memory(Cursor::new(data)));
+            }
+            state.misses += 1;
+            state.budget / MAX_ENTRY_FRACTION
+        };
+        let file = File::open(path)?;
+        let size = file.metadata()?.len() as usize;
+        if size > max_size {
+            return Ok(SoundReader::Disk(BufReader::new(file)));
+        }
+        let mut buf = Vec::with_capacity(size);
+        BufReader::new(file).read_to_end(&mut buf)?;
+        let data: Arc<[u8]> = buf.into();
+        Ok(SoundReader::Memory(Cursor::new(self.insert(path, data))))
+    }
+
+    /// Cache a file that was read, unless another thread cached it meanwhile.
+    /// Returns the cached data.
+    fn insert(&self, path: &Path, data: Arc<[u8]>) -> Arc<[u8]> {
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(entry) = state.entries.get_mut(path) {
+            entry.last_used = clock;
+            return entry.data.clone();
+        }
+        state.make_room(data.len());
+        state.used += data.len();
+        state.entries.insert(
+            path.to_path_buf(),
+            CacheEntry {
+                data: data.clone(),
+                last_used: clock,
+            },
+        );
+        data
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Open a sound file from the cache if it is there, or else from the disk without caching it.
+    /// Doesn't count as a hit or a miss.
+    pub fn open_uncached(&self, path: &Path) -> io::Result<SoundReader> {
+        let state = self.lock();
+        if let Some(entry) = state.entries.get(path) {
+            return Ok(SoundReader::Memory(Cursor::new(entry.data.clone())));
+        }
+        drop(state);
+        Ok(SoundReader::Disk(BufReader::new(File::open(path)?)))
+    }
+
+    /// Whether the files of the soundpack haven't been checked yet.
+    /// The soundpack counts as checked afterwards.
+    pub fn should_check(&self, sound_dir: &Path) -> bool {
+        let mut state = self.lock();
+        state.checked.insert(sound_dir.to_path_buf())
+    }
+
+    /// Read files into the cache ahead of time, without counting them as misses.
+    /// Only the free part of the budget is used, so preloading doesn't evict anything.
+    /// Files that don't fit are skipped. Returns the number of files read.
+    pub fn preload<'a, I>(&self, paths: I) -> usize
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let mut count = 0;
+        let mut skipped = 0;
+        for path in paths {
+            let free = {
+                let state = self.lock();
+                if state.entries.contains_key(path) {
+                    continue;
+                }
+                state.budget.saturating_sub(state.used)
+            };
+            let data = fs::metadata(path).and_then(|metadata| {
+                if metadata.len() as usize > free {
+                    Ok(None)
+                } else {
+                    fs::read(path).map(Some)
+                }
+            });
+            let data: Arc<[u8]> = match data {
+                Ok(Some(data)) => data.into(),
+                Ok(None) => {
+                    skipped += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to preload {}: {}", path.display(), e);
+                    warn!("Will ignore this file.");
+                    continue;
+                }
+            };
+            let mut state = self.lock();
+            if state.entries.contains_key(path) {
+                continue;
+            }
+            // The file may have grown, or other files may have been cached meanwhile.
+            if state.used + data.len() > state.budget {
+                skipped += 1;
+                continue;
+            }
+            state.clock += 1;
+            let last_used = state.clock;
+            state.used += data.len();
+            state
+                .entries
+                .insert(path.to_path_buf(), CacheEntry { data, last_used });
+            count += 1;
+        }
+        if skipped > 0 {
+            info!(
+                "{} files didn't fit in the sound cache, and weren't preloaded.",
+                skipped
+            );
+        }
+        count
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.lock();
+        CacheStats {
+            files: state.entries.len(),
+            used: state.used,
+            budget: state.budget,
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for each test, removed at the end.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "soundsense-rs-cache-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// A file of `size` bytes.
+        fn file(&self, name: &str, size: usize) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, vec![0u8; size]).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn is_cached(cache: &SoundCache, path: &Path) -> bool {
+        cache.lock().entries.contains_key(path)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = TempDir::new("lru");
+        // Room for 8 files of 100 bytes, the largest size cached on demand.
+        let cache = SoundCache::new(100 * MAX_ENTRY_FRACTION);
+        let paths: Vec<PathBuf> = (0..=MAX_ENTRY_FRACTION)
+            .map(|i| dir.file(&format!("{}.wav", i), 100))
+            .collect();
+        for path in &paths[..MAX_ENTRY_FRACTION] {
+            cache.open(path).unwrap();
+        }
+        // Opening the first file again makes the second one the least recently used.
+        assert!(matches!(
+            cache.open(&paths[0]).unwrap(),
+            SoundReader::Memory(_)
+        ));
+        cache.open(&paths[MAX_ENTRY_FRACTION]).unwrap();
+        assert!(is_cached(&cache, &paths[0]));
+        assert!(!is_cached(&cache, &paths[1]));
+        assert!(is_cached(&cache, &paths[MAX_ENTRY_FRACTION]));
+        let stats = cache.stats();
+        assert_eq!(stats.files, MAX_ENTRY_FRACTION);
+        assert_eq!(stats.used, 100 * MAX_ENTRY_FRACTION);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, MAX_ENTRY_FRACTION + 1);
+    }
+
+    #[test]
+    fn large_files_are_read_from_disk() {
+        let dir = TempDir::new("large");
+        let cache = SoundCache::new(100 * MAX_ENTRY_FRACTION);
+        let small = dir.file("small.wav", 50);
+        let large = dir.file("large.wav", 101);
+        cache.open(&small).unwrap();
+        assert!(matches!(cache.open(&large).unwrap(), SoundReader::Disk(_)));
+        // A large file doesn't evict anything.
+        assert!(is_cached(&cache, &small));
+        assert!(!is_cached(&cache, &large));
+        assert_eq!(cache.stats().used, 50);
+    }
+
+    #[test]
+    fn preload_fills_the_free_budget() {
+        let dir = TempDir::new("preload");
+        let cache = SoundCache::new(800);
+        let opened = dir.file("opened.wav", 100);
+        cache.open(&opened).unwrap();
+        let paths = [
+            dir.file("a.wav", 200),
+            // Doesn't fit in the 500 bytes left, but the smaller files after it do.
+            dir.file("b.wav", 600),
+            dir.file("c.wav", 400),
+            dir.file("d.wav", 100),
+        ];
+        let count = cache.preload(paths.iter().map(PathBuf::as_path));
+        assert_eq!(count, 3);
+        assert!(!is_cached(&cache, &paths[1]));
+        // Preloading doesn't evict, nor count as hits or misses.
+        assert!(is_cached(&cache, &opened));
+        let stats = cache.stats();
+        assert_eq!(stats.used, 800);
+        assert_eq!((stats.hits, stats.misses), (0, 1));
+        // Preloaded files are used more recently than the files opened before them.
+        let e = dir.file("e.wav", 100);
+        cache.open(&e).unwrap();
+        assert!(!is_cached(&cache, &opened));
+        assert_eq!(paths.iter().filter(|p| is_cached(&cache, p)).count(), 3);
+    }
+}
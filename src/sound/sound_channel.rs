@@ -1,11 +1,47 @@
 use super::*;
-use source::Spatial;
 
 mod loop_player;
 mod oneshot_player;
+mod pan;
+mod skip;
+mod wake;
 
 use loop_player::LoopPlayer;
 use oneshot_player::OneshotPlayer;
+use pan::Pan;
+use skip::SkipStart;
+use wake::WakeOnDrop;
+
+/// How often the players' control wrappers update their sources, in milliseconds.
+const ACCESS_PERIOD_MS: u64 = 5;
+
+/// A gain that moves linearly towards a target. Used for fades.
+struct Ramp {
+    gain: f32,
+}
+
+impl Ramp {
+    #[inline]
+    fn new(gain: f32) -> Self {
+        Self { gain }
+    }
+
+    /// Move one access period towards the target.
+    /// `duration` is the time in milliseconds a full 0 to 1 ramp takes; 0 jumps to the target.
+    fn step(&mut self, target: f32, duration: usize) -> f32 {
+        if duration == 0 {
+            self.gain = target;
+        } else {
+            let step = ACCESS_PERIOD_MS as f32 / duration as f32;
+            self.gain = if self.gain < target {
+                (self.gain + step).min(target)
+            } else {
+                (self.gain - step).max(target)
+            };
+        }
+        self.gain
+    }
+}
 
 /// Struct responsible for containing currently playing sounds.
 /// "music" and "weather" channels can play only one sound at a time.
@@ -15,34 +51,77 @@ pub struct SoundChannel {
     local_volume: VolumeLock,
     delay: usize,
     local_is_paused: IsPausedLock,
-    threshold: u8,
+    /// Whether the channel's sources are silenced.
+    /// Set by the SoundManager from the channel's own mute, the total mute, and the solo.
+    local_is_muted: IsMutedLock,
+    /// Whether the user muted this channel.
+    is_muted: bool,
+    threshold: Threshold,
     pub play_type: ChannelPlayType,
+    /// What to do when a Sound's concurency limit is hit, unless the Sound decides.
+    pub steal_policy: StealPolicy,
+    /// Gain applied to playing oneshots when a new oneshot starts.
+    pub oneshot_ducking: f32,
+    /// Gain applied to the loop while a oneshot plays.
+    pub loop_ducking: f32,
+    /// Volume of the channel lowered by other channels' ducking rules.
+    duck_volume: VolumeLock,
+    /// Current cross-channel ducking, in decibels.
+    duck_db: f32,
+    /// Depth the cross-channel ducking is moving towards, in decibels.
+    duck_target: f32,
+    /// Speed of the current ducking ramp, in decibels per millisecond.
+    duck_rate: f32,
+    /// Release time of the last ducking rule applied to this channel.
+    duck_release: usize,
+    /// Layered stems, if the channel plays its loops that way.
+    layers: Option<LayerSetting>,
+    /// Where the channel's sounds come from, unless the Sound decides.
+    position: Option<Position>,
+    /// Cache of the sound files.
+    cache: SoundCache,
+    /// Output device the channel is routed to, or None for the main device.
+    pub device: Option<Box<str>>,
 }
 
 impl SoundChannel {
     /// Create a new SoundChannel.
+    /// The loop sends on `wake` when one of its tracks ends, so the sound thread can play the next.
     #[inline]
     pub fn new(
-        device: &Device,
+        bus: &MasterBus,
         name: &str,
         total_volume: VolumeLock,
         total_is_paused: IsPausedLock,
+        stereo: StereoSetting,
+        cache: SoundCache,
+        wake: Sender<()>,
     ) -> Self {
         let local_volume = VolumeLock::new();
         let local_is_paused = IsPausedLock::new();
+        let local_is_muted = IsMutedLock::new();
+        let duck_volume = VolumeLock::new();
         Self {
             looping: LoopPlayer::new(
-                device,
+                bus,
                 local_volume.clone(),
                 total_volume.clone(),
                 local_is_paused.clone(),
                 total_is_paused.clone(),
+                local_is_muted.clone(),
+                duck_volume.clone(),
+                stereo,
+                cache.clone(),
+                wake,
             ),
             one_shots: OneshotPlayer::new(
                 local_volume.clone(),
                 total_volume,
                 local_is_paused.clone(),
                 total_is_paused,
+                local_is_muted.clone(),
+                duck_volume.clone(),
+                stereo,
             ),
             local_volume,
             delay: 0,
@@ -53,14 +132,28 @@ impl SoundChannel {
                     ChannelPlayType::All
                 }
             },
+            steal_policy: StealPolicy::RejectNewest,
             local_is_paused,
-            threshold: 4,
+            local_is_muted,
+            is_muted: false,
+            threshold: Threshold::Everything,
+            oneshot_ducking: 0.5,
+            loop_ducking: 0.25,
+            duck_volume,
+            duck_db: 0.0,
+            duck_target: 0.0,
+            duck_rate: 0.0,
+            duck_release: 0,
+            layers: None,
+            position: None,
+            cache,
+            device: None,
         }
     }
 
     /// Maintain this channel.
     /// Maintain looping player, tick down delay, cleanup oneshots.
-    pub fn maintain(&mut self, rng: &mut ThreadRng, dt: usize) {
+    pub fn maintain(&mut self, bus: &MasterBus, rng: &mut ThreadRng, dt: usize) {
         let delay = self.delay.saturating_sub(dt);
         self.delay = delay;
         if self.delay > 0 {
@@ -73,16 +166,18 @@ impl SoundChannel {
         } else {
             self.looping.pause()
         }
-        self.looping.maintain(rng);
+        self.looping.maintain(bus, rng);
     }
 
     /// Change the loop.
     /// If "music" or "weather", stop all oneshots.
+    /// The loop comes from `position` if given, or else from the channel's position.
     pub fn change_loop(
         &mut self,
-        device: &Device,
+        bus: &MasterBus,
         files: &[SoundFile],
         delay: usize,
+        position: Option<Position>,
         rng: &mut ThreadRng,
     ) {
         if self.play_type == ChannelPlayType::SingleLazy {
@@ -92,9 +187,17 @@ impl SoundChannel {
         } else if self.play_type == ChannelPlayType::SingleEager {
             self.one_shots.stop();
         }
-        self.looping.change_loop(device, files, rng);
+        self.looping
+            .change_loop(bus, files, position.or(self.position), rng);
         self.delay = delay;
-        self.maintain(rng, 0);
+        self.maintain(bus, rng, 0);
+    }
+
+    /// Continue the loop on another bus, as when the output device changes.
+    /// The oneshots playing on the old bus are dropped, since the old bus may not play anymore.
+    pub fn move_to(&mut self, bus: &MasterBus, rng: &mut ThreadRng) {
+        self.one_shots.clear();
+        self.looping.move_to(bus, rng);
     }
 
     pub fn stop_loop(&mut self, delay: usize) {
@@ -111,24 +214,99 @@ impl SoundChannel {
         self.local_is_paused.flip()
     }
 
+    /// Flip the user mute. Returns the new value.
+    /// The SoundManager applies it with `set_silenced`.
+    #[inline]
+    pub fn flip_mute(&mut self) -> bool {
+        self.is_muted = !self.is_muted;
+        self.is_muted
+    }
+    #[inline]
+    pub fn is_muted(&self) -> bool {
+        self.is_muted
+    }
+
+    /// Silence or unsilence the channel's sources, keeping their playback position.
+    #[inline]
+    pub fn set_silenced(&mut self, is_silenced: bool) {
+        self.local_is_muted.set(is_silenced);
+    }
+    /// Whether the channel's sources can be heard: neither silenced nor paused.
+    #[inline]
+    pub fn is_audible(&self) -> bool {
+        !self.local_is_muted.get() && !self.local_is_paused.get()
+    }
+
+    #[inline]
+    pub fn set_paused(&mut self, is_paused: bool) {
+        self.local_is_paused.set(is_paused);
+    }
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.local_is_paused.get()
+    }
+
     pub fn finish(&mut self) {
         self.looping.stop();
         self.one_shots.stop();
     }
 
+    /// Move the cross-channel ducking one tick towards its target.
+    /// `ducking` is the (amount in decibels, attack, release) of the rule currently ducking
+    /// this channel, or None to return to full volume.
+    /// Each ramp covers the distance from the depth it starts at in the attack or release time.
+    pub fn update_ducking(&mut self, ducking: Option<(f32, usize, usize)>, dt: usize) {
+        let (target, duration) = match ducking {
+            Some((amount, attack, release)) => {
+                self.duck_release = release;
+                let duration = if amount < self.duck_db {
+                    attack
+                } else {
+                    release
+                };
+                (amount, duration)
+            }
+            None => (0.0, self.duck_release),
+        };
+        if self.duck_db == target {
+            self.duck_target = target;
+            return;
+        }
+        if duration == 0 {
+            self.duck_db = target;
+            self.duck_target = target;
+        } else {
+            if target != self.duck_target {
+                self.duck_target = target;
+                self.duck_rate = (self.duck_db - target).abs() / duration as f32;
+            }
+            let step = self.duck_rate * dt as f32;
+            self.duck_db = if self.duck_db < target {
+                (self.duck_db + step).min(target)
+            } else {
+                (self.duck_db - step).max(target)
+            };
+        }
+        self.duck_volume.set(db_to_gain(self.duck_db));
+    }
+
     /// Play a oneshot.
-    /// Will make other oneshots 50% quieter.
+    /// Will make other oneshots quieter by `oneshot_ducking`, and the loop by `loop_ducking`.
     /// If "music" or "weather", pauses loop and stops other oneshots.
+    /// The oneshot comes from `position` if given, or else from the channel's position.
+    /// Returns whether a oneshot was started.
     pub fn add_oneshot(
         &mut self,
-        device: &Device,
+        bus: &MasterBus,
         file: &SoundFile,
         delay: usize,
+        position: Option<Position>,
+        priority: Threshold,
         rng: &mut ThreadRng,
-    ) {
+    ) -> bool {
         if self.play_type == ChannelPlayType::SingleLazy {
             if self.len() != 0 {
-                return;
+                return false;
             }
         } else if self.play_type == ChannelPlayType::SingleEager {
             self.looping.pause();
@@ -138,22 +316,101 @@ impl SoundChannel {
         self.one_shots.play();
         for idx in 0..self.one_shots.len() {
             let current_vol = self.one_shots.get_volume(idx);
-            self.one_shots.set_volume(idx, current_vol * 0.5);
+            self.one_shots
+                .set_volume(idx, current_vol * self.oneshot_ducking);
         }
-        self.looping.set_volume(0.25);
-        let mut data = get_soundfiles(file, rng);
-        match data.len() {
-            0 => (),
+        self.looping.set_volume(self.loop_ducking);
+        let mut data = get_soundfiles(&self.cache, file, rng);
+        if let Some(position) = position.or(self.position) {
+            for (_, volume, balance) in data.iter_mut() {
+                *volume *= position.attenuation();
+                *balance = position.balance();
+            }
+        }
+        let is_added = match data.len() {
+            0 => false,
             1 => {
                 let (source, volume, balance) = data.remove(0);
-                self.one_shots.add_source(device, source, volume, balance);
+                self.one_shots
+                    .add_source(bus, source, volume, balance, priority);
+                true
             }
             _ => {
                 let (source, volume, balance) = data.remove(rng.gen_range(0, data.len()));
-                self.one_shots.add_source(device, source, volume, balance);
+                self.one_shots
+                    .add_source(bus, source, volume, balance, priority);
+                true
             }
-        }
+        };
         self.delay = delay;
+        is_added
+    }
+
+    /// Number of oneshots still playing.
+    #[inline]
+    pub fn voice_count(&self) -> usize {
+        self.one_shots.playing_count()
+    }
+
+    /// The index and priority of the least important playing oneshot.
+    #[inline]
+    pub fn least_important_oneshot(&self) -> Option<(usize, Threshold)> {
+        self.one_shots.least_important()
+    }
+
+    /// The index of the oneshot to stop for a new oneshot at the concurency limit.
+    /// Uses the channel's policy unless one is given.
+    #[inline]
+    pub fn oneshot_to_steal(
+        &self,
+        policy: Option<StealPolicy>,
+        priority: Threshold,
+    ) -> Option<usize> {
+        self.one_shots
+            .steal_candidate(policy.unwrap_or(self.steal_policy), priority)
+    }
+
+    #[inline]
+    pub fn stop_oneshot(&mut self, idx: usize) {
+        self.one_shots.stop_source(idx);
+    }
+
+    #[inline]
+    pub fn set_fade(&mut self, fade: FadeSetting) {
+        self.looping.set_fade(fade);
+    }
+
+    /// Play the files of the loops together as stems, following the intensity.
+    pub fn set_layers(&mut self, layers: Option<LayerSetting>) {
+        self.looping.set_layer_fade(layers.as_ref().map(|l| l.fade));
+        self.layers = layers;
+    }
+    #[inline]
+    pub fn layers(&self) -> Option<&LayerSetting> {
+        self.layers.as_ref()
+    }
+
+    /// Set the order of the loops and playlists, and whether loops resume when they return.
+    #[inline]
+    pub fn set_playlist(&mut self, mode: Option<PlaylistMode>, resume: bool) {
+        self.looping.set_playlist(mode, resume);
+    }
+
+    /// The track played by the loop.
+    #[inline]
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        self.looping.now_playing()
+    }
+
+    /// Set the intensity of the recent events, from 0 to 1.
+    #[inline]
+    pub fn set_intensity(&self, intensity: f32) {
+        self.looping.set_intensity(intensity);
+    }
+
+    #[inline]
+    pub fn set_position(&mut self, position: Option<Position>) {
+        self.position = position;
     }
 
     #[inline]
@@ -166,11 +423,11 @@ impl SoundChannel {
     }
 
     #[inline]
-    pub fn set_threshold(&mut self, threshold: u8) {
+    pub fn set_threshold(&mut self, threshold: Threshold) {
         self.threshold = threshold;
     }
     #[inline]
-    pub fn get_threshold(&mut self) -> u8 {
+    pub fn get_threshold(&self) -> Threshold {
         self.threshold
     }
 
@@ -178,15 +435,41 @@ impl SoundChannel {
     pub fn len(&self) -> usize {
         self.one_shots.len() + self.looping.len()
     }
+
+    /// Whether the channel needs maintaining every tick: oneshots are playing,
+    /// a delay or the ducking is running down.
+    /// A loop alone doesn't, since it wakes the sound thread when its track ends.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.delay > 0 || !self.one_shots.is_empty() || self.duck_db != 0.0
+    }
+
+    /// Whether the channel's loop is playing.
+    #[inline]
+    pub fn is_looping(&self) -> bool {
+        self.looping.len() != 0
+    }
 }
 
 /// Get a Vector of (source, volume, balance) from a SoundFile.
 /// Note that non-playlist files will just return a 1-length Vector.
+/// The speed and volume vary randomly within the SoundFile's pitch and volume variations.
 fn get_soundfiles(
+    cache: &SoundCache,
     soundfile: &SoundFile,
     rng: &mut ThreadRng,
-) -> Vec<(rodio::decoder::Decoder<std::fs::File>, f32, f32)> {
-    let volume = soundfile.volume;
+) -> Vec<(source::Speed<SoundSource>, f32, f32)> {
+    let mut volume = soundfile.volume;
+    if soundfile.volume_variation > 0.0 {
+        let variation = soundfile.volume_variation;
+        volume *= db_to_gain(rng.gen_range(-variation, variation));
+    }
+    let speed = if soundfile.pitch_variation > 0.0 {
+        let variation = soundfile.pitch_variation;
+        rng.gen_range(1.0 - variation, 1.0 + variation)
+    } else {
+        1.0
+    };
     let balance = if soundfile.random_balance {
         rng.gen_range(-1.0, 1.0)
     } else {
@@ -194,13 +477,16 @@ fn get_soundfiles(
     };
     match soundfile.r#type {
         SoundFileType::IsPath(ref path) => {
-            if let Some(source) = get_source(path) {
-                return vec![(source, volume, balance)];
+            if let Some(source) = get_source(cache, path) {
+                return vec![(source.speed(speed), volume, balance)];
             }
         }
-        SoundFileType::IsPlaylist(ref paths) => {
-            if let Some(source) = get_source(&paths.choose(rng).unwrap()) {
-                return vec![(source, volume, balance)];
+        SoundFileType::IsPlaylist(ref entries) => {
+            if let Some(entry) = entries.choose(rng) {
+                trace!("   track: {:?} {:?}", entry.title, entry.duration);
+                if let Some(source) = get_source(cache, &entry.path) {
+                    return vec![(source.speed(speed), volume, balance)];
+                }
             }
         }
     }
@@ -209,8 +495,8 @@ fn get_soundfiles(
 
 /// Check if the file at the give path is a valid sound source.
 /// Otherwise, return a None.
-fn get_source(path: &Path) -> Option<rodio::decoder::Decoder<std::fs::File>> {
-    let f = match fs::File::open(path) {
+fn get_source(cache: &SoundCache, path: &Path) -> Option<SoundSource> {
+    let f = match cache.open(path) {
         Ok(f) => f,
         Err(e) => {
             warn!("Path {} is invalid: {}", path.display(), e);
@@ -218,7 +504,7 @@ fn get_source(path: &Path) -> Option<rodio::decoder::Decoder<std::fs::File>> {
             return None;
         }
     };
-    let source = Decoder::new(f);
+    let source = formats::decode(f);
     match source {
         Ok(source) => Some(source),
         Err(e) => {
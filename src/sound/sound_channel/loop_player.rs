@@ -1,7 +1,96 @@
 use super::*;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::sync::mpsc::Receiver;
 
+/// How many times in a row a loop is resumed on a new queue after its queue was dropped,
+/// before it is given up until it changes.
+const MAX_LOST_QUEUES: usize = 3;
+
+/// A file of a loop, with the progress through its tracks if it is a playlist.
+#[derive(Clone)]
+struct LoopFile {
+    file: SoundFile,
+    /// Index of the next track in sequential order.
+    next: usize,
+    /// Index of the track playing, or last played.
+    current: usize,
+    /// Recently played tracks, oldest first.
+    recent: VecDeque<usize>,
+    /// Tracks played since the file came to the front of the loop.
+    played: usize,
+}
+
+impl LoopFile {
+    fn new(file: SoundFile) -> Self {
+        Self {
+            file,
+            next: 0,
+            current: 0,
+            recent: VecDeque::new(),
+            played: 0,
+        }
+    }
+
+    fn track_count(&self) -> usize {
+        match &self.file.r#type {
+            SoundFileType::IsPath(_) => 1,
+            SoundFileType::IsPlaylist(entries) => entries.len(),
+        }
+    }
+
+    /// Whether there are tracks left to play before the next file's turn.
+    fn has_next(&self) -> bool {
+        self.played < self.track_count()
+    }
+
+    /// Choose the next track, in the file's playlist mode, or else the given one.
+    fn next_track(&mut self, default_mode: Option<PlaylistMode>, rng: &mut ThreadRng) -> usize {
+        let count = self.track_count().max(1);
+        let mode = self
+            .file
+            .playlist_mode
+            .or(default_mode)
+            .unwrap_or(PlaylistMode::Sequential);
+        let track = match mode {
+            PlaylistMode::Sequential => self.next % count,
+            PlaylistMode::Shuffle => rng.gen_range(0, count),
+            PlaylistMode::ShuffleNoRepeat(no_repeat) => {
+                // At least one track has to be left to choose from.
+                let no_repeat = no_repeat.min(count - 1);
+                let skip = self.recent.len().saturating_sub(no_repeat);
+                let candidates: Vec<usize> = (0..count)
+                    .filter(|track| !self.recent.iter().skip(skip).any(|t| t == track))
+                    .collect();
+                candidates.choose(rng).copied().unwrap_or(0)
+            }
+        };
+        self.next = track + 1;
+        self.current = track;
+        self.played += 1;
+        self.recent.push_back(track);
+        if self.recent.len() > count {
+            self.recent.pop_front();
+        }
+        track
+    }
+}
+
+/// Where a loop left off when it was replaced.
+struct ResumePoint {
+    files: VecDeque<LoopFile>,
+    /// How long the track had played, in milliseconds.
+    elapsed: usize,
+}
+
+/// Identifies a loop by the first path of each of its files.
+fn loop_key(files: &[SoundFile]) -> Vec<PathBuf> {
+    files
+        .iter()
+        .filter_map(|file| file.r#type.paths().next())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
 /// Struct responsible of playing looping sounds.
 pub struct LoopPlayer {
     /// Atomic reference cell to the SourceQueueInput.
@@ -26,35 +115,98 @@ pub struct LoopPlayer {
     local_is_paused: IsPausedLock,
     /// Total is_paused (SoundManager's is_paused).
     total_is_paused: IsPausedLock,
+    /// Channel's is_muted.
+    local_is_muted: IsMutedLock,
+    /// Channel's volume lowered by other channels' ducking.
+    duck_volume: VolumeLock,
+    /// Fade durations of this loop.
+    fade: FadeSetting,
+    /// Stereo width and mono downmix.
+    stereo: StereoSetting,
+    /// Where the current loop comes from, overriding the balance of its files.
+    position: Option<Position>,
+    /// Fade duration of the stems, if the files of a loop are played together as stems.
+    layer_fade: Option<usize>,
+    /// Intensity of the recent events, from 0 to 1. Sets the gains of the stems.
+    intensity: VolumeLock,
+    /// Fade-out duration used when the current loop stops.
+    /// Is the crossfade duration when the loop is being replaced, and fade_out otherwise.
+    stop_fade: Arc<AtomicUsize>,
     /// Option for Receiver that checks if the current source has finished playing.
     sleep_until_end: Option<Receiver<()>>,
+    /// Times in a row the queue was dropped before its source ended.
+    lost_queues: usize,
+    /// Wakes the sound thread when a source of the loop is dropped.
+    wake: Sender<()>,
+    /// Cache of the sound files.
+    cache: SoundCache,
     /// SoundFile deque.
     /// Whenever a source finishes playing, the first file will play, then the deque rotates.
-    files: VecDeque<SoundFile>,
+    /// A playlist plays as many tracks as it has before the deque rotates.
+    files: VecDeque<LoopFile>,
+    /// Identifies the current loop, to resume it later.
+    loop_key: Vec<PathBuf>,
+    /// Order of the loops' files and playlists, unless a SoundFile decides.
+    playlist_mode: Option<PlaylistMode>,
+    /// Whether loops continue where they left off, when they return.
+    resume: bool,
+    /// Where the replaced loops left off.
+    resume_points: HashMap<Vec<PathBuf>, ResumePoint>,
+    /// Title and duration of the current track, unless the loop is layered.
+    track: Option<(Box<str>, Option<Duration>)>,
+    /// How long the current track has played, in milliseconds.
+    elapsed: Arc<AtomicUsize>,
 }
 impl LoopPlayer {
+    #[allow(clippy::too_many_arguments)]
     #[inline]
     pub fn new(
-        device: &Device,
+        bus: &MasterBus,
         local_volume: VolumeLock,
         total_volume: VolumeLock,
         local_is_paused: IsPausedLock,
         total_is_paused: IsPausedLock,
+        local_is_muted: IsMutedLock,
+        duck_volume: VolumeLock,
+        stereo: StereoSetting,
+        cache: SoundCache,
+        wake: Sender<()>,
     ) -> Self {
         let (queue_tx, queue_rx) = queue::queue(true);
-        play_raw(device, queue_rx);
+        bus.add(queue_rx);
         Self {
             queue_tx,
             local_volume,
             total_volume,
             local_is_paused,
             total_is_paused,
+            local_is_muted,
+            duck_volume,
             stopped: Arc::new(AtomicBool::new(false)),
             paused: Arc::new(AtomicBool::new(false)),
             skipped: Arc::new(AtomicBool::new(false)),
             volume: VolumeLock::new(),
+            fade: FadeSetting::default(),
+            stereo,
+            position: None,
+            layer_fade: None,
+            intensity: {
+                let intensity = VolumeLock::new();
+                intensity.set(0.0);
+                intensity
+            },
+            stop_fade: Arc::new(AtomicUsize::new(0)),
             sleep_until_end: None,
+            lost_queues: 0,
+            wake,
+            cache,
             files: VecDeque::new(),
+            loop_key: Vec::new(),
+            playlist_mode: None,
+            resume: false,
+            resume_points: HashMap::new(),
+            track: None,
+            elapsed: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -89,6 +241,41 @@ impl LoopPlayer {
         self.skipped.store(true, Ordering::SeqCst);
     }
 
+    #[inline]
+    pub fn set_fade(&mut self, fade: FadeSetting) {
+        self.fade = fade;
+        self.stop_fade.store(fade.fade_out, Ordering::SeqCst);
+    }
+
+    /// Play the files of each loop together as stems, or one after the other if None.
+    #[inline]
+    pub fn set_layer_fade(&mut self, layer_fade: Option<usize>) {
+        self.layer_fade = layer_fade;
+    }
+
+    #[inline]
+    pub fn set_playlist(&mut self, mode: Option<PlaylistMode>, resume: bool) {
+        self.playlist_mode = mode;
+        self.resume = resume;
+    }
+
+    /// The track being played, and how much of it has played.
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        if self.is_stopped() || self.files.is_empty() {
+            return None;
+        }
+        self.track.as_ref().map(|(title, duration)| NowPlaying {
+            title: title.clone(),
+            position: Duration::from_millis(self.elapsed.load(Ordering::Relaxed) as u64),
+            duration: *duration,
+        })
+    }
+
+    #[inline]
+    pub fn set_intensity(&self, intensity: f32) {
+        self.intensity.set(intensity);
+    }
+
     #[inline]
     pub fn set_volume(&self, volume: f32) {
         self.volume.set(volume);
@@ -108,133 +295,398 @@ impl LoopPlayer {
 
     /// Change the loop.
     /// Replaces the current set of files with another one.
-    pub fn change_loop(&mut self, device: &Device, files: &[SoundFile], rng: &mut ThreadRng) {
+    /// If a loop was playing, it is crossfaded into the new one.
+    /// A resumable loop continues where it left off, and keeps playing if it already is.
+    pub fn change_loop(
+        &mut self,
+        bus: &MasterBus,
+        files: &[SoundFile],
+        position: Option<Position>,
+        rng: &mut ThreadRng,
+    ) {
+        let key = loop_key(files);
+        let resume =
+            self.layer_fade.is_none() && (self.resume || files.iter().any(|file| file.resume));
+        if resume && self.len() != 0 && key == self.loop_key {
+            return;
+        }
+        let fade_in = if self.len() != 0 {
+            self.stop_fade.store(self.fade.crossfade, Ordering::SeqCst);
+            self.fade.crossfade
+        } else {
+            self.fade.fade_in
+        };
+        self.stop();
+        // Remember where the replaced loop left off.
+        let was_resumable = self.resume || self.files.iter().any(|file| file.file.resume);
+        if was_resumable && self.layer_fade.is_none() && !self.files.is_empty() {
+            let point = ResumePoint {
+                files: std::mem::take(&mut self.files),
+                elapsed: self.elapsed.load(Ordering::Relaxed),
+            };
+            self.resume_points
+                .insert(std::mem::take(&mut self.loop_key), point);
+        }
+        let start = match self.resume_points.remove(&key).filter(|_| resume) {
+            Some(point) => {
+                trace!("  Resuming loop.");
+                self.files = point.files;
+                Some(point.elapsed)
+            }
+            None => {
+                self.files = files.iter().cloned().map(LoopFile::new).collect();
+                if self.layer_fade.is_none() && self.playlist_mode != Some(PlaylistMode::Sequential)
+                {
+                    let (front, back) = self.files.as_mut_slices();
+                    front.shuffle(rng);
+                    back.shuffle(rng);
+                }
+                None
+            }
+        };
+        self.loop_key = key;
+        self.new_queue(bus);
+        self.lost_queues = 0;
+        self.position = position;
+        self.append_file(rng, fade_in, start);
+    }
+
+    /// Move the loop to another bus, as when the output device changes.
+    /// The current track continues where it was on the new bus.
+    pub fn move_to(&mut self, bus: &MasterBus, rng: &mut ThreadRng) {
+        let was_playing = !self.is_stopped() && !self.files.is_empty();
+        let was_paused = self.is_paused();
+        let elapsed = self.elapsed.load(Ordering::Relaxed);
+        self.stop_fade.store(0, Ordering::SeqCst);
         self.stop();
-        self.files = files.iter().cloned().collect();
-        let (front, back) = self.files.as_mut_slices();
-        front.shuffle(rng);
-        back.shuffle(rng);
+        self.new_queue(bus);
+        if was_playing {
+            self.paused.store(was_paused, Ordering::SeqCst);
+            self.append_file(rng, self.fade.fade_in, Some(elapsed));
+        } else {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Start a new queue on the bus, with new controls.
+    /// The sources of the old queue keep the old controls, so they can fade out on their own.
+    fn new_queue(&mut self, bus: &MasterBus) {
         let (queue_tx, queue_rx) = queue::queue(true);
-        play_raw(device, queue_rx);
+        bus.add(queue_rx);
         let volume = self.volume.get();
         self.stopped = Arc::new(AtomicBool::new(false));
         self.paused = Arc::new(AtomicBool::new(false));
+        self.stop_fade = Arc::new(AtomicUsize::new(self.fade.fade_out));
         self.volume = VolumeLock::new();
         self.volume.set(volume);
         self.queue_tx = queue_tx;
-        self.append_file(rng);
+        self.sleep_until_end = None;
     }
 
-    /// Gets sound source(s) from the first file path, and append to the SourceQueue.
-    fn append_file(&mut self, rng: &mut ThreadRng) {
-        let file = self.files.front_mut().unwrap();
-        let files = match &file.r#type {
-            SoundFileType::IsPath(path) => vec![path.clone()],
-            SoundFileType::IsPlaylist(paths) => paths.to_vec(),
+    /// Gets the next track of the first file, and append it to the SourceQueue.
+    /// The source fades in over `fade_in` milliseconds.
+    /// If `start` is given, the last track is played again from that many milliseconds.
+    /// A track that can't be played is skipped for the next one, until every track was tried.
+    fn append_file(&mut self, rng: &mut ThreadRng, fade_in: usize, mut start: Option<usize>) {
+        // Replaced once a source is appended. Stays empty if none can be.
+        self.sleep_until_end = None;
+        self.track = None;
+        if let Some(layer_fade) = self.layer_fade {
+            self.append_stems(rng, fade_in, layer_fade);
+            return;
+        }
+        let mode = self.playlist_mode;
+        let attempts = match self.files.front() {
+            Some(loop_file) => loop_file.track_count().max(1),
+            None => return,
         };
-        let volume = file.volume;
-        let balance = if file.random_balance {
-            None
-        } else {
-            Some(file.balance)
+        for _ in 0..attempts {
+            let loop_file = match self.files.front_mut() {
+                Some(loop_file) => loop_file,
+                None => return,
+            };
+            let track = match start {
+                Some(_) => loop_file.current,
+                None => loop_file.next_track(mode, rng),
+            };
+            let file = &loop_file.file;
+            let (path, title, duration) = match &file.r#type {
+                SoundFileType::IsPath(path) => (path.clone(), None, None),
+                SoundFileType::IsPlaylist(entries) => {
+                    let entry = &entries[track];
+                    (entry.path.clone(), entry.title.clone(), entry.duration)
+                }
+            };
+            let mut volume = file.volume;
+            let balance = if let Some(position) = self.position {
+                volume *= position.attenuation();
+                position.balance()
+            } else if file.random_balance {
+                rng.gen_range(-1.0, 1.0)
+            } else {
+                file.balance
+            };
+            let source = match self.open(&path) {
+                Some(source) => source,
+                None => {
+                    // The next track starts from its beginning.
+                    start = None;
+                    continue;
+                }
+            };
+            let title = title.unwrap_or_else(|| {
+                path.file_stem()
+                    .map_or_else(|| path.to_string_lossy(), |stem| stem.to_string_lossy())
+                    .into()
+            });
+            trace!("  Track: {}", title);
+            self.track = Some((title, duration));
+            let start = start.unwrap_or(0);
+            self.elapsed = Arc::new(AtomicUsize::new(start));
+            self.append_source(source, volume, balance, fade_in, start);
+            return;
+        }
+        warn!("None of the tracks of the loop can be played.");
+        warn!("Will ignore this loop until it changes.");
+    }
+
+    /// Open and decode a file. Failures are logged.
+    fn open(&self, path: &Path) -> Option<SoundSource> {
+        let f = match self.cache.open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Failed to open file {}: {}", path.display(), e);
+                warn!("Will ignore this file.");
+                return None;
+            }
         };
-        for path in files.iter() {
-            let f = match fs::File::open(path) {
+        match formats::decode(f) {
+            Ok(source) => Some(source),
+            Err(e) => {
+                warn!("Error while decoding {}: {}", path.display(), e);
+                warn!("Will ignore this source.");
+                None
+            }
+        }
+    }
+
+    /// Mixes all the files into one source, each following the intensity with its own gain.
+    /// The stems stay in sync, since they are played as a single source.
+    fn append_stems(&mut self, rng: &mut ThreadRng, fade_in: usize, layer_fade: usize) {
+        let mut mixed: Option<Box<dyn Source<Item = f32> + Send>> = None;
+        for file in self.files.iter().map(|loop_file| &loop_file.file) {
+            let path = match &file.r#type {
+                SoundFileType::IsPath(path) => path,
+                SoundFileType::IsPlaylist(entries) => match entries.choose(rng) {
+                    Some(entry) => &entry.path,
+                    None => continue,
+                },
+            };
+            let f = match self.cache.open(path) {
                 Ok(f) => f,
                 Err(e) => {
                     warn!("Failed to open file {}: {}", path.display(), e);
-                    warn!("Will ignore this file.");
+                    warn!("Will ignore this stem.");
                     continue;
                 }
             };
-            match Decoder::new(f) {
-                Ok(source) => {
-                    let balance = balance.unwrap_or_else(|| rng.gen_range(-1.0, 1.0));
-                    self.append_source(source, volume, balance)
-                }
+            let source = match formats::decode(f) {
+                Ok(source) => source,
                 Err(e) => {
                     warn!("Error while decoding {}: {}", path.display(), e);
-                    warn!("Will ignore this source.");
+                    warn!("Will ignore this stem.");
+                    continue;
                 }
-            }
+            };
+            let stem_file = file.clone();
+            let intensity = self.intensity.clone();
+            let mut ramp = Ramp::new(stem_file.stem_gain(intensity.get()));
+            let stem = source
+                .convert_samples::<f32>()
+                .amplify(file.volume * ramp.gain)
+                .periodic_access(Duration::from_millis(ACCESS_PERIOD_MS), move |src| {
+                    let gain = ramp.step(stem_file.stem_gain(intensity.get()), layer_fade);
+                    src.set_factor(stem_file.volume * gain);
+                });
+            mixed = Some(match mixed {
+                Some(mixed) => Box::new(mixed.mix(stem)),
+                None => Box::new(stem),
+            });
+        }
+        if let (Some(mixed), Some(front)) = (mixed, self.files.front()) {
+            let file = &front.file;
+            let (volume, balance) = if let Some(position) = self.position {
+                (position.attenuation(), position.balance())
+            } else if file.random_balance {
+                (1.0, rng.gen_range(-1.0, 1.0))
+            } else {
+                (1.0, file.balance)
+            };
+            self.append_source(mixed, volume, balance, fade_in, 0);
         }
     }
 
     /// Wraps the source with the appropriate control wrappers, then adds it to the queue.
-    fn append_source<S>(&mut self, source: S, source_volume: f32, balance: f32)
-    where
+    /// Stopping, skipping, pausing and volume changes are faded according to the FadeSetting.
+    /// The first `start` milliseconds of the source are skipped, and it fades in after them.
+    fn append_source<S>(
+        &mut self,
+        source: S,
+        source_volume: f32,
+        balance: f32,
+        fade_in: usize,
+        start: usize,
+    ) where
         S: Source + Send + 'static,
         S::Item: Sample + Send,
     {
         let stopped = self.stopped.clone();
         let paused = self.paused.clone();
         let skipped = self.skipped.clone();
+        let stop_fade = self.stop_fade.clone();
         let volume = self.volume.clone();
         let local_volume = self.local_volume.clone();
         let total_volume = self.total_volume.clone();
         let local_is_paused = self.local_is_paused.clone();
         let total_is_paused = self.total_is_paused.clone();
-        let source = source
+        let local_is_muted = self.local_is_muted.clone();
+        let duck_volume = self.duck_volume.clone();
+        let elapsed = self.elapsed.clone();
+        let fade = self.fade;
+        // Gain of the fade in/out.
+        let mut fader = Ramp::new(if fade_in == 0 { 1.0 } else { 0.0 });
+        // Gain of the ducking applied through `volume`.
+        let mut ducker = Ramp::new(volume.get());
+        // Fade-in duration. The first fade-in may be a crossfade, later ones are resumes.
+        let mut rise = fade_in;
+        let mut started = false;
+        let mut skipping = false;
+        let source = SkipStart::new(source, start)
             .pausable(false)
             .amplify(1.0)
             .stoppable()
-            .periodic_access(Duration::from_millis(5), move |src| {
-                if stopped.load(Ordering::Relaxed) || skipped.swap(false, Ordering::Relaxed) {
+            .periodic_access(Duration::from_millis(ACCESS_PERIOD_MS), move |src| {
+                let is_stopped = stopped.load(Ordering::Relaxed);
+                // Sources queued behind a stopped source don't need to fade.
+                if is_stopped && !started {
+                    src.stop();
+                    return;
+                }
+                started = true;
+                skipping |= skipped.swap(false, Ordering::Relaxed);
+                let is_paused = paused.load(Ordering::Relaxed)
+                    || local_is_paused.get()
+                    || total_is_paused.get();
+                let gain = if is_stopped || skipping {
+                    fader.step(0.0, stop_fade.load(Ordering::Relaxed))
+                } else if is_paused {
+                    fader.step(0.0, fade.fade_out)
+                } else {
+                    src.inner_mut().inner_mut().set_paused(false);
+                    // The fade-in waits for the skipped start.
+                    if src.inner_mut().inner_mut().inner_mut().is_skipping() {
+                        fader.gain
+                    } else {
+                        let gain = fader.step(1.0, rise);
+                        if gain == 1.0 {
+                            rise = fade.fade_in;
+                        }
+                        gain
+                    }
+                };
+                if gain == 0.0 && (is_stopped || skipping) {
                     src.stop();
+                    return;
+                }
+                let target_volume = volume.get();
+                let duck_fade = if target_volume < ducker.gain {
+                    fade.fade_out
                 } else {
-                    src.inner_mut().set_factor(
-                        source_volume * volume.get() * local_volume.get() * total_volume.get(),
-                    );
-                    src.inner_mut().inner_mut().set_paused(
-                        paused.load(Ordering::Relaxed)
-                            || local_is_paused.get()
-                            || total_is_paused.get(),
-                    );
+                    fade.fade_in
+                };
+                src.inner_mut().set_factor(
+                    source_volume
+                        * gain
+                        * ducker.step(target_volume, duck_fade)
+                        * duck_volume.get()
+                        * local_volume.get()
+                        * total_volume.get()
+                        * if local_is_muted.get() { 0.0 } else { 1.0 },
+                );
+                if gain == 0.0 && is_paused {
+                    src.inner_mut().inner_mut().set_paused(true);
+                } else if !src.inner_mut().inner_mut().inner_mut().is_skipping() {
+                    elapsed.fetch_add(ACCESS_PERIOD_MS as usize, Ordering::Relaxed);
                 }
             })
             .convert_samples::<f32>();
+        let source = WakeOnDrop::new(source, self.wake.clone());
+        let balance = balance * self.stereo.width;
+        // In mono, mix all the channels down and play them on every speaker.
+        if self.stereo.mono {
+            let gain = 1.0 / source.channels().max(1) as f32;
+            let source = source::ChannelVolume::new(source, vec![gain, gain]);
+            self.sleep_until_end = Some(self.queue_tx.append_with_signal(source));
+        }
         // If balance is equal, just append it to queue.
-        if balance == 0.0 {
+        else if balance == 0.0 {
             self.sleep_until_end = Some(self.queue_tx.append_with_signal(source));
         }
-        // If not, add a Spatial wrapper around the source, then append it to queue.
+        // If not, pan the source, then append it to queue.
         else {
-            let source = source.buffered();
-            let source = Spatial::new(
-                source,
-                [balance, 1.0, 0.0],
-                [-1.0, 0.0, 0.0],
-                [1.0, 0.0, 0.0],
-            );
+            let source = Pan::new(source, balance);
             self.sleep_until_end = Some(self.queue_tx.append_with_signal(source));
         }
     }
 
     /// Maintain the loop.
-    pub fn maintain(&mut self, rng: &mut ThreadRng) {
+    /// A source dropped without signaling its end, as when its bus stopped playing,
+    /// is resumed on a new queue of the bus, up to MAX_LOST_QUEUES times in a row.
+    pub fn maintain(&mut self, bus: &MasterBus, rng: &mut ThreadRng) {
         use std::sync::mpsc::TryRecvError;
         if self.stopped.load(Ordering::Relaxed) {
             return;
         }
         if let Some(song_end_receiver) = &mut self.sleep_until_end {
             match song_end_receiver.try_recv() {
-                Ok(_) => self.on_source_end(rng),
+                Ok(_) => {
+                    self.lost_queues = 0;
+                    self.on_source_end(rng);
+                }
                 Err(TryRecvError::Empty) => (),
                 Err(TryRecvError::Disconnected) => {
-                    panic!("TryRecvError::Disconnected on LoopPlayer maintain!")
+                    self.sleep_until_end = None;
+                    self.lost_queues += 1;
+                    if self.lost_queues > MAX_LOST_QUEUES {
+                        warn!("The loop's queue keeps getting dropped.");
+                        warn!("Will ignore this loop until it changes.");
+                    } else {
+                        warn!("The loop's queue was dropped before its track ended.");
+                        warn!("Will resume the track on a new queue.");
+                        self.move_to(bus, rng);
+                    }
                 }
             }
         }
     }
 
     /// Triggerd when the current source ends.
-    /// If there are no more sources in queue, rotated the files deque, and appends the first file.
+    /// Once the first file has played all its tracks, rotates the files deque.
+    /// Then appends the next track of the first file.
+    /// Stems are all appended again, so the layered loop starts over.
     fn on_source_end(&mut self, rng: &mut ThreadRng) {
         trace!("Song finished.");
         if !self.files.is_empty() && !self.stopped.load(Ordering::Relaxed) {
             trace!("  Playing next song.");
-            self.files.rotate_left(1);
-            self.append_file(rng);
+            if self.layer_fade.is_none() {
+                if let Some(front) = self.files.front_mut() {
+                    if !front.has_next() {
+                        front.played = 0;
+                        self.files.rotate_left(1);
+                    }
+                }
+            }
+            self.append_file(rng, 0, None);
         }
     }
 }
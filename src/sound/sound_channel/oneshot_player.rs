@@ -6,10 +6,27 @@ use super::*;
 struct Control {
     /// This volume is independent from Channel's local_volume and SoundManager's total_volume.
     volume: VolumeLock,
+    /// Gain of the source itself: its file's volume, variation and position attenuation.
+    gain: f32,
     /// Whether the source is stopped.
     stopped: AtomicBool,
     /// Marker to check whether the sound has stopped playing.
     count: Arc<AtomicUsize>,
+    /// The playback threshold of the Sound. Less important sources are stopped first.
+    priority: Threshold,
+}
+
+impl Control {
+    #[inline]
+    fn is_playing(&self) -> bool {
+        !self.stopped.load(Ordering::Relaxed) && self.count.load(Ordering::Relaxed) == 1
+    }
+
+    /// How loud the source plays, before the channel and total volumes.
+    #[inline]
+    fn effective_gain(&self) -> f32 {
+        self.gain * self.volume.get()
+    }
 }
 
 /// Struct responsible of playing oneshot sounds.
@@ -26,6 +43,12 @@ pub struct OneshotPlayer {
     local_is_paused: IsPausedLock,
     /// SoundManager's is_paused
     total_is_paused: IsPausedLock,
+    /// Channel's is_muted.
+    local_is_muted: IsMutedLock,
+    /// Channel's volume lowered by other channels' ducking.
+    duck_volume: VolumeLock,
+    /// Stereo width and mono downmix.
+    stereo: StereoSetting,
 }
 
 impl OneshotPlayer {
@@ -35,6 +58,9 @@ impl OneshotPlayer {
         total_volume: VolumeLock,
         local_is_paused: IsPausedLock,
         total_is_paused: IsPausedLock,
+        local_is_muted: IsMutedLock,
+        duck_volume: VolumeLock,
+        stereo: StereoSetting,
     ) -> Self {
         Self {
             paused: Arc::new(AtomicBool::new(false)),
@@ -43,6 +69,9 @@ impl OneshotPlayer {
             total_volume,
             local_is_paused,
             total_is_paused,
+            local_is_muted,
+            duck_volume,
+            stereo,
         }
     }
 
@@ -69,6 +98,14 @@ impl OneshotPlayer {
         }
     }
 
+    /// Stop all sources and forget them at once.
+    /// For sources that may never finish, like those on a bus that stopped playing.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.stop();
+        self.controls.clear();
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.controls.len()
@@ -79,6 +116,58 @@ impl OneshotPlayer {
         self.len() == 0
     }
 
+    /// Number of sources that haven't finished or been stopped yet.
+    #[inline]
+    pub fn playing_count(&self) -> usize {
+        self.controls.iter().filter(|c| c.is_playing()).count()
+    }
+
+    /// The index and priority of the least important playing source.
+    /// Of equally important sources, the oldest one is chosen.
+    pub fn least_important(&self) -> Option<(usize, Threshold)> {
+        self.controls
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.is_playing())
+            .fold(None, |least, (idx, c)| match least {
+                Some((_, priority)) if priority >= c.priority => least,
+                _ => Some((idx, c.priority)),
+            })
+    }
+
+    /// The index of the playing source to stop for a new source, by the policy.
+    /// A source is only stolen for the lowest priority if it is less important than the new one.
+    pub fn steal_candidate(&self, policy: StealPolicy, priority: Threshold) -> Option<usize> {
+        let mut playing = self
+            .controls
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.is_playing());
+        match policy {
+            StealPolicy::RejectNewest => None,
+            StealPolicy::StealOldest => playing.next().map(|(idx, _)| idx),
+            StealPolicy::StealQuietest => playing
+                .fold(None, |quietest: Option<(usize, f32)>, (idx, c)| {
+                    let volume = c.effective_gain();
+                    match quietest {
+                        Some((_, quietest_volume)) if quietest_volume <= volume => quietest,
+                        _ => Some((idx, volume)),
+                    }
+                })
+                .map(|(idx, _)| idx),
+            StealPolicy::StealLowestPriority => self
+                .least_important()
+                .filter(|&(_, least)| least > priority)
+                .map(|(idx, _)| idx),
+        }
+    }
+
+    /// Stop a single source.
+    #[inline]
+    pub fn stop_source(&self, idx: usize) {
+        self.controls[idx].stopped.store(true, Ordering::SeqCst);
+    }
+
     #[inline]
     pub fn get_volume(&self, idx: usize) -> f32 {
         self.controls[idx].volume.get()
@@ -92,39 +181,51 @@ impl OneshotPlayer {
     /// Add a oneshot source.
     /// Generate a control for the source.
     /// Wraps the source in appropriate control wraps plays it.
-    pub fn add_source<S>(&mut self, device: &Device, source: S, source_volume: f32, balance: f32)
-    where
+    pub fn add_source<S>(
+        &mut self,
+        bus: &MasterBus,
+        source: S,
+        source_volume: f32,
+        balance: f32,
+        priority: Threshold,
+    ) where
         S: Source + Send + 'static,
         S::Item: Sample + Send,
     {
         let count = Arc::new(AtomicUsize::new(1));
         let control = Arc::new(Control {
             volume: VolumeLock::new(),
+            gain: source_volume,
             stopped: AtomicBool::new(false),
             count,
+            priority,
         });
         let paused = self.paused.clone();
         let local_volume = self.local_volume.clone();
         let total_volume = self.total_volume.clone();
         let local_is_paused = self.local_is_paused.clone();
         let total_is_paused = self.total_is_paused.clone();
+        let local_is_muted = self.local_is_muted.clone();
+        let duck_volume = self.duck_volume.clone();
         let control_a = control.clone();
         let control_b = control.clone();
         let source = source
             .pausable(false)
             .amplify(1.0)
             .stoppable()
-            .periodic_access(Duration::from_millis(5), move |src| {
+            .periodic_access(Duration::from_millis(ACCESS_PERIOD_MS), move |src| {
                 if control_a.stopped.load(Ordering::Relaxed) {
                     src.stop();
                 } else {
                     src.inner_mut().set_factor(
                         source_volume
                             * control_a.volume.get()
+                            * duck_volume.get()
                             * local_volume.get()
                             * total_volume.get()
                             * if local_is_paused.get() { 0.0 } else { 1.0 }
-                            * if total_is_paused.get() { 0.0 } else { 1.0 },
+                            * if total_is_paused.get() { 0.0 } else { 1.0 }
+                            * if local_is_muted.get() { 0.0 } else { 1.0 },
                     );
                     src.inner_mut()
                         .inner_mut()
@@ -133,24 +234,20 @@ impl OneshotPlayer {
             })
             .convert_samples::<f32>();
         let source = source::Done::new(source, control_b.count.clone());
-        if balance == 0.0 {
-            play_raw(device, source);
+        let balance = balance * self.stereo.width;
+        if self.stereo.mono {
+            let gain = 1.0 / source.channels().max(1) as f32;
+            bus.add(source::ChannelVolume::new(source, vec![gain, gain]));
+        } else if balance == 0.0 {
+            bus.add(source);
         } else {
-            let source = source.buffered();
-            let source = Spatial::new(
-                source,
-                [balance, 1.0, 0.0],
-                [-1.0, 0.0, 0.0],
-                [1.0, 0.0, 0.0],
-            );
-            play_raw(device, source);
+            bus.add(Pan::new(source, balance));
         }
         self.controls.push(control);
     }
 
     /// Remove all controls if stopped, or if the source has finished playing.
     pub fn maintain(&mut self) {
-        self.controls
-            .retain(|c| !c.stopped.load(Ordering::Relaxed) && c.count.load(Ordering::Relaxed) == 1);
+        self.controls.retain(|c| c.is_playing());
     }
 }
@@ -0,0 +1,105 @@
+use super::*;
+
+/// Pans a source between the left and right speakers with a constant-power law.
+/// Works sample by sample, so long sources are streamed instead of being buffered.
+/// Mono sources are spread over two channels; other sources have their even channels
+/// treated as left, and their odd channels as right.
+pub struct Pan<S> {
+    input: S,
+    /// Gains of the left and right channels.
+    /// Scaled so that a centered source plays as loud as an unpanned one.
+    gains: [f32; 2],
+    /// The right channel of the last mono sample, to be returned next.
+    pending: Option<f32>,
+    /// Channel of the next input sample.
+    channel: u16,
+    /// Number of channels of the input's current frame.
+    channels: u16,
+}
+
+impl<S> Pan<S>
+where
+    S: Source<Item = f32>,
+{
+    /// `balance` goes from -1 (full left) to 1 (full right).
+    pub fn new(input: S, balance: f32) -> Self {
+        let angle = (balance.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+        let channels = input.channels();
+        Self {
+            input,
+            gains: [
+                std::f32::consts::SQRT_2 * angle.cos(),
+                std::f32::consts::SQRT_2 * angle.sin(),
+            ],
+            pending: None,
+            channel: 0,
+            channels,
+        }
+    }
+}
+
+impl<S> Iterator for Pan<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.pending.take() {
+            return Some(right);
+        }
+        // The channel count may only change between frames.
+        if self.channel == 0 {
+            self.channels = self.input.channels();
+        }
+        let sample = self.input.next()?;
+        if self.channels == 1 {
+            self.pending = Some(sample * self.gains[1]);
+            return Some(sample * self.gains[0]);
+        }
+        let gain = self.gains[(self.channel % 2) as usize];
+        self.channel = (self.channel + 1) % self.channels;
+        Some(sample * gain)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (low, high) = self.input.size_hint();
+        if self.input.channels() == 1 {
+            (low * 2, high.map(|high| high * 2))
+        } else {
+            (low, high)
+        }
+    }
+}
+
+impl<S> Source for Pan<S>
+where
+    S: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        let len = self.input.current_frame_len()?;
+        Some(if self.input.channels() == 1 {
+            len * 2 + self.pending.is_some() as usize
+        } else {
+            len
+        })
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels().max(2)
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
@@ -0,0 +1,136 @@
+use super::*;
+use crossbeam::channel::{bounded, Receiver, TryRecvError};
+use std::thread;
+
+/// Skips the start of a source, as when a loop resumes where it left off.
+/// The skipped part is decoded on a worker thread, which hands the source back once done,
+/// so neither the sound thread nor the audio thread decode it. The source is silent until then.
+pub struct SkipStart<S> {
+    state: SkipState<S>,
+    channels: u16,
+    sample_rate: u32,
+    total_duration: Option<Duration>,
+    /// Samples of silence left in the current frame.
+    silent: u16,
+}
+
+enum SkipState<S> {
+    /// The worker is still decoding the skipped part.
+    /// It sends `None` if the source ends before the start.
+    Skipping(Receiver<Option<S>>),
+    Playing(S),
+    Ended,
+}
+
+impl<S> SkipStart<S>
+where
+    S: Source + Send + 'static,
+    S::Item: Sample,
+{
+    /// Skip the first `start` milliseconds of `input`.
+    pub fn new(mut input: S, start: usize) -> Self {
+        let channels = input.channels();
+        let sample_rate = input.sample_rate();
+        let total_duration = input.total_duration();
+        let remaining = start as u64 * sample_rate as u64 / 1000 * channels as u64;
+        let state = if remaining == 0 {
+            SkipState::Playing(input)
+        } else {
+            let (tx, rx) = bounded(1);
+            thread::spawn(move || {
+                let mut remaining = remaining;
+                while remaining > 0 {
+                    if input.next().is_none() {
+                        let _ = tx.send(None);
+                        return;
+                    }
+                    remaining -= 1;
+                }
+                // The source is dropped with the receiver if the loop already stopped.
+                let _ = tx.send(Some(input));
+            });
+            SkipState::Skipping(rx)
+        };
+        Self {
+            state,
+            channels,
+            sample_rate,
+            total_duration,
+            silent: 0,
+        }
+    }
+
+    /// Whether the source is still skipping, and so silent.
+    #[inline]
+    pub fn is_skipping(&self) -> bool {
+        matches!(self.state, SkipState::Skipping(_)) || self.silent > 0
+    }
+}
+
+impl<S> Iterator for SkipStart<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        if self.silent > 0 {
+            self.silent -= 1;
+            return Some(S::Item::zero_value());
+        }
+        // The source is only taken back between frames of silence.
+        if let SkipState::Skipping(rx) = &self.state {
+            match rx.try_recv() {
+                Ok(Some(input)) => self.state = SkipState::Playing(input),
+                Ok(None) | Err(TryRecvError::Disconnected) => self.state = SkipState::Ended,
+                Err(TryRecvError::Empty) => {
+                    self.silent = self.channels.max(1) - 1;
+                    return Some(S::Item::zero_value());
+                }
+            }
+        }
+        match &mut self.state {
+            SkipState::Playing(input) => input.next(),
+            _ => None,
+        }
+    }
+}
+
+impl<S> Source for SkipStart<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        match &self.state {
+            _ if self.silent > 0 => Some(self.silent as usize),
+            SkipState::Skipping(_) => Some(self.channels as usize),
+            SkipState::Playing(input) => input.current_frame_len(),
+            SkipState::Ended => Some(0),
+        }
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        match &self.state {
+            SkipState::Playing(input) => input.channels(),
+            _ => self.channels,
+        }
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        match &self.state {
+            SkipState::Playing(input) => input.sample_rate(),
+            _ => self.sample_rate,
+        }
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
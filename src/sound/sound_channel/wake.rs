@@ -0,0 +1,67 @@
+use super::*;
+
+/// Wakes the sound thread when the source is dropped, as when its queue moves on to the next source,
+/// or the queue itself is dropped. The queue signals the end of a source before dropping it,
+/// so the woken thread finds the signal waiting.
+pub struct WakeOnDrop<S> {
+    input: S,
+    wake: Sender<()>,
+}
+
+impl<S> WakeOnDrop<S> {
+    #[inline]
+    pub fn new(input: S, wake: Sender<()>) -> Self {
+        Self { input, wake }
+    }
+}
+
+impl<S> Drop for WakeOnDrop<S> {
+    fn drop(&mut self) {
+        // A full channel means the thread is already being woken.
+        let _ = self.wake.try_send(());
+    }
+}
+
+impl<S> Iterator for WakeOnDrop<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<S::Item> {
+        self.input.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<S> Source for WakeOnDrop<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
@@ -1,5 +1,23 @@
 use super::*;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::thread;
+
+/// How long to wait after the last volume change before writing the default settings, in milliseconds.
+const SETTINGS_WRITE_DELAY: usize = 1000;
+
+/// How long it takes for one call of a Sound to be forgotten, in milliseconds.
+const RECENT_CALL_DECAY: usize = 500;
+
+/// How often the cache statistics and the playing tracks are sent to the UI, in milliseconds.
+const STATUS_PERIOD: usize = 1000;
+
+/// How often a manager is maintained while sounds or timers run down, in milliseconds.
+const MAINTAIN_PERIOD: usize = 10;
+
+/// How often the output devices are checked, in milliseconds.
+/// A device that played nothing for this long is considered lost.
+const DEVICE_CHECK_PERIOD: usize = 2000;
 
 /// The struct that parses the log entries.
 /// Plays appropriate sounds on appropriate channels;
@@ -8,14 +26,32 @@ use std::collections::HashMap;
 pub struct SoundManager {
     /// All the Sounds loaded from the soundpack.
     sounds: Vec<SoundEntry>,
+    /// The patterns of all the Sounds, in the same order.
+    sound_patterns: PatternSet,
+    /// Buffer for the indices of the Sounds matching the current log line.
+    matched: Vec<usize>,
     /// The indices of the recently played Sounds.
     recent: HashSet<usize>,
+    /// Time since the recent calls of the Sounds last decayed.
+    recent_call_timer: usize,
     /// The previous log message. Replaces `x[0-9]+` messages.
     previous_log: String,
     /// The patterns that SoundManager shouldn't process.
-    ignore_list: Vec<Regex>,
-    /// The sound device of the system.
-    device: Device,
+    ignore_list: PatternSet,
+    /// Rules lowering the volume of channels while other channels play.
+    ducking_rules: Vec<DuckingRule>,
+    /// Positions of the values captured by the Sounds' positionFrom groups.
+    positions: Vec<PositionRule>,
+    /// Variables describing the state of the game.
+    game_state: GameState,
+    /// Conditions on the game state, for the channels that have them.
+    channel_conditions: HashMap<Box<str>, Vec<StateCondition>>,
+    /// The channels whose conditions currently don't hold.
+    disabled_channels: HashSet<Box<str>>,
+    /// Mixes the channels into the output devices they are routed to.
+    outputs: Outputs,
+    /// Maximum number of oneshots playing at once, over all channels.
+    max_voices: usize,
     /// All the channels, sorted alphabetically.
     channels: BTreeMap<Box<str>, SoundChannel>,
     /// The total volume.
@@ -23,330 +59,136 @@ pub struct SoundManager {
     /// Total is_paused.
     total_is_paused: IsPausedLock,
     /// Total playback_treshold
-    total_threshold: u8,
+    total_threshold: Threshold,
+    /// Whether all channels are muted.
+    total_is_muted: bool,
+    /// The channel that is soloed, if any. All other channels are silenced.
+    solo: Option<Box<str>>,
     /// Sender for UIMessage sent to the UI.
     ui_sender: Sender<UIMessage>,
     /// RNG for probability and randomly choosing a soundfile from many.
     rng: ThreadRng,
+    /// Time left until the current volumes and thresholds are written as the defaults.
+    /// None if there are no unsaved changes.
+    settings_timeout: Option<usize>,
+    /// Cache of the sound files, shared by all the channels.
+    cache: SoundCache,
+    /// Time since the cache statistics and the playing tracks were last checked.
+    status_timer: usize,
+    /// The cache statistics last sent to the UI.
+    cache_stats: CacheStats,
+    /// The playing tracks last sent to the UI, by channel.
+    now_playing: HashMap<Box<str>, Option<NowPlaying>>,
+    /// Time since the output devices were last checked.
+    device_timer: usize,
+    /// Woken by the loops when their tracks end.
+    wake_rx: Receiver<()>,
+}
+
+/// The mixer state of a manager, kept when the sound thread restarts.
+#[derive(Clone)]
+pub struct MixerState {
+    mixes: Vec<ChannelMix>,
+    /// The muted channels, and "total" if all channels are muted.
+    muted: Vec<Box<str>>,
+    solo: Option<Box<str>>,
+}
+
+/// Try to decode every file of the soundpack, and report the ones that can't be,
+/// instead of finding out when they should be played.
+/// Opening every file takes a while on large soundpacks, so it is done on its own thread.
+fn check_files(sounds: &[SoundEntry], cache: &SoundCache, ui_sender: &Sender<UIMessage>) {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut seen: HashSet<&Path> = HashSet::new();
+    for path in sounds
+        .iter()
+        .flat_map(|sound| sound.files.iter())
+        .flat_map(|file| file.r#type.paths())
+    {
+        if seen.insert(path) {
+            paths.push(path.to_path_buf());
+        }
+    }
+    let cache = cache.clone();
+    let ui_sender = ui_sender.clone();
+    let spawned = thread::Builder::new()
+        .name("file_check".to_string())
+        .spawn(move || {
+            let mut undecodable = Vec::new();
+            for path in paths.iter() {
+                match formats::check(path, &cache) {
+                    Ok(format) => trace!("{} is {}", path.display(), format),
+                    Err(e) => {
+                        warn!("{}", e);
+                        undecodable.push(e);
+                    }
+                }
+            }
+            info!(
+                "Checked {} sound files, {} can't be decoded.",
+                paths.len(),
+                undecodable.len()
+            );
+            if !undecodable.is_empty() {
+                // The UI may be gone by now.
+                let _ = ui_sender.send(UIMessage::UndecodableFiles(undecodable));
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to start checking the sound files: {}", e);
+        warn!("Will ignore this check.");
+    }
 }
 
 impl SoundManager {
     /// Create a new manager.
     /// A new manager is created every time the user reloads a soundpack.
-    #[allow(clippy::cognitive_complexity)]
-    pub fn new(sound_dir: &Path, ui_sender: Sender<UIMessage>) -> Result<Self> {
+    pub fn new(
+        sound_dir: &Path,
+        ui_sender: Sender<UIMessage>,
+        stereo: StereoSetting,
+        cache: SoundCache,
+        device: Option<&str>,
+    ) -> Result<Self> {
         let total_volume = VolumeLock::new();
         let total_is_paused = IsPausedLock::new();
-        let mut sounds = Vec::new();
-        let mut channel_settings = None;
-        let device = default_output_device().ok_or("Failed to get default audio output device.")?;
+        let Soundpack {
+            sounds,
+            channel_names: pack_channels,
+            channel_settings,
+            ducking_rules,
+            state_variables,
+            state_updates,
+            threshold_names,
+            positions,
+            master,
+            unresolved,
+        } = load_soundpack(sound_dir)?;
+        let max_voices = master.max_voices;
+        let outputs = Outputs::new(device, master).map_err(|e| SoundError::Device {
+            soundpack: sound_dir.into(),
+            message: e.to_string(),
+        })?;
+        if let Some(name) = device.filter(|_| outputs.is_main_lost()) {
+            ui_sender.send(UIMessage::OutputDeviceUnavailable(name.into()))?;
+        }
+        ui_sender.send(UIMessage::OutputDeviceChanged(
+            outputs.main_name().map(Box::from),
+        ))?;
+        let (wake_tx, wake_rx) = bounded(1);
         let mut channels: BTreeMap<Box<str>, SoundChannel> = BTreeMap::new();
-        channels.insert(
-            String::from("misc").into_boxed_str(),
-            SoundChannel::new(
-                &device,
-                "misc",
+        for channel_name in pack_channels {
+            let channel = SoundChannel::new(
+                outputs.bus(None),
+                &channel_name,
                 total_volume.clone(),
                 total_is_paused.clone(),
-            ),
-        );
-
-        /// Traverse the soundpack in DFS. Parses XML files.
-        fn visit_dir(dir: &Path, func: &mut dyn FnMut(&Path) -> Result<()>) -> Result<()> {
-            trace!("Directory: {:?}", dir);
-            match fs::read_dir(dir) {
-                Ok(entries) => {
-                    for entry in entries {
-                        let entry = entry?;
-                        let path = entry.path();
-                        if path.is_dir() {
-                            visit_dir(&path, func)?;
-                        } else if path.is_file()
-                            && path.extension().map_or(false, |ext| ext == "xml")
-                        {
-                            func(&path)?;
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!("Error while visiting {}: {}", dir.display(), e);
-                    warn!("Will ignore this directory.");
-                }
-            }
-            Ok(())
-        }
-
-        // Parse an XML file.
-        let mut func = |file_path: &Path| -> Result<()> {
-            use quick_xml::{events::Event, Reader};
-            trace!(" XML: {:?}", file_path);
-            let mut reader = Reader::from_file(file_path)?;
-            let mut current_sound: Option<SoundEntry> = None;
-            let buf = &mut Vec::new();
-            loop {
-                match reader.read_event(buf) {
-                    // <...> or <.../>
-                    Ok(Event::Start(ref data)) | Ok(Event::Empty(ref data)) => {
-                        let local_name = data.local_name();
-                        // <sound> or <sound/>
-                        if local_name == b"sound" {
-                            let mut pattern: Option<Regex> = None;
-                            let mut channel: Option<Box<str>> = None;
-                            let mut loop_attr: Option<bool> = None;
-                            let mut concurency: Option<usize> = None;
-                            let mut timeout: Option<usize> = None;
-                            let mut probability: Option<usize> = None;
-                            let mut delay: Option<usize> = None;
-                            let mut halt_on_match: bool = false;
-                            let mut random_balance: bool = false;
-                            #[allow(unused_mut)]
-                            let mut playback_threshold: u8 = 4;
-                            let files = Vec::new();
-                            let weights = Vec::new();
-
-                            for attr in data.attributes().with_checks(false) {
-                                let attr = attr?;
-                                // This value came from an XML file, so it must be utf8.
-                                let attr_value =
-                                    unsafe { std::str::from_utf8_unchecked(&attr.value) };
-                                match attr.key {
-                                    b"logPattern" => {
-                                        let processed =
-                                            FAULTY_ESCAPE.replace_all(&attr_value, "$1");
-                                        let processed = EMPTY_EXPR.replace_all(&processed, ")?");
-                                        pattern = Some(Regex::new(&processed)?);
-                                    }
-                                    b"channel" => {
-                                        let channel_name: Box<str> = attr_value.into();
-                                        if !channels.contains_key(&channel_name) {
-                                            channels.insert(
-                                                channel_name.clone(),
-                                                SoundChannel::new(
-                                                    &device,
-                                                    &channel_name,
-                                                    total_volume.clone(),
-                                                    total_is_paused.clone(),
-                                                ),
-                                            );
-                                        }
-                                        channel = Some(channel_name);
-                                    }
-                                    b"loop" => {
-                                        loop_attr.replace(attr_value == "start");
-                                    }
-                                    b"concurency" => {
-                                        concurency = Some(attr_value.parse()?);
-                                    }
-                                    b"timeout" => {
-                                        timeout = Some(attr_value.parse()?);
-                                    }
-                                    // Probability was mispelled...
-                                    b"propability" | b"probability" => {
-                                        probability = Some(attr_value.parse()?);
-                                    }
-                                    b"delay" => {
-                                        delay = Some(attr_value.parse()?);
-                                    }
-                                    b"haltOnMatch" => {
-                                        halt_on_match = attr_value == "true";
-                                    }
-                                    b"randomBalance" => {
-                                        random_balance = attr_value == "true";
-                                    }
-                                    b"playbackThreshhold" => {
-                                        playback_threshold = attr_value.parse()?;
-                                    }
-                                    b"ansiFormat" => (),
-                                    b"ansiPattern" => (),
-                                    _ => {
-                                        warn!("Unknown sound value: {}", unsafe {
-                                            std::str::from_utf8_unchecked(attr.key)
-                                        });
-                                        warn!("Will ignore this value.");
-                                    }
-                                }
-                            }
-
-                            trace!("  SoundEntry");
-                            if let Some(pattern) = pattern {
-                                trace!("  -Pattern: {}", pattern);
-                                current_sound = Some(SoundEntry {
-                                    pattern,
-                                    channel,
-                                    loop_attr,
-                                    concurency,
-                                    timeout,
-                                    probability,
-                                    delay,
-                                    halt_on_match,
-                                    random_balance,
-                                    playback_threshold,
-                                    files,
-                                    weights,
-                                    current_timeout: 0,
-                                    recent_call: 0,
-                                });
-                            } else {
-                                warn!("A SoundEntry in {:?} doesn't have a pattern!", file_path);
-                                warn!("Will ignore this SoundEntry.");
-                            }
-                        }
-                        // <soundFile> or <soundFile/>
-                        else if local_name == b"soundFile" {
-                            if current_sound.is_none() {
-                                warn!(
-                                    "A SoundFile in {:?} was declared outside of a valid Sound!",
-                                    file_path
-                                );
-                                warn!("Will ignore this SoundFile.");
-                            }
-                            let mut path = PathBuf::from(file_path);
-                            let mut is_playlist = false;
-                            let mut weight: f32 = 100.0;
-                            let mut volume: f32 = 1.0;
-                            let mut random_balance: bool = false;
-                            let mut balance: f32 = 0.0;
-                            let mut delay: usize = 0;
-
-                            for attr in data.attributes() {
-                                let attr = attr?;
-                                let attr_value =
-                                    unsafe { std::str::from_utf8_unchecked(&attr.value) };
-                                match attr.key {
-                                    b"fileName" => path.set_file_name(attr_value),
-                                    b"weight" => {
-                                        weight = attr_value.parse()?;
-                                    }
-                                    b"volumeAdjustment" => {
-                                        // TODO: check if linear conversion from decibel to normal volume does work
-                                        volume = (attr_value.parse::<f32>()? + 40.0) / 40.0;
-                                    }
-                                    b"randomBalance" => {
-                                        random_balance = attr_value == "true";
-                                    }
-                                    b"balanceAdjustment" => {
-                                        balance = attr_value.parse()?;
-                                    }
-                                    b"delay" => {
-                                        delay = attr_value.parse()?;
-                                    }
-                                    b"playlist" => {
-                                        is_playlist = true;
-                                    }
-                                    _ => {
-                                        warn!("Unknown sound value: {}", unsafe {
-                                            std::str::from_utf8_unchecked(attr.key)
-                                        });
-                                        warn!("Will ignore this value.");
-                                    }
-                                }
-                            }
-                            trace!("  --SoundFile: {:?}", path);
-                            let r#type = if is_playlist {
-                                let path_vec = parse_playlist(&path)?;
-                                SoundFileType::IsPlaylist(path_vec)
-                            } else {
-                                // test_file(&path);
-                                SoundFileType::IsPath(path)
-                            };
-                            let sound_file = SoundFile {
-                                r#type,
-                                weight,
-                                volume,
-                                random_balance,
-                                delay,
-                                balance,
-                            };
-                            let sound = current_sound.as_mut().unwrap();
-                            sound.files.push(sound_file);
-                            sound.weights.push(weight);
-                        } else if local_name == b"channelSettings" {
-                            trace!("  ChannelSettings");
-                            channel_settings = Some(HashMap::new());
-                        }
-                        // <channelSetting/>
-                        else if local_name == b"channelSetting" {
-                            if channel_settings.is_none() {
-                                warn!("A ChannelSetting in {:?} was declared outside of ChannelSettings!", file_path);
-                                warn!("Will ignore this ChannelSetting.");
-                                continue;
-                            }
-                            trace!("  -ChannelSetting");
-                            let mut name: Option<Box<str>> = None;
-                            let mut play_type = ChannelPlayType::All;
-                            for attr in data.attributes() {
-                                let attr = attr?;
-                                let attr_value =
-                                    unsafe { std::str::from_utf8_unchecked(&attr.value) };
-                                match attr.key {
-                                    b"name" => {
-                                        trace!("  --name: {}", attr_value);
-                                        name.replace(Box::from(attr_value));
-                                    }
-                                    b"playType" => {
-                                        trace!("  --play_type: {}", attr_value);
-                                        match attr_value {
-                                            "singleEager" => {
-                                                play_type = ChannelPlayType::SingleEager
-                                            }
-                                            "singleLazy" => play_type = ChannelPlayType::SingleLazy,
-                                            "all" => play_type = ChannelPlayType::All,
-                                            other => {
-                                                warn!("Unknown Channel PlayType: {}", other);
-                                                warn!("Will ignore this value.");
-                                            }
-                                        }
-                                    }
-                                    _ => (),
-                                }
-                            }
-                            if let Some(name) = name {
-                                let channel_setting = ChannelSetting { play_type };
-                                channel_settings
-                                    .as_mut()
-                                    .unwrap()
-                                    .insert(name, channel_setting);
-                            } else {
-                                warn!(
-                                    "A ChannelSetting in {:?} didn't specify a channel name.",
-                                    file_path
-                                );
-                                warn!("Will ignore this ChannelSetting.");
-                            }
-                        }
-                    }
-
-                    // </Sound>
-                    Ok(Event::End(data)) => {
-                        if current_sound.is_some() && data.local_name() == b"sound" {
-                            sounds.push(current_sound.take().ok_or(
-                                "Tried to finish a Sound, even though there is no Sound!",
-                            )?);
-                        }
-                    }
-
-                    Ok(Event::Eof) => return Ok(()),
-
-                    Err(e) => {
-                        error!(
-                            "Error parsing xml at position {}: {:?}",
-                            reader.buffer_position(),
-                            e
-                        );
-                        return Err(format!(
-                            "Error parsing xml at position {}: {:?}",
-                            reader.buffer_position(),
-                            e
-                        )
-                        .into());
-                    }
-
-                    _ => (), // Other Reader::Events aren't used, just ignore them.
-                }
-            }
-        };
-
-        visit_dir(sound_dir, &mut func)?; // Run the DFS!
+                stereo,
+                cache.clone(),
+                wake_tx.clone(),
+            );
+            channels.insert(channel_name, channel);
+        }
 
         // Add "total" channel.
         let mut channel_names: Vec<Box<str>> = vec!["total".into()];
@@ -362,60 +204,137 @@ impl SoundManager {
         // Add the "misc" channel last, so it comes last in the UI.
         channel_names.push("misc".into());
         ui_sender.send(UIMessage::LoadedSoundpack(channel_names))?;
+        ui_sender.send(UIMessage::LoadedThresholdNames(threshold_names))?;
 
         info!("Soundpack loaded!");
+        let preloaded = cache.preload(
+            sounds
+                .iter()
+                .filter(|sound| sound.preload)
+                .flat_map(|sound| sound.files.iter())
+                .flat_map(|file| file.r#type.paths()),
+        );
+        info!("Preloaded {} sound files.", preloaded);
+        // Reloads and restarts don't report the same files again.
+        if cache.should_check(sound_dir) {
+            if !unresolved.is_empty() {
+                ui_sender.send(UIMessage::UnresolvedPlaylistEntries(unresolved))?;
+            }
+            check_files(&sounds, &cache, &ui_sender);
+        }
+        let sound_patterns = PatternSet::new(sounds.iter().map(|s| s.pattern.as_str()))
+            .map_err(|e| SoundError::soundpack(sound_dir, None, &e))?;
+        let game_state = GameState::new(state_variables, state_updates)
+            .map_err(|e| SoundError::soundpack(sound_dir, None, e.as_ref()))?;
+        for sound in sounds.iter() {
+            game_state.check_conditions(&sound.state_conditions);
+            if let Some(name) = &sound.probability_boost {
+                if game_state.get_number(name).is_none() {
+                    warn!(
+                        "The probabilityBoost of {} isn't a numeric StateVariable: {}",
+                        sound.pattern, name
+                    );
+                    warn!("Will ignore this value.");
+                }
+            }
+        }
         let mut manager = Self {
             sounds,
+            sound_patterns,
+            matched: Vec::new(),
             recent: HashSet::new(),
+            recent_call_timer: 0,
             previous_log: String::new(),
-            ignore_list: Vec::new(),
-            device,
+            ignore_list: PatternSet::empty(),
+            ducking_rules,
+            positions,
+            game_state,
+            channel_conditions: HashMap::new(),
+            disabled_channels: HashSet::new(),
+            outputs,
+            max_voices,
             channels,
             total_volume,
             total_is_paused,
-            total_threshold: 4,
+            total_threshold: Threshold::Everything,
+            total_is_muted: false,
+            solo: None,
             ui_sender,
             rng: thread_rng(),
+            settings_timeout: None,
+            cache,
+            status_timer: STATUS_PERIOD,
+            cache_stats: CacheStats::default(),
+            now_playing: HashMap::new(),
+            device_timer: 0,
+            wake_rx,
         };
 
         // Apply channels settings if it exists.
         if let Some(channel_settings) = channel_settings {
-            manager.apply_channel_settings(channel_settings);
-        }
-
-        let conf_path = dirs::config_dir()
-            .map(|mut p| {
-                p.push("soundsense-rs/default-volumes.ini");
-                debug!("Checking for default-volumes config in: {}", p.display());
-                p
-            })
-            .filter(|p| p.is_file())
-            .or_else(|| {
-                std::env::current_exe()
-                    .ok()
-                    .map(|mut p| {
-                        p.pop();
-                        p.push("default-volumes.ini");
-                        debug!("Checking for default-volumes config in: {}", p.display());
-                        p
-                    })
-                    .filter(|p| p.is_file())
-            });
-        if let Some(conf_path) = conf_path {
-            // Check if there are default volumes.
-            let file = fs::File::open(conf_path)?;
-            // Apply default volumes.
-            manager.get_default_volume(file)?;
+            manager.apply_channel_settings(channel_settings)?;
+        }
+
+        // Apply default volumes and thresholds if there are any.
+        if let Some(conf_path) = find_config_file("default-volumes.ini") {
+            manager.get_default_volume(&conf_path)?;
+        }
+        if let Some(conf_path) = find_config_file("default-thresholds.ini") {
+            manager.get_default_thresholds(&conf_path)?;
         }
 
         Ok(manager)
     }
 
     /// Apply ChannelSettings.
-    fn apply_channel_settings(&mut self, channel_settings: HashMap<Box<str>, ChannelSetting>) {
+    fn apply_channel_settings(
+        &mut self,
+        channel_settings: HashMap<Box<str>, ChannelSetting>,
+    ) -> Result<()> {
         for (name, setting) in channel_settings.iter() {
             if let Some(channel) = self.channels.get_mut(name) {
                 channel.play_type = setting.play_type;
+                channel.steal_policy = setting.steal_policy;
+                channel.set_fade(setting.fade);
+                channel.oneshot_ducking = setting.oneshot_ducking;
+                channel.loop_ducking = setting.loop_ducking;
+                channel.set_layers(setting.layers.clone());
+                channel.set_position(setting.position);
+                channel.set_playlist(setting.playlist_mode, setting.resume);
+                if let Some(device) = &setting.device {
+                    // The channel moves to its device once it is available.
+                    channel.device = Some(device.clone());
+                    if !self.outputs.route(device) {
+                        self.ui_sender
+                            .send(UIMessage::OutputDeviceUnavailable(device.clone()))?;
+                    }
+                }
+            }
+            if !setting.state_conditions.is_empty() {
+                self.game_state.check_conditions(&setting.state_conditions);
+                self.channel_conditions
+                    .insert(name.clone(), setting.state_conditions.clone());
+            }
+        }
+        self.update_channel_conditions();
+        Ok(())
+    }
+
+    /// Disable the channels whose game state conditions don't hold anymore, stopping their loops.
+    /// Enable those whose conditions hold again.
+    fn update_channel_conditions(&mut self) {
+        for (name, conditions) in self.channel_conditions.iter() {
+            let is_met = self.game_state.all_met(conditions);
+            if is_met {
+                if self.disabled_channels.remove(name) {
+                    trace!("channel {} enabled by game state", name);
+                }
+            } else if !self.disabled_channels.contains(name) {
+                trace!("channel {} disabled by game state", name);
+                if let Some(channel) = self.channels.get_mut(name) {
+                    channel.stop_loop(0);
+                }
+                self.disabled_channels.insert(name.clone());
             }
         }
     }
@@ -425,22 +344,171 @@ impl SoundManager {
         {
             let sounds = &mut self.sounds;
             let recent = &mut self.recent;
+            self.recent_call_timer += dt;
+            let decay = self.recent_call_timer / RECENT_CALL_DECAY;
+            self.recent_call_timer %= RECENT_CALL_DECAY;
             // Tick down timeout and recent_call.
-            // If the timeout == 0, remove from recent list.
+            // If both reach 0, remove from recent list.
             recent.retain(|&i| {
                 let timeout = sounds[i].current_timeout.saturating_sub(dt);
-                let recent_call = sounds[i].recent_call.saturating_sub(1);
+                let recent_call = sounds[i].recent_call.saturating_sub(decay);
                 sounds[i].current_timeout = timeout;
                 sounds[i].recent_call = recent_call;
-                timeout != 0
+                timeout != 0 || recent_call != 0
             });
         }
+        self.update_intensity();
+        self.game_state.maintain(dt);
+        self.update_channel_conditions();
         for chn in self.channels.values_mut() {
-            chn.maintain(&mut self.rng, dt);
+            let bus = self.outputs.bus(chn.device.as_deref());
+            chn.maintain(bus, &mut self.rng, dt);
+        }
+        self.apply_ducking(dt);
+        self.report_status(dt)?;
+        self.check_devices(dt)?;
+        Ok(())
+    }
+
+    /// Whether anything needs maintaining: sounds playing or waiting to play,
+    /// timeouts or game state values running down, or settings waiting to be written.
+    fn is_busy(&self) -> bool {
+        !self.recent.is_empty()
+            || self.settings_timeout.is_some()
+            || !self.game_state.is_settled()
+            || self.channels.values().any(|channel| channel.is_busy())
+    }
+
+    /// How long the manager can wait before it is maintained again, in milliseconds.
+    /// Every MAINTAIN_PERIOD while it is busy. While only loops play, until the next status report,
+    /// since the loops wake the sound thread when their tracks end. Otherwise until the next device check.
+    pub fn maintenance_delay(&self) -> usize {
+        let device_check = DEVICE_CHECK_PERIOD.saturating_sub(self.device_timer);
+        if self.is_busy() {
+            MAINTAIN_PERIOD
+        } else if self.channels.values().any(|channel| channel.is_looping()) {
+            STATUS_PERIOD
+                .saturating_sub(self.status_timer)
+                .min(device_check)
+        } else {
+            device_check
+        }
+    }
+
+    /// Receives a message when a loop's track ends, and the manager should be maintained.
+    #[inline]
+    pub fn wakeups(&self) -> Receiver<()> {
+        self.wake_rx.clone()
+    }
+
+    /// Every DEVICE_CHECK_PERIOD, find the output devices that were lost or came back.
+    /// The channels playing on them move to another bus, continuing their loops.
+    fn check_devices(&mut self, dt: usize) -> Result<()> {
+        self.device_timer += dt;
+        if self.device_timer < DEVICE_CHECK_PERIOD {
+            return Ok(());
+        }
+        self.device_timer = 0;
+        let check = self.outputs.check();
+        let outputs = &self.outputs;
+        let rng = &mut self.rng;
+        for channel in self.channels.values_mut() {
+            let is_moved = match channel.device.as_deref() {
+                Some(name) if check.routes_changed.iter().any(|n| n.as_ref() == name) => true,
+                Some(name) if outputs.is_routed(name) => false,
+                _ => check.main_changed,
+            };
+            if is_moved {
+                channel.move_to(outputs.bus(channel.device.as_deref()), rng);
+            }
+        }
+        for event in check.events {
+            self.ui_sender.send(match event {
+                DeviceEvent::Lost(name) => UIMessage::OutputDeviceLost(name),
+                DeviceEvent::Recovered(name) => UIMessage::OutputDeviceRecovered(name),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Send the cache statistics and the playing tracks to the UI every STATUS_PERIOD,
+    /// if they changed.
+    fn report_status(&mut self, dt: usize) -> Result<()> {
+        self.status_timer += dt;
+        if self.status_timer < STATUS_PERIOD {
+            return Ok(());
+        }
+        self.status_timer = 0;
+        let stats = self.cache.stats();
+        if stats != self.cache_stats {
+            self.cache_stats = stats;
+            self.ui_sender.send(UIMessage::CacheStatsUpdated(stats))?;
+        }
+        for (name, channel) in self.channels.iter() {
+            let now_playing = channel.now_playing();
+            let is_changed = self
+                .now_playing
+                .get(name)
+                .map_or(now_playing.is_some(), |last| *last != now_playing);
+            if is_changed {
+                self.ui_sender.send(UIMessage::NowPlayingUpdated(
+                    name.clone(),
+                    now_playing.clone(),
+                ))?;
+                self.now_playing.insert(name.clone(), now_playing);
+            }
         }
         Ok(())
     }
 
+    /// Set the intensity of the layered channels from the recent calls of the Sounds
+    /// on their source channels.
+    fn update_intensity(&mut self) {
+        let sounds = &self.sounds;
+        let recent = &self.recent;
+        for channel in self.channels.values() {
+            if let Some(layers) = channel.layers() {
+                let calls: usize = recent
+                    .iter()
+                    .map(|&i| &sounds[i])
+                    .filter(|sound| {
+                        let name = sound.channel.as_deref().unwrap_or("misc");
+                        layers.sources.is_empty()
+                            || layers.sources.iter().any(|source| source.as_ref() == name)
+                    })
+                    .map(|sound| sound.recent_call)
+                    .sum();
+                channel.set_intensity((calls as f32 / layers.full_intensity as f32).min(1.0));
+            }
+        }
+    }
+
+    /// Duck the channels whose trigger channels are playing oneshots that can be heard.
+    /// A trigger channel's loop doesn't count, or a looping channel would duck its target for good.
+    /// If several rules apply to a channel, the deepest one wins.
+    fn apply_ducking(&mut self, dt: usize) {
+        if self.ducking_rules.is_empty() {
+            return;
+        }
+        let channels = &mut self.channels;
+        let mut targets: HashMap<&str, &DuckingRule> = HashMap::new();
+        for rule in self.ducking_rules.iter() {
+            let is_triggered = channels
+                .get(&rule.trigger)
+                .is_some_and(|channel| channel.is_audible() && channel.voice_count() != 0);
+            if is_triggered {
+                let deepest = targets.entry(&rule.target).or_insert(rule);
+                if rule.amount < deepest.amount {
+                    *deepest = rule;
+                }
+            }
+        }
+        for (name, channel) in channels.iter_mut() {
+            let rule = targets.get(name.as_ref());
+            channel.update_ducking(rule.map(|r| (r.amount, r.attack, r.release)), dt);
+        }
+    }
+
     /// Set the volume of all, or specific channels.
     pub fn set_volume(&mut self, channel_name: &str, volume: f32) -> Result<()> {
         if channel_name == "total" {
@@ -451,7 +519,7 @@ impl SoundManager {
         Ok(())
     }
 
-    pub fn set_threshold(&mut self, channel_name: &str, threshold: u8) -> Result<()> {
+    pub fn set_threshold(&mut self, channel_name: &str, threshold: Threshold) -> Result<()> {
         if channel_name == "total" {
             self.total_threshold = threshold;
         } else if let Some(channel) = self.channels.get_mut(channel_name) {
@@ -494,15 +562,95 @@ impl SoundManager {
         Ok(())
     }
 
+    /// Mute/Unmute a channel, or all channels if "total".
+    pub fn mute(&mut self, channel_name: &str) -> Result<()> {
+        let is_muted = if channel_name == "total" {
+            self.total_is_muted = !self.total_is_muted;
+            self.total_is_muted
+        } else if let Some(channel) = self.channels.get_mut(channel_name) {
+            channel.flip_mute()
+        } else {
+            return Ok(());
+        };
+        self.update_silenced();
+        self.ui_sender.send(UIMessage::ChannelWasMuted(
+            Box::from(channel_name),
+            is_muted,
+        ))?;
+        Ok(())
+    }
+
+    /// Solo/Unsolo a channel. Soloing "total" clears the solo.
+    pub fn solo(&mut self, channel_name: &str) -> Result<()> {
+        if channel_name == "total" || self.solo.as_deref() == Some(channel_name) {
+            self.solo = None;
+        } else if self.channels.contains_key(channel_name) {
+            self.solo = Some(Box::from(channel_name));
+        } else {
+            return Ok(());
+        }
+        self.update_silenced();
+        self.ui_sender
+            .send(UIMessage::SoloWasChanged(self.solo.clone()))?;
+        Ok(())
+    }
+
+    /// The main output device chosen by the user, or None for the default device.
+    #[inline]
+    pub fn output_device(&self) -> Option<&str> {
+        self.outputs.main_name()
+    }
+
+    /// Play on another main output device, or on the default device if None.
+    /// The channels playing on the main device continue their loops on the new one.
+    /// Keeps the current device if the new one isn't available.
+    pub fn set_output_device(&mut self, name: Option<&str>) -> Result<()> {
+        if let Err(e) = self.outputs.set_main(name) {
+            warn!("{}", e);
+            warn!("Will keep the current device.");
+            self.ui_sender.send(UIMessage::OutputDeviceUnavailable(
+                name.unwrap_or("default").into(),
+            ))?;
+            return Ok(());
+        }
+        let outputs = &self.outputs;
+        let rng = &mut self.rng;
+        for channel in self.channels.values_mut() {
+            let is_routed = channel
+                .device
+                .as_deref()
+                .is_some_and(|name| outputs.is_routed(name));
+            if !is_routed {
+                channel.move_to(outputs.bus(None), rng);
+            }
+        }
+        self.ui_sender
+            .send(UIMessage::OutputDeviceChanged(name.map(Box::from)))?;
+        Ok(())
+    }
+
+    /// Silence the channels that are muted, or not soloed while another channel is.
+    fn update_silenced(&mut self) {
+        for (channel_name, channel) in self.channels.iter_mut() {
+            let is_silenced = self.total_is_muted
+                || channel.is_muted()
+                || self.solo.as_ref().is_some_and(|solo| solo != channel_name);
+            channel.set_silenced(is_silenced);
+        }
+    }
+
     pub fn finish(mut self) {
+        // The UI is notified of write errors, and a failed send can't be reported anyway.
+        let _ = self.flush_settings();
         for (_, channel) in self.channels.iter_mut() {
             channel.finish();
         }
     }
 
-    /// Reload the ignore list.
-    pub fn set_ignore_list(&mut self, ignore_list: Vec<Regex>) -> Result<()> {
-        self.ignore_list = ignore_list;
+    /// Reload the ignore list, read from `path`.
+    pub fn set_ignore_list(&mut self, path: &Path, ignore_list: Vec<Regex>) -> Result<()> {
+        self.ignore_list = PatternSet::new(ignore_list.iter().map(|r| r.as_str()))
+            .map_err(|e| SoundError::config(path, &e))?;
         self.ui_sender.send(UIMessage::LoadedIgnoreList)?;
         Ok(())
     }
@@ -515,204 +663,527 @@ impl SoundManager {
         lazy_static! {
             static ref REPEAT_PATTERN: Regex = Regex::new(r"^x[0-9]+$").unwrap();
         }
-        if REPEAT_PATTERN.is_match(&log) {
+        if REPEAT_PATTERN.is_match(log) {
             log = self.previous_log.as_str();
             trace!(" swapped: {}", log);
         } else {
             self.previous_log = log.to_string();
         }
 
-        for pattern in self.ignore_list.iter() {
-            if pattern.is_match(log) {
-                return Ok(());
-            }
+        if self.ignore_list.is_match(log) {
+            return Ok(());
         }
 
+        self.game_state.process_log(log);
+        // Loops of channels disabled by this line are stopped on the next maintain.
+        let game_state = &self.game_state;
+        let channel_conditions = &self.channel_conditions;
+        let is_enabled = |name: &str| {
+            channel_conditions
+                .get(name)
+                .is_none_or(|conditions| game_state.all_met(conditions))
+        };
+
         let rng = &mut self.rng;
         let sounds = &mut self.sounds;
         let recent = &mut self.recent;
+        let matched = &mut self.matched;
+        let positions = &self.positions;
+        let total_threshold = self.total_threshold;
+        let max_voices = self.max_voices;
+        // Find all the Sounds whose pattern matches the log in one pass,
+        // then handle them in the soundpack's order.
+        self.sound_patterns.matches_into(log, matched);
 
-        for (i, sound) in sounds.iter_mut().enumerate() {
-            // Activate the Sound if the log matches its pattern.
-            if sound.pattern.is_match(log) {
-                trace!(" pattern: {}", sound.pattern.as_str());
-                recent.insert(i);
-                sound.recent_call += 1;
-
-                let mut can_play = sound.current_timeout == 0;
-                if can_play {
-                    if let Some(probability) = sound.probability {
-                        can_play &= probability > rng.gen_range(0usize, 100usize);
-                        if !can_play {
-                            trace!("  can't play: failed probability roll");
-                        }
+        for &i in matched.iter() {
+            let sound = &mut sounds[i];
+            trace!(" pattern: {}", sound.pattern.as_str());
+            recent.insert(i);
+            sound.recent_call += 1;
+
+            let mut can_play = sound.current_timeout == 0;
+            if can_play {
+                can_play &= game_state.all_met(&sound.state_conditions);
+                if !can_play {
+                    trace!("  can't play: game state conditions don't hold");
+                }
+                if let Some(mut probability) = sound.probability {
+                    if let Some(name) = &sound.probability_boost {
+                        let boost = game_state.get_number(name).unwrap_or(0.0);
+                        probability = (probability as f32 + boost).max(0.0) as usize;
                     }
-                    can_play &= self.total_threshold >= sound.playback_threshold;
+                    can_play &= probability > rng.gen_range(0usize, 100usize);
                     if !can_play {
-                        trace!(
-                            "  can't play: at threshold limit - sound.playback_threshold: {}, total_threshold: {}",
-                            sound.playback_threshold, self.total_threshold
-                        );
+                        trace!("  can't play: failed probability roll");
                     }
-                } else {
-                    trace!("  can't play: current_timeout: {}", sound.current_timeout);
                 }
+            } else {
+                trace!("  can't play: current_timeout: {}", sound.current_timeout);
+            }
+
+            if can_play {
+                let captures = if sound.has_conditions || sound.position_group.is_some() {
+                    sound.pattern.captures(log)
+                } else {
+                    None
+                };
+                // Narrow the files down by the values captured by the pattern, if any file has a condition.
+                let (files, weights): (Cow<[SoundFile]>, Cow<[f32]>) = if sound.has_conditions {
+                    let files = select_files(&sound.files, captures.as_ref());
+                    let weights = files.iter().map(|file| file.weight).collect();
+                    (Cow::Owned(files), Cow::Owned(weights))
+                } else {
+                    (
+                        Cow::Borrowed(sound.files.as_slice()),
+                        Cow::Borrowed(sound.weights.as_slice()),
+                    )
+                };
+                // Choose index.
+                // If there are more than one soundfiles,
+                //      and the sound doesn't loop, choose based on weighted distribution.
+                // Else, 0.
+                let idx: usize = if files.len() > 1 && !sound.loop_attr.unwrap_or(false) {
+                    match WeightedIndex::new(weights.iter()) {
+                        Ok(weight) => weight.sample(rng),
+                        Err(e) => {
+                            trace!("Error while weighing files: {}", e);
+                            0
+                        }
+                    }
+                } else {
+                    0
+                };
 
-                if can_play {
-                    let files = &sound.files;
-                    // Choose index.
-                    // If there are more than one soundfiles,
-                    //      and the sound doesn't loop, choose based on weighted distribution.
-                    // Else, 0.
-                    let idx: usize = if files.len() > 1 && !sound.loop_attr.unwrap_or(false) {
-                        match WeightedIndex::new(&sound.weights) {
-                            Ok(weight) => weight.sample(rng),
-                            Err(e) => {
-                                trace!("Error while weighing files: {}", e);
-                                0
+                // Place the sound from the captured value, or the Sound's own position.
+                let position = sound
+                    .position_group
+                    .as_ref()
+                    .zip(captures.as_ref())
+                    .and_then(|(group, captures)| group.get(captures))
+                    .map(|value| {
+                        let value = value.as_str();
+                        positions
+                            .iter()
+                            .find(|rule| rule.values.iter().any(|v| v.eq_ignore_ascii_case(value)))
+                            .map(|rule| rule.position)
+                            .unwrap_or_else(|| {
+                                let distance = sound.position.map_or(0.0, |p| p.distance);
+                                Position::from_hash(value, distance)
+                            })
+                    })
+                    .or(sound.position);
+
+                // Check the global voice cap before adding a oneshot.
+                // At the cap, a less important oneshot is stolen once the new one starts.
+                let plays_oneshot = !files.is_empty() && sound.loop_attr != Some(true);
+                let mut stolen = None;
+                let mut has_voice = true;
+                if plays_oneshot {
+                    let voices: usize = self.channels.values().map(SoundChannel::voice_count).sum();
+                    if voices >= max_voices {
+                        match least_important_voice(&self.channels) {
+                            Some((name, idx, priority)) if priority > sound.playback_threshold => {
+                                trace!("  at voice limit {}: stealing from {}", max_voices, name);
+                                stolen = Some((name, idx));
+                            }
+                            _ => {
+                                trace!("  can't play: at voice limit {}", max_voices);
+                                has_voice = false;
                             }
                         }
+                    }
+                }
+                let mut played = false;
+                // Whether a oneshot was stolen at the concurency limit, which also frees a voice.
+                let mut replaced_any = false;
+
+                // Play on a given channel.
+                if let Some(chn) = &sound.channel {
+                    trace!("  channel: {}", chn);
+                    let channel = if let Some(channel) = self.channels.get_mut(chn) {
+                        channel
                     } else {
-                        0
+                        trace!("   doesn't exist in current soundpack!");
+                        continue;
                     };
-
-                    // Play on a given channel.
-                    if let Some(chn) = &sound.channel {
-                        trace!("  channel: {}", chn);
-                        let channel = if let Some(channel) = self.channels.get_mut(chn) {
-                            channel
-                        } else {
-                            trace!("   doesn't exist in current soundpack!");
-                            continue;
-                        };
-                        let chn_len = channel.len();
-                        let chn_threshold = channel.get_threshold();
-                        // Check if there are too many sounds playing on this channel (concurrency).
-                        if chn_len >= sound.concurency.unwrap_or(std::usize::MAX) {
-                            trace!(
-                                "   can't play: at concurency limit: limit {}, channel {}",
-                                sound.concurency.unwrap(),
-                                chn_len
-                            );
+                    // Only the oneshots count towards the concurency, not the loop.
+                    let chn_len = channel.voice_count();
+                    // A sound plays if its level is within both the channel and the total threshold.
+                    let chn_threshold = channel.get_threshold().min(total_threshold);
+                    // At the concurency limit, a oneshot may replace one playing on the channel.
+                    let limit = sound.concurency.unwrap_or(usize::MAX);
+                    let at_limit = chn_len >= limit;
+                    let replaced = if at_limit && plays_oneshot {
+                        channel.oneshot_to_steal(sound.steal_policy, sound.playback_threshold)
+                    } else {
+                        None
+                    };
+                    // Check if there are too many sounds playing on this channel (concurrency).
+                    if at_limit && replaced.is_none() {
+                        trace!(
+                            "   can't play: at concurency limit: limit {}, channel {}",
+                            limit,
+                            chn_len
+                        );
+                    }
+                    // Check if the game state allows the channel to play.
+                    else if !is_enabled(chn) {
+                        trace!("   can't play: channel disabled by game state");
+                    }
+                    // Check if the playback_threshold is higher than the threshold.
+                    else if chn_threshold < sound.playback_threshold {
+                        trace!("   can't play: at threshold limit - sound.playback_threshold: {:?}, threshold: {:?}",
+                            sound.playback_threshold, chn_threshold);
+                    } else {
+                        // Set current_timeout if the sound has a timeout value.
+                        if let Some(timeout) = sound.timeout {
+                            sound.current_timeout = timeout;
                         }
-                        // Check if the playback_threshold is higher than the channel threshold.
-                        else if chn_threshold < sound.playback_threshold {
-                            trace!("   can't play: at threshold limit - sound.playback_threshold: {}, channel_threshold: {}",
-                                sound.playback_threshold, chn_threshold);
-                        } else {
-                            // Set current_timeout if the sound has a timeout value.
-                            if let Some(timeout) = sound.timeout {
-                                sound.current_timeout = timeout;
-                            }
-                            let device = &self.device;
-
-                            // Check if the sound starts a loop
-                            if let Some(is_loop_start) = sound.loop_attr {
-                                if is_loop_start {
-                                    trace!("   loop=start");
-                                    channel.change_loop(
-                                        device,
-                                        sound.files.as_slice(),
+                        let bus = self.outputs.bus(channel.device.as_deref());
+
+                        // Check if the sound starts a loop
+                        if let Some(is_loop_start) = sound.loop_attr {
+                            if is_loop_start && files.is_empty() && !sound.files.is_empty() {
+                                // Every file was ruled out by its condition.
+                                trace!("   loop=start: no file matches, keeping the current loop");
+                            } else if is_loop_start {
+                                trace!("   loop=start");
+                                channel.change_loop(
+                                    bus,
+                                    &files,
+                                    sound.delay.unwrap_or(0),
+                                    position,
+                                    rng,
+                                );
+                            } else {
+                                // If loop=stop, add the sound to the oneshot player.
+                                trace!("   loop=stop");
+                                channel.stop_loop(sound.delay.unwrap_or(0));
+                                if !files.is_empty() && has_voice {
+                                    played = channel.add_oneshot(
+                                        bus,
+                                        &files[idx],
                                         sound.delay.unwrap_or(0),
+                                        position,
+                                        sound.playback_threshold,
                                         rng,
                                     );
-                                } else {
-                                    // If loop=stop, add the sound to the oneshot player.
-                                    trace!("   loop=stop");
-                                    channel.stop_loop(sound.delay.unwrap_or(0));
-                                    if !sound.files.is_empty() {
-                                        channel.add_oneshot(
-                                            device,
-                                            &files[idx],
-                                            sound.delay.unwrap_or(0),
-                                            rng,
-                                        );
-                                    }
                                 }
                             }
-                            // Otherwise, add to oneshot player.
-                            else if !sound.files.is_empty()
-                                && channel.len() <= sound.concurency.unwrap_or(std::usize::MAX)
-                            {
-                                channel.add_oneshot(
-                                    device,
-                                    &files[idx],
-                                    sound.delay.unwrap_or(0),
-                                    rng,
-                                );
-                            }
                         }
-                    } else if !sound.files.is_empty() {
-                        trace!("  channel: misc");
-                        let channel = self.channels.get_mut("misc").unwrap();
-                        let chn_len = channel.len();
-                        let chn_threshold = channel.get_threshold();
-                        if chn_len >= sound.concurency.unwrap_or(std::usize::MAX) {
-                            trace!(
-                                "   can't play: at concurency limit - limit {}, channel {}",
-                                sound.concurency.unwrap(),
-                                chn_len
-                            );
-                        } else if chn_threshold < sound.playback_threshold {
-                            trace!("   can't play: at threshold limit - sound.playback_threshold: {}, channel_threshold: {}",
-                                sound.playback_threshold, chn_threshold);
-                        } else {
-                            if let Some(timeout) = sound.timeout {
-                                sound.current_timeout = timeout;
-                            }
-                            channel.add_oneshot(
-                                &self.device,
+                        // Otherwise, add to oneshot player.
+                        else if !files.is_empty() && has_voice {
+                            played = channel.add_oneshot(
+                                bus,
                                 &files[idx],
                                 sound.delay.unwrap_or(0),
+                                position,
+                                sound.playback_threshold,
                                 rng,
                             );
                         }
                     }
+                    if let Some(idx) = replaced.filter(|_| played) {
+                        trace!("   replaced a oneshot at concurency limit");
+                        channel.stop_oneshot(idx);
+                        replaced_any = true;
+                    }
+                } else if !files.is_empty() {
+                    trace!("  channel: misc");
+                    let channel = self.channels.get_mut("misc").ok_or_else(|| {
+                        SoundError::Internal("the soundpack has no misc channel".to_string())
+                    })?;
+                    // Only the oneshots count towards the concurency, not the loop.
+                    let chn_len = channel.voice_count();
+                    // A sound plays if its level is within both the channel and the total threshold.
+                    let chn_threshold = channel.get_threshold().min(total_threshold);
+                    let limit = sound.concurency.unwrap_or(usize::MAX);
+                    let at_limit = chn_len >= limit;
+                    let replaced = if at_limit {
+                        channel.oneshot_to_steal(sound.steal_policy, sound.playback_threshold)
+                    } else {
+                        None
+                    };
+                    if at_limit && replaced.is_none() {
+                        trace!(
+                            "   can't play: at concurency limit - limit {}, channel {}",
+                            limit,
+                            chn_len
+                        );
+                    } else if !is_enabled("misc") {
+                        trace!("   can't play: channel disabled by game state");
+                    } else if chn_threshold < sound.playback_threshold {
+                        trace!("   can't play: at threshold limit - sound.playback_threshold: {:?}, threshold: {:?}",
+                            sound.playback_threshold, chn_threshold);
+                    } else if has_voice {
+                        if let Some(timeout) = sound.timeout {
+                            sound.current_timeout = timeout;
+                        }
+                        played = channel.add_oneshot(
+                            self.outputs.bus(channel.device.as_deref()),
+                            &files[idx],
+                            sound.delay.unwrap_or(0),
+                            position,
+                            sound.playback_threshold,
+                            rng,
+                        );
+                    }
+                    if let Some(idx) = replaced.filter(|_| played) {
+                        trace!("   replaced a oneshot at concurency limit");
+                        channel.stop_oneshot(idx);
+                        replaced_any = true;
+                    }
                 }
 
-                if sound.halt_on_match {
-                    return Ok(());
+                // A trigger steals at most one voice.
+                if let Some((name, idx)) = stolen.filter(|_| played && !replaced_any) {
+                    if let Some(channel) = self.channels.get_mut(&name) {
+                        channel.stop_oneshot(idx);
+                    }
                 }
             }
+
+            if sound.halt_on_match {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedule writing the current slider values and thresholds as the defaults.
+    /// Repeated calls push the write back, so holding down a key results in a single write.
+    pub fn set_current_volumes_as_default(&mut self) {
+        self.settings_timeout = Some(SETTINGS_WRITE_DELAY);
+    }
+
+    /// Tick down the settings write delay, and write the file once it runs out.
+    pub fn maintain_settings(&mut self, dt: usize) -> Result<()> {
+        match self
+            .settings_timeout
+            .map(|timeout| timeout.saturating_sub(dt))
+        {
+            Some(0) => self.flush_settings(),
+            timeout => {
+                self.settings_timeout = timeout;
+                Ok(())
+            }
+        }
+    }
+
+    /// Write unsaved settings immediately.
+    /// Write errors are reported to the UI instead of restarting the sound thread.
+    pub fn flush_settings(&mut self) -> Result<()> {
+        if self.settings_timeout.take().is_some() {
+            if let Err(e) = self.write_default_volumes() {
+                warn!("Failed to save default volumes: {}", e);
+                self.ui_sender
+                    .send(UIMessage::SettingsSaveFailed(e.to_string()))?;
+            }
         }
         Ok(())
     }
 
-    /// Write the current slider values into the soundsense-rs/default-volumes.ini file.
-    pub fn set_current_volumes_as_default(&self, mut file: File) -> Result<()> {
-        use std::io::Write;
+    /// Write the current slider values into the soundsense-rs/default-volumes.ini file,
+    /// and the thresholds into the soundsense-rs/default-thresholds.ini file.
+    /// Older versions parse every entry of default-volumes.ini as a number,
+    /// so the thresholds are kept out of it.
+    fn write_default_volumes(&self) -> AnyResult<()> {
+        use std::fmt::Write;
+        let mut conf_dir = dirs::config_dir().ok_or("Failed to get configuration directory.")?;
+        conf_dir.push("soundsense-rs");
+        let mut text = String::new();
         writeln!(
-            &mut file,
+            &mut text,
             "total={}",
             (self.total_volume.get() * 100.0) as u32
         )?;
         for (channel_name, channel) in self.channels.iter() {
             writeln!(
-                &mut file,
+                &mut text,
                 "{}={}",
                 channel_name,
                 (channel.get_local_volume() * 100.0) as u32
             )?;
         }
+        crate::profile::write_atomic(&conf_dir.join("default-volumes.ini"), &text)?;
+
+        let mut text = String::new();
+        writeln!(&mut text, "total={}", self.total_threshold.name())?;
+        for (channel_name, channel) in self.channels.iter() {
+            writeln!(
+                &mut text,
+                "{}={}",
+                channel_name,
+                channel.get_threshold().name()
+            )?;
+        }
+        crate::profile::write_atomic(&conf_dir.join("default-thresholds.ini"), &text)?;
+        Ok(())
+    }
+
+    /// Collect the volume, threshold and pause state of "total" and every channel.
+    pub fn current_mix(&self) -> Vec<ChannelMix> {
+        let mut mixes = vec![ChannelMix {
+            name: "total".into(),
+            volume: self.total_volume.get() * 100.0,
+            threshold: self.total_threshold,
+            is_paused: self.total_is_paused.get(),
+        }];
+        for (channel_name, channel) in self.channels.iter() {
+            mixes.push(ChannelMix {
+                name: channel_name.clone(),
+                volume: channel.get_local_volume() * 100.0,
+                threshold: channel.get_threshold(),
+                is_paused: channel.is_paused(),
+            });
+        }
+        mixes
+    }
+
+    /// Apply a mix. Channels that aren't in the mix are left untouched.
+    fn apply_mix(&mut self, mixes: &[ChannelMix]) {
+        for mix in mixes {
+            if &*mix.name == "total" {
+                self.total_volume.set(mix.volume / 100.0);
+                self.total_threshold = mix.threshold;
+                self.total_is_paused.set(mix.is_paused);
+            } else if let Some(channel) = self.channels.get_mut(&mix.name) {
+                channel.set_local_volume(mix.volume / 100.0);
+                channel.set_threshold(mix.threshold);
+                channel.set_paused(mix.is_paused);
+            }
+        }
+    }
+
+    /// Collect the mix, mutes and solo, to restore them after a restart.
+    pub fn mixer_state(&self) -> MixerState {
+        let mut muted: Vec<Box<str>> = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| channel.is_muted())
+            .map(|(channel_name, _)| channel_name.clone())
+            .collect();
+        if self.total_is_muted {
+            muted.push("total".into());
+        }
+        MixerState {
+            mixes: self.current_mix(),
+            muted,
+            solo: self.solo.clone(),
+        }
+    }
+
+    /// Restore the mixer state of a previous manager, and show it in the UI.
+    /// Channels that aren't in this soundpack are skipped.
+    pub fn restore_mixer_state(&mut self, state: &MixerState) -> Result<()> {
+        self.apply_mix(&state.mixes);
+        let mixes: Vec<&ChannelMix> = state
+            .mixes
+            .iter()
+            .filter(|m| &*m.name == "total" || self.channels.contains_key(&m.name))
+            .collect();
+        self.ui_sender.send(UIMessage::LoadedVolumeSettings(
+            mixes.iter().map(|m| (m.name.clone(), m.volume)).collect(),
+        ))?;
+        self.ui_sender.send(UIMessage::LoadedThresholdSettings(
+            mixes
+                .iter()
+                .map(|m| (m.name.clone(), m.threshold))
+                .collect(),
+        ))?;
+        for mix in mixes.iter().filter(|m| m.is_paused) {
+            self.ui_sender
+                .send(UIMessage::ChannelWasPlayPaused(mix.name.clone(), true))?;
+        }
+        for channel_name in state.muted.iter() {
+            let is_muted = if &**channel_name == "total" {
+                self.total_is_muted = true;
+                true
+            } else if let Some(channel) = self.channels.get_mut(channel_name) {
+                channel.is_muted() || channel.flip_mute()
+            } else {
+                false
+            };
+            if is_muted {
+                self.ui_sender
+                    .send(UIMessage::ChannelWasMuted(channel_name.clone(), true))?;
+            }
+        }
+        self.solo = state
+            .solo
+            .clone()
+            .filter(|solo| self.channels.contains_key(solo));
+        if self.solo.is_some() {
+            self.ui_sender
+                .send(UIMessage::SoloWasChanged(self.solo.clone()))?;
+        }
+        self.update_silenced();
+        Ok(())
+    }
+
+    /// Save the current mix as the named profile.
+    pub fn save_profile(&self, name: &str) -> Result<()> {
+        let result = crate::profile::profile_path(name)
+            .ok_or_else(|| format!("\"{}\" is not a valid profile name.", name))
+            .and_then(|path| {
+                crate::profile::write_profile(&path, &self.current_mix())
+                    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+            });
+        let message = match result {
+            Ok(()) => UIMessage::SavedProfile(name.into()),
+            Err(e) => {
+                warn!("Failed to save profile {}: {}", name, e);
+                UIMessage::ProfileFailed(name.into(), e)
+            }
+        };
+        self.ui_sender.send(message)?;
+        Ok(())
+    }
+
+    /// Apply the named profile to the current mix.
+    pub fn switch_profile(&mut self, name: &str) -> Result<()> {
+        let result = crate::profile::profile_path(name)
+            .filter(|path| path.is_file())
+            .ok_or_else(|| format!("There is no profile named \"{}\".", name))
+            .and_then(|path| {
+                crate::profile::read_profile(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+            });
+        let message = match result {
+            Ok(mixes) => {
+                self.apply_mix(&mixes);
+                let mixes = mixes
+                    .into_iter()
+                    .filter(|m| &*m.name == "total" || self.channels.contains_key(&m.name))
+                    .collect();
+                UIMessage::SwitchedProfile(name.into(), mixes)
+            }
+            Err(e) => {
+                warn!("Failed to switch to profile {}: {}", name, e);
+                UIMessage::ProfileFailed(name.into(), e)
+            }
+        };
+        self.ui_sender.send(message)?;
         Ok(())
     }
 
     /// Get the volume from the soundsense-rs/default-volumes.ini file.
-    fn get_default_volume(&mut self, mut file: File) -> Result<()> {
+    fn get_default_volume(&mut self, conf_path: &Path) -> Result<()> {
         lazy_static! {
             static ref INI_ENTRY: Regex = Regex::new("([[:word:]]+)=(.+)").unwrap();
         }
-        let mut buf = String::new();
+        let buf = fs::read_to_string(conf_path).map_err(|e| SoundError::config(conf_path, &e))?;
         let mut entries = vec![];
-        file.read_to_string(&mut buf)?;
         for line in buf.lines() {
+            // Sections aren't volumes. Earlier builds wrote the thresholds under one.
+            if line.trim_start().starts_with('[') {
+                break;
+            }
             if let Some(cap) = INI_ENTRY.captures(line) {
-                let name = cap.get(1).ok_or("Failed to parse .ini file.")?.as_str();
-                let volume: f32 = cap
-                    .get(2)
-                    .ok_or("Failed to parse .ini file.")?
-                    .as_str()
-                    .parse()?;
+                let name = cap.get(1).map_or("", |m| m.as_str());
+                let volume: f32 = cap[2]
+                    .parse()
+                    .map_err(|e| SoundError::config(conf_path, &e))?;
                 if name == "total" {
                     self.total_volume.set(volume / 100.0);
                 } else if let Some(chn) = self.channels.get_mut(name) {
@@ -726,52 +1197,79 @@ impl SoundManager {
             .send(UIMessage::LoadedVolumeSettings(entries))?;
         Ok(())
     }
-}
-
-/// Convert a playlist into a list or file paths.
-fn parse_playlist(path: &Path) -> Result<Vec<PathBuf>> {
-    let parent_path = path.parent().unwrap();
-
-    let mut path_vec = Vec::new();
-    let f = File::open(path)?;
-    let f = BufReader::new(f);
-    // Check if the path contains the m3u or pls extension.
-    // Else, error out.
-    let extension = path
-        .extension()
-        .filter(|ext| *ext == "m3u" || *ext == "pls")
-        .ok_or_else(|| {
-            format!(
-                "Playlist {:?} is not valid! Playlist needs to have either .m3u or .pls extension.",
-                path
-            )
-        })?;
-    if extension == "m3u" {
-        for line in f.lines().filter_map(|l| l.ok()) {
-            lazy_static! {
-                static ref M3U_PATTERN: Regex = Regex::new(r"#EXT.*").unwrap();
-            }
 
-            if !M3U_PATTERN.is_match(&line) {
-                let mut path = PathBuf::from(parent_path);
-                path.push(line);
-                trace!("   Playlist Entry: {:?}", path);
-                path_vec.push(path);
-            }
+    /// Get the thresholds from the soundsense-rs/default-thresholds.ini file.
+    fn get_default_thresholds(&mut self, conf_path: &Path) -> Result<()> {
+        lazy_static! {
+            static ref INI_ENTRY: Regex = Regex::new("([[:word:]]+)=(.+)").unwrap();
         }
-    } else if extension == "pls" {
-        for line in f.lines().filter_map(|l| l.ok()) {
-            lazy_static! {
-                static ref PLS_PATTERN: Regex = Regex::new(r"File.+=(.+)").unwrap();
-            }
-
-            if let Some(caps) = PLS_PATTERN.captures(&line) {
-                let mut path = PathBuf::from(parent_path);
-                path.push(&caps[0]);
-                trace!("   Playlist Entry: {:?}", path);
-                path_vec.push(path);
+        let buf = fs::read_to_string(conf_path).map_err(|e| SoundError::config(conf_path, &e))?;
+        let mut thresholds = vec![];
+        for cap in buf.lines().filter_map(|line| INI_ENTRY.captures(line)) {
+            let name = &cap[1];
+            let threshold = match Threshold::parse(&cap[2]) {
+                Some(threshold) => threshold,
+                None => {
+                    warn!(
+                        "Invalid threshold for {} in default-thresholds.ini: {}",
+                        name, &cap[2]
+                    );
+                    warn!("Will ignore this value.");
+                    continue;
+                }
+            };
+            if name == "total" {
+                self.total_threshold = threshold;
+            } else if let Some(chn) = self.channels.get_mut(name) {
+                chn.set_threshold(threshold);
+            } else {
+                continue;
             }
+            thresholds.push((Box::from(name), threshold));
         }
+        self.ui_sender
+            .send(UIMessage::LoadedThresholdSettings(thresholds))?;
+        Ok(())
     }
-    Ok(path_vec)
+}
+
+/// Find a settings file in the soundsense-rs config directory, or else next to the executable.
+fn find_config_file(file_name: &str) -> Option<PathBuf> {
+    dirs::config_dir()
+        .map(|mut p| {
+            p.push("soundsense-rs");
+            p.push(file_name);
+            debug!("Checking for {} in: {}", file_name, p.display());
+            p
+        })
+        .filter(|p| p.is_file())
+        .or_else(|| {
+            std::env::current_exe()
+                .ok()
+                .map(|mut p| {
+                    p.pop();
+                    p.push(file_name);
+                    debug!("Checking for {} in: {}", file_name, p.display());
+                    p
+                })
+                .filter(|p| p.is_file())
+        })
+}
+
+/// The channel, index and priority of the least important oneshot playing on any channel.
+/// Of equally important oneshots, the one on the first channel is chosen.
+fn least_important_voice(
+    channels: &BTreeMap<Box<str>, SoundChannel>,
+) -> Option<(Box<str>, usize, Threshold)> {
+    channels
+        .iter()
+        .filter_map(|(name, channel)| {
+            channel
+                .least_important_oneshot()
+                .map(|(idx, priority)| (name.clone(), idx, priority))
+        })
+        .fold(None, |least, voice| match least {
+            Some((_, _, priority)) if priority >= voice.2 => least,
+            _ => Some(voice),
+        })
 }
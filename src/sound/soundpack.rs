@@ -0,0 +1,913 @@
+use super::*;
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
+
+/// Everything loaded from a soundpack directory.
+pub struct Soundpack {
+    /// All the Sounds in the soundpack.
+    pub sounds: Vec<SoundEntry>,
+    /// Names of the channels used by the Sounds, plus "misc".
+    pub channel_names: BTreeSet<Box<str>>,
+    /// The ChannelSettings, if the soundpack contains any.
+    pub channel_settings: Option<HashMap<Box<str>, ChannelSetting>>,
+    /// Rules lowering the volume of channels while other channels play.
+    pub ducking_rules: Vec<DuckingRule>,
+    /// Game state variables.
+    pub state_variables: Vec<StateVariable>,
+    /// Rules changing the game state variables.
+    pub state_updates: Vec<StateUpdate>,
+    /// Names of the threshold levels, possibly renamed by the soundpack.
+    pub threshold_names: ThresholdNames,
+    /// Positions of the values captured by the Sounds' positionFrom groups.
+    pub positions: Vec<PositionRule>,
+    /// Compressor/limiter and voice cap of the master output.
+    pub master: MasterSetting,
+    /// Playlist entries and playlists that can't be played.
+    pub unresolved: Vec<SoundError>,
+}
+
+/// Parse all the XML files in the soundpack directory.
+/// Doesn't need an audio device, so the SoundChannels are created by the caller.
+#[allow(clippy::cognitive_complexity)]
+pub fn load_soundpack(sound_dir: &Path) -> Result<Soundpack> {
+    let mut sounds = Vec::new();
+    let mut channel_settings = None;
+    let mut ducking_rules: Vec<DuckingRule> = Vec::new();
+    let mut state_variables: Vec<StateVariable> = Vec::new();
+    let mut state_updates: Vec<StateUpdate> = Vec::new();
+    let mut threshold_names = ThresholdNames::default();
+    let mut positions: Vec<PositionRule> = Vec::new();
+    let mut master = MasterSetting::default();
+    let mut unresolved: Vec<SoundError> = Vec::new();
+    // The playbackThreshold of each Sound, resolved once all the level names are known.
+    let mut threshold_texts: Vec<(usize, Box<str>)> = Vec::new();
+    let mut channel_names: BTreeSet<Box<str>> = BTreeSet::new();
+    channel_names.insert("misc".into());
+
+    /// Traverse the soundpack in DFS. Parses XML files.
+    fn visit_dir(dir: &Path, func: &mut dyn FnMut(&Path) -> Result<()>) -> Result<()> {
+        trace!("Directory: {:?}", dir);
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry.map_err(|e| SoundError::soundpack(dir, None, &e))?;
+                    let path = entry.path();
+                    if path.is_dir() {
+                        visit_dir(&path, func)?;
+                    } else if path.is_file() && path.extension().is_some_and(|ext| ext == "xml") {
+                        func(&path)?;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Error while visiting {}: {}", dir.display(), e);
+                warn!("Will ignore this directory.");
+            }
+        }
+        Ok(())
+    }
+
+    // Position of the reader in the XML file being parsed, to report where errors are.
+    let position = Cell::new(0);
+
+    // Parse an XML file.
+    let mut func = |file_path: &Path| -> AnyResult<()> {
+        use quick_xml::{events::Event, Reader};
+        trace!(" XML: {:?}", file_path);
+        let mut reader = Reader::from_file(file_path)?;
+        let mut current_sound: Option<SoundEntry> = None;
+        let mut current_threshold: Option<Box<str>> = None;
+        // The pitch and volume variations of the current Sound, inherited by its SoundFiles.
+        let mut current_variation: (f32, f32) = (0.0, 0.0);
+        let buf = &mut Vec::new();
+        loop {
+            let event = reader.read_event(buf);
+            position.set(reader.buffer_position());
+            match event {
+                // <...> or <.../>
+                Ok(Event::Start(ref data)) | Ok(Event::Empty(ref data)) => {
+                    let local_name = data.local_name();
+                    // <sound> or <sound/>
+                    if local_name == b"sound" {
+                        let mut pattern: Option<Regex> = None;
+                        let mut channel: Option<Box<str>> = None;
+                        let mut loop_attr: Option<bool> = None;
+                        let mut concurency: Option<usize> = None;
+                        let mut steal_policy: Option<StealPolicy> = None;
+                        let mut timeout: Option<usize> = None;
+                        let mut probability: Option<usize> = None;
+                        let mut delay: Option<usize> = None;
+                        let mut halt_on_match: bool = false;
+                        let mut random_balance: bool = false;
+                        let mut threshold_text: Option<Box<str>> = None;
+                        let mut state_conditions: Vec<StateCondition> = Vec::new();
+                        let mut probability_boost: Option<Box<str>> = None;
+                        let mut azimuth: Option<f32> = None;
+                        let mut distance: Option<f32> = None;
+                        let mut position_group: Option<CaptureGroup> = None;
+                        let mut pitch_variation: f32 = 0.0;
+                        let mut volume_variation: f32 = 0.0;
+                        let mut preload: bool = false;
+                        let files = Vec::new();
+                        let weights = Vec::new();
+
+                        for attr in data.attributes().with_checks(false) {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"logPattern" => {
+                                    let processed = FAULTY_ESCAPE.replace_all(attr_value, "$1");
+                                    let processed = EMPTY_EXPR.replace_all(&processed, ")?");
+                                    pattern = Some(Regex::new(&processed)?);
+                                }
+                                b"channel" => {
+                                    let channel_name: Box<str> = attr_value.into();
+                                    channel_names.insert(channel_name.clone());
+                                    channel = Some(channel_name);
+                                }
+                                b"loop" => {
+                                    loop_attr.replace(attr_value == "start");
+                                }
+                                b"concurency" => {
+                                    concurency = Some(attr_value.parse()?);
+                                }
+                                b"voiceSteal" => {
+                                    steal_policy = StealPolicy::parse(attr_value);
+                                    if steal_policy.is_none() {
+                                        warn!("Unknown voice stealing policy: {}", attr_value);
+                                        warn!("Will ignore this value.");
+                                    }
+                                }
+                                b"timeout" => {
+                                    timeout = Some(attr_value.parse()?);
+                                }
+                                // Probability was mispelled...
+                                b"propability" | b"probability" => {
+                                    probability = Some(attr_value.parse()?);
+                                }
+                                b"delay" => {
+                                    delay = Some(attr_value.parse()?);
+                                }
+                                b"haltOnMatch" => {
+                                    halt_on_match = attr_value == "true";
+                                }
+                                b"randomBalance" => {
+                                    random_balance = attr_value == "true";
+                                }
+                                b"preload" => {
+                                    preload = attr_value == "true";
+                                }
+                                // Threshold was also mispelled...
+                                b"playbackThreshhold" | b"playbackThreshold" => {
+                                    threshold_text = Some(attr_value.into());
+                                }
+                                b"gameState" => {
+                                    state_conditions = StateCondition::parse_all(attr_value)
+                                        .ok_or_else(|| {
+                                            format!("Invalid game state condition: {}", attr_value)
+                                        })?;
+                                }
+                                b"probabilityBoost" => {
+                                    probability_boost = Some(attr_value.into());
+                                }
+                                b"azimuth" => {
+                                    azimuth = Some(attr_value.parse()?);
+                                }
+                                b"distance" => {
+                                    distance = Some(attr_value.parse()?);
+                                }
+                                b"pitchVariation" => {
+                                    pitch_variation = attr_value.parse()?;
+                                }
+                                b"volumeVariation" => {
+                                    volume_variation = attr_value.parse()?;
+                                }
+                                b"positionFrom" => {
+                                    position_group =
+                                        Some(CaptureGroup::parse(attr_value).ok_or_else(|| {
+                                            format!("Invalid capture group: {}", attr_value)
+                                        })?);
+                                }
+                                b"ansiFormat" => (),
+                                b"ansiPattern" => (),
+                                _ => {
+                                    warn!(
+                                        "Unknown sound value: {}",
+                                        String::from_utf8_lossy(attr.key)
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+
+                        trace!("  SoundEntry");
+                        if let Some(pattern) = pattern {
+                            trace!("  -Pattern: {}", pattern);
+                            if let Some(group) = &position_group {
+                                if !group.is_valid_for(&pattern) {
+                                    warn!(
+                                        "The positionFrom group in {:?} isn't in pattern {}",
+                                        file_path, pattern
+                                    );
+                                    warn!("Will ignore this value.");
+                                    position_group = None;
+                                }
+                            }
+                            let position = match (azimuth, distance) {
+                                (None, None) => None,
+                                (azimuth, distance) => Some(Position {
+                                    azimuth: azimuth.unwrap_or(0.0),
+                                    distance: distance.unwrap_or(0.0),
+                                }),
+                            };
+                            current_threshold = threshold_text;
+                            current_variation = (pitch_variation, volume_variation);
+                            current_sound = Some(SoundEntry {
+                                pattern,
+                                channel,
+                                loop_attr,
+                                concurency,
+                                steal_policy,
+                                timeout,
+                                probability,
+                                delay,
+                                halt_on_match,
+                                random_balance,
+                                playback_threshold: Threshold::Everything,
+                                state_conditions,
+                                probability_boost,
+                                position,
+                                position_group,
+                                preload,
+                                files,
+                                weights,
+                                has_conditions: false,
+                                current_timeout: 0,
+                                recent_call: 0,
+                            });
+                        } else {
+                            warn!("A SoundEntry in {:?} doesn't have a pattern!", file_path);
+                            warn!("Will ignore this SoundEntry.");
+                        }
+                    }
+                    // <soundFile> or <soundFile/>
+                    else if local_name == b"soundFile" {
+                        if current_sound.is_none() {
+                            warn!(
+                                "A SoundFile in {:?} was declared outside of a valid Sound!",
+                                file_path
+                            );
+                            warn!("Will ignore this SoundFile.");
+                            continue;
+                        }
+                        let mut path = PathBuf::from(file_path);
+                        let mut is_playlist = false;
+                        let mut weight: f32 = 100.0;
+                        let mut volume: f32 = 1.0;
+                        let mut random_balance: bool = false;
+                        let mut balance: f32 = 0.0;
+                        let mut delay: usize = 0;
+                        let mut condition: Option<FileCondition> = None;
+                        let mut intensity: (f32, f32) = (0.0, 0.0);
+                        let (mut pitch_variation, mut volume_variation) = current_variation;
+                        let mut playlist_mode: Option<Box<str>> = None;
+                        let mut no_repeat: Option<usize> = None;
+                        let mut resume: bool = false;
+
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"fileName" => path.set_file_name(attr_value),
+                                b"weight" => {
+                                    weight = attr_value.parse()?;
+                                }
+                                b"volumeAdjustment" => {
+                                    // TODO: check if linear conversion from decibel to normal volume does work
+                                    volume = (attr_value.parse::<f32>()? + 40.0) / 40.0;
+                                }
+                                b"randomBalance" => {
+                                    random_balance = attr_value == "true";
+                                }
+                                b"balanceAdjustment" => {
+                                    balance = attr_value.parse()?;
+                                }
+                                b"delay" => {
+                                    delay = attr_value.parse()?;
+                                }
+                                b"playlist" => {
+                                    is_playlist = true;
+                                }
+                                b"playlistMode" => {
+                                    playlist_mode = Some(attr_value.into());
+                                }
+                                b"noRepeat" => {
+                                    no_repeat = Some(attr_value.parse()?);
+                                }
+                                b"resume" => {
+                                    resume = attr_value == "true";
+                                }
+                                b"when" => {
+                                    condition = Some(FileCondition::parse(attr_value).ok_or_else(
+                                        || format!("Invalid SoundFile condition: {}", attr_value),
+                                    )?);
+                                }
+                                b"pitchVariation" => {
+                                    pitch_variation = attr_value.parse()?;
+                                }
+                                b"volumeVariation" => {
+                                    volume_variation = attr_value.parse()?;
+                                }
+                                b"intensity" => {
+                                    // "low-high" or a single value, in percent.
+                                    let mut bounds = attr_value.splitn(2, '-');
+                                    let low: f32 = bounds.next().unwrap_or("").trim().parse()?;
+                                    let high: f32 = match bounds.next() {
+                                        Some(high) => high.trim().parse()?,
+                                        None => low,
+                                    };
+                                    intensity = (low / 100.0, high / 100.0);
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown sound value: {}",
+                                        String::from_utf8_lossy(attr.key)
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+                        trace!("  --SoundFile: {:?}", path);
+                        let playlist_mode =
+                            parse_playlist_mode(playlist_mode.as_deref(), no_repeat);
+                        let r#type = if is_playlist {
+                            let entries = parse_playlist(&path, &mut unresolved)?;
+                            if entries.is_empty() {
+                                warn!("Playlist {:?} doesn't have any playable entries.", path);
+                                warn!("Will ignore this SoundFile.");
+                                unresolved.push(SoundError::Playlist {
+                                    path,
+                                    message: "no playable entries".to_string(),
+                                });
+                                continue;
+                            }
+                            SoundFileType::IsPlaylist(entries)
+                        } else {
+                            // test_file(&path);
+                            SoundFileType::IsPath(path)
+                        };
+                        let sound_file = SoundFile {
+                            r#type,
+                            weight,
+                            volume,
+                            random_balance,
+                            delay,
+                            balance,
+                            condition,
+                            intensity,
+                            pitch_variation: pitch_variation.clamp(0.0, 90.0) / 100.0,
+                            volume_variation: volume_variation.abs(),
+                            playlist_mode,
+                            resume,
+                        };
+                        let sound = match current_sound.as_mut() {
+                            Some(sound) => sound,
+                            None => continue,
+                        };
+                        if let Some(condition) = &sound_file.condition {
+                            if !condition.group.is_valid_for(&sound.pattern) {
+                                warn!(
+                                    "A SoundFile condition in {:?} refers to a capture group missing from pattern {}",
+                                    file_path, sound.pattern
+                                );
+                                warn!("Will treat the group as never captured.");
+                            }
+                            sound.has_conditions = true;
+                        }
+                        sound.files.push(sound_file);
+                        sound.weights.push(weight);
+                    } else if local_name == b"channelSettings" {
+                        trace!("  ChannelSettings");
+                        channel_settings = Some(HashMap::new());
+                    }
+                    // <channelSetting/>
+                    else if local_name == b"channelSetting" {
+                        if channel_settings.is_none() {
+                            warn!(
+                                "A ChannelSetting in {:?} was declared outside of ChannelSettings!",
+                                file_path
+                            );
+                            warn!("Will ignore this ChannelSetting.");
+                            continue;
+                        }
+                        trace!("  -ChannelSetting");
+                        let mut name: Option<Box<str>> = None;
+                        let mut play_type = ChannelPlayType::All;
+                        let mut steal_policy = StealPolicy::RejectNewest;
+                        let mut fade = FadeSetting::default();
+                        let mut oneshot_ducking: f32 = 0.5;
+                        let mut loop_ducking: f32 = 0.25;
+                        let mut state_conditions: Vec<StateCondition> = Vec::new();
+                        let mut azimuth: Option<f32> = None;
+                        let mut distance: Option<f32> = None;
+                        let mut is_layered = false;
+                        let mut playlist_mode: Option<Box<str>> = None;
+                        let mut no_repeat: Option<usize> = None;
+                        let mut resume = false;
+                        let mut device: Option<Box<str>> = None;
+                        let mut layers = LayerSetting {
+                            sources: Vec::new(),
+                            full_intensity: 20,
+                            fade: 2000,
+                        };
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"name" => {
+                                    trace!("  --name: {}", attr_value);
+                                    name.replace(Box::from(attr_value));
+                                }
+                                b"playType" => {
+                                    trace!("  --play_type: {}", attr_value);
+                                    match attr_value {
+                                        "singleEager" => play_type = ChannelPlayType::SingleEager,
+                                        "singleLazy" => play_type = ChannelPlayType::SingleLazy,
+                                        "all" => play_type = ChannelPlayType::All,
+                                        other => {
+                                            warn!("Unknown Channel PlayType: {}", other);
+                                            warn!("Will ignore this value.");
+                                        }
+                                    }
+                                }
+                                b"voiceSteal" => {
+                                    trace!("  --voice_steal: {}", attr_value);
+                                    match StealPolicy::parse(attr_value) {
+                                        Some(policy) => steal_policy = policy,
+                                        None => {
+                                            warn!("Unknown voice stealing policy: {}", attr_value);
+                                            warn!("Will ignore this value.");
+                                        }
+                                    }
+                                }
+                                b"fadeIn" => {
+                                    trace!("  --fade_in: {}", attr_value);
+                                    fade.fade_in = attr_value.parse()?;
+                                }
+                                b"fadeOut" => {
+                                    trace!("  --fade_out: {}", attr_value);
+                                    fade.fade_out = attr_value.parse()?;
+                                }
+                                b"crossfade" => {
+                                    trace!("  --crossfade: {}", attr_value);
+                                    fade.crossfade = attr_value.parse()?;
+                                }
+                                b"oneshotDucking" => {
+                                    trace!("  --oneshot_ducking: {}", attr_value);
+                                    oneshot_ducking = db_to_gain(attr_value.parse()?);
+                                }
+                                b"loopDucking" => {
+                                    trace!("  --loop_ducking: {}", attr_value);
+                                    loop_ducking = db_to_gain(attr_value.parse()?);
+                                }
+                                b"gameState" => {
+                                    trace!("  --game_state: {}", attr_value);
+                                    state_conditions = StateCondition::parse_all(attr_value)
+                                        .ok_or_else(|| {
+                                            format!("Invalid game state condition: {}", attr_value)
+                                        })?;
+                                }
+                                b"layered" => {
+                                    trace!("  --layered: {}", attr_value);
+                                    is_layered = attr_value == "true";
+                                }
+                                b"intensityFrom" => {
+                                    trace!("  --intensity_from: {}", attr_value);
+                                    layers.sources = attr_value
+                                        .split(',')
+                                        .map(str::trim)
+                                        .filter(|name| !name.is_empty())
+                                        .map(Box::from)
+                                        .collect();
+                                }
+                                b"fullIntensity" => {
+                                    trace!("  --full_intensity: {}", attr_value);
+                                    layers.full_intensity = attr_value.parse::<usize>()?.max(1);
+                                }
+                                b"layerFade" => {
+                                    trace!("  --layer_fade: {}", attr_value);
+                                    layers.fade = attr_value.parse()?;
+                                }
+                                b"azimuth" => {
+                                    trace!("  --azimuth: {}", attr_value);
+                                    azimuth = Some(attr_value.parse()?);
+                                }
+                                b"distance" => {
+                                    trace!("  --distance: {}", attr_value);
+                                    distance = Some(attr_value.parse()?);
+                                }
+                                b"playlistMode" => {
+                                    trace!("  --playlist_mode: {}", attr_value);
+                                    playlist_mode = Some(attr_value.into());
+                                }
+                                b"noRepeat" => {
+                                    trace!("  --no_repeat: {}", attr_value);
+                                    no_repeat = Some(attr_value.parse()?);
+                                }
+                                b"resume" => {
+                                    trace!("  --resume: {}", attr_value);
+                                    resume = attr_value == "true";
+                                }
+                                b"device" => {
+                                    trace!("  --device: {}", attr_value);
+                                    device = Some(attr_value.into());
+                                }
+                                _ => (),
+                            }
+                        }
+                        if let Some(name) = name {
+                            let channel_setting = ChannelSetting {
+                                play_type,
+                                fade,
+                                steal_policy,
+                                oneshot_ducking,
+                                loop_ducking,
+                                state_conditions,
+                                layers: if is_layered { Some(layers) } else { None },
+                                position: match (azimuth, distance) {
+                                    (None, None) => None,
+                                    (azimuth, distance) => Some(Position {
+                                        azimuth: azimuth.unwrap_or(0.0),
+                                        distance: distance.unwrap_or(0.0),
+                                    }),
+                                },
+                                playlist_mode: parse_playlist_mode(
+                                    playlist_mode.as_deref(),
+                                    no_repeat,
+                                ),
+                                resume,
+                                device,
+                            };
+                            if let Some(channel_settings) = channel_settings.as_mut() {
+                                channel_settings.insert(name, channel_setting);
+                            }
+                        } else {
+                            warn!(
+                                "A ChannelSetting in {:?} didn't specify a channel name.",
+                                file_path
+                            );
+                            warn!("Will ignore this ChannelSetting.");
+                        }
+                    }
+                    // <ducking/>
+                    else if local_name == b"ducking" {
+                        if channel_settings.is_none() {
+                            warn!(
+                                "A Ducking in {:?} was declared outside of ChannelSettings!",
+                                file_path
+                            );
+                            warn!("Will ignore this Ducking.");
+                            continue;
+                        }
+                        trace!("  -Ducking");
+                        let mut trigger: Option<Box<str>> = None;
+                        let mut target: Option<Box<str>> = None;
+                        let mut amount: f32 = -12.0;
+                        let mut attack: usize = 0;
+                        let mut release: usize = 0;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"trigger" => {
+                                    trace!("  --trigger: {}", attr_value);
+                                    trigger.replace(Box::from(attr_value));
+                                }
+                                b"target" => {
+                                    trace!("  --target: {}", attr_value);
+                                    target.replace(Box::from(attr_value));
+                                }
+                                b"amount" => {
+                                    trace!("  --amount: {}", attr_value);
+                                    amount = attr_value.parse()?;
+                                }
+                                b"attack" => {
+                                    trace!("  --attack: {}", attr_value);
+                                    attack = attr_value.parse()?;
+                                }
+                                b"release" => {
+                                    trace!("  --release: {}", attr_value);
+                                    release = attr_value.parse()?;
+                                }
+                                _ => (),
+                            }
+                        }
+                        match (trigger, target) {
+                            (Some(trigger), Some(target)) if trigger != target => {
+                                ducking_rules.push(DuckingRule {
+                                    trigger,
+                                    target,
+                                    amount: amount.min(0.0),
+                                    attack,
+                                    release,
+                                });
+                            }
+                            _ => {
+                                warn!(
+                                    "A Ducking in {:?} needs two different trigger and target channels.",
+                                    file_path
+                                );
+                                warn!("Will ignore this Ducking.");
+                            }
+                        }
+                    }
+                    // <thresholdLevel/>
+                    else if local_name == b"thresholdLevel" {
+                        trace!("  ThresholdLevel");
+                        let mut level: Option<Threshold> = None;
+                        let mut name: Option<Box<str>> = None;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"level" => {
+                                    trace!("  --level: {}", attr_value);
+                                    level = Some(Threshold::parse(attr_value).ok_or_else(|| {
+                                        format!(
+                                            "Invalid threshold level: {}. Expected 0 to 4, or one of nothing, critical, important, fluff, everything.",
+                                            attr_value
+                                        )
+                                    })?);
+                                }
+                                b"name" => {
+                                    trace!("  --name: {}", attr_value);
+                                    name.replace(Box::from(attr_value));
+                                }
+                                _ => (),
+                            }
+                        }
+                        match (level, name) {
+                            (Some(level), Some(name)) if !name.trim().is_empty() => {
+                                threshold_names.set(level, name.trim());
+                            }
+                            _ => {
+                                warn!(
+                                    "A ThresholdLevel in {:?} needs a level and a name.",
+                                    file_path
+                                );
+                                warn!("Will ignore this ThresholdLevel.");
+                            }
+                        }
+                    }
+                    // <masterBus/>
+                    else if local_name == b"masterBus" {
+                        trace!("  MasterBus");
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"threshold" => {
+                                    trace!("  --threshold: {}", attr_value);
+                                    master.threshold = attr_value.parse::<f32>()?.min(0.0);
+                                }
+                                b"ratio" => {
+                                    trace!("  --ratio: {}", attr_value);
+                                    master.ratio = attr_value.parse::<f32>()?.max(1.0);
+                                }
+                                b"knee" => {
+                                    trace!("  --knee: {}", attr_value);
+                                    master.knee = attr_value.parse::<f32>()?.max(0.0);
+                                }
+                                b"attack" => {
+                                    trace!("  --attack: {}", attr_value);
+                                    master.attack = attr_value.parse()?;
+                                }
+                                b"release" => {
+                                    trace!("  --release: {}", attr_value);
+                                    master.release = attr_value.parse()?;
+                                }
+                                b"makeup" => {
+                                    trace!("  --makeup: {}", attr_value);
+                                    master.makeup = attr_value.parse()?;
+                                }
+                                b"maxVoices" => {
+                                    trace!("  --max_voices: {}", attr_value);
+                                    master.max_voices = attr_value.parse::<usize>()?.max(1);
+                                }
+                                _ => (),
+                            }
+                        }
+                    }
+                    // <position/>
+                    else if local_name == b"position" {
+                        trace!("  Position");
+                        let mut values: Vec<Box<str>> = Vec::new();
+                        let mut position = Position {
+                            azimuth: 0.0,
+                            distance: 0.0,
+                        };
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"value" => {
+                                    trace!("  --value: {}", attr_value);
+                                    values =
+                                        attr_value.split('|').map(|v| v.trim().into()).collect();
+                                }
+                                b"azimuth" => {
+                                    trace!("  --azimuth: {}", attr_value);
+                                    position.azimuth = attr_value.parse()?;
+                                }
+                                b"distance" => {
+                                    trace!("  --distance: {}", attr_value);
+                                    position.distance = attr_value.parse()?;
+                                }
+                                _ => (),
+                            }
+                        }
+                        if values.is_empty() {
+                            warn!("A Position in {:?} doesn't have a value!", file_path);
+                            warn!("Will ignore this Position.");
+                        } else {
+                            positions.push(PositionRule { values, position });
+                        }
+                    }
+                    // <stateVariable/>
+                    else if local_name == b"stateVariable" {
+                        trace!("  StateVariable");
+                        let mut name: Option<Box<str>> = None;
+                        let mut default = StateValue::Number(0.0);
+                        let mut decay: f32 = 0.0;
+                        let mut max: Option<f32> = None;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"name" => {
+                                    trace!("  --name: {}", attr_value);
+                                    name.replace(Box::from(attr_value));
+                                }
+                                b"default" => {
+                                    trace!("  --default: {}", attr_value);
+                                    default = StateValue::parse(attr_value);
+                                }
+                                b"decay" => {
+                                    trace!("  --decay: {}", attr_value);
+                                    decay = attr_value.parse()?;
+                                }
+                                b"max" => {
+                                    trace!("  --max: {}", attr_value);
+                                    max = Some(attr_value.parse()?);
+                                }
+                                _ => (),
+                            }
+                        }
+                        if let Some(name) = name {
+                            state_variables.push(StateVariable {
+                                name,
+                                default,
+                                decay,
+                                max,
+                            });
+                        } else {
+                            warn!("A StateVariable in {:?} doesn't have a name!", file_path);
+                            warn!("Will ignore this StateVariable.");
+                        }
+                    }
+                    // <stateUpdate/>
+                    else if local_name == b"stateUpdate" {
+                        trace!("  StateUpdate");
+                        let mut variable: Option<Box<str>> = None;
+                        let mut pattern: Option<Regex> = None;
+                        let mut change: Option<StateChange> = None;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = attr_text(&attr.value, file_path)?;
+                            match attr.key {
+                                b"variable" => {
+                                    trace!("  --variable: {}", attr_value);
+                                    variable.replace(Box::from(attr_value));
+                                }
+                                b"logPattern" => {
+                                    let processed = FAULTY_ESCAPE.replace_all(attr_value, "$1");
+                                    let processed = EMPTY_EXPR.replace_all(&processed, ")?");
+                                    pattern = Some(Regex::new(&processed)?);
+                                }
+                                b"set" => {
+                                    trace!("  --set: {}", attr_value);
+                                    change = Some(StateChange::Set(attr_value.into()));
+                                }
+                                b"add" => {
+                                    trace!("  --add: {}", attr_value);
+                                    change = Some(StateChange::Add(attr_value.parse()?));
+                                }
+                                _ => (),
+                            }
+                        }
+                        if let (Some(variable), Some(pattern), Some(change)) =
+                            (variable, pattern, change)
+                        {
+                            state_updates.push(StateUpdate {
+                                variable,
+                                pattern,
+                                change,
+                            });
+                        } else {
+                            warn!(
+                                "A StateUpdate in {:?} needs a variable, a logPattern, and either set or add.",
+                                file_path
+                            );
+                            warn!("Will ignore this StateUpdate.");
+                        }
+                    }
+                }
+
+                // </Sound>
+                Ok(Event::End(data))
+                    if current_sound.is_some() && data.local_name() == b"sound" =>
+                {
+                    if let Some(text) = current_threshold.take() {
+                        threshold_texts.push((sounds.len(), text));
+                    }
+                    sounds.push(
+                        current_sound
+                            .take()
+                            .ok_or("Tried to finish a Sound, even though there is no Sound!")?,
+                    );
+                }
+
+                Ok(Event::Eof) => return Ok(()),
+
+                Err(e) => {
+                    error!(
+                        "Error parsing xml at position {}: {:?}",
+                        reader.buffer_position(),
+                        e
+                    );
+                    return Err(format!("invalid XML: {}", e).into());
+                }
+
+                _ => (), // Other Reader::Events aren't used, just ignore them.
+            }
+        }
+    };
+
+    // Run the DFS!
+    visit_dir(sound_dir, &mut |file_path: &Path| {
+        func(file_path).map_err(|e| {
+            let line = line_at(file_path, position.get());
+            SoundError::soundpack(file_path, line, e.as_ref())
+        })
+    })?;
+
+    for (idx, text) in threshold_texts {
+        match threshold_names.parse(&text) {
+            Some(threshold) => sounds[idx].playback_threshold = threshold,
+            None => {
+                warn!(
+                    "Unknown playbackThreshold {:?} for pattern {}",
+                    text, sounds[idx].pattern
+                );
+                warn!("Will use the default level, everything.");
+            }
+        }
+    }
+
+    Ok(Soundpack {
+        sounds,
+        channel_names,
+        channel_settings,
+        ducking_rules,
+        state_variables,
+        state_updates,
+        threshold_names,
+        positions,
+        master,
+        unresolved,
+    })
+}
+
+/// The line of the file at the given byte position, counting from 1.
+fn line_at(file_path: &Path, position: usize) -> Option<usize> {
+    let text = fs::read(file_path).ok()?;
+    let end = position.min(text.len());
+    Some(text[..end].iter().filter(|&&b| b == b'\n').count() + 1)
+}
+
+/// The text of an attribute value, or an error naming the file if it isn't valid UTF-8.
+fn attr_text<'a>(value: &'a [u8], file_path: &Path) -> AnyResult<&'a str> {
+    std::str::from_utf8(value)
+        .map_err(|e| format!("An attribute in {:?} isn't valid UTF-8: {}", file_path, e).into())
+}
+
+/// Parse the `playlistMode` and `noRepeat` attributes, which may come in any order.
+fn parse_playlist_mode(text: Option<&str>, no_repeat: Option<usize>) -> Option<PlaylistMode> {
+    let text = text?;
+    let mode = PlaylistMode::parse(text, no_repeat);
+    if mode.is_none() {
+        warn!("Unknown playlist mode: {}", text);
+        warn!("Will ignore this value.");
+    }
+    mode
+}
@@ -1,5 +1,5 @@
-use crate::app::App;
-use crate::message::Threshold;
+use crate::app::{App, PathKind};
+use std::time::Duration;
 
 use tui::{
     backend::Backend,
@@ -10,15 +10,24 @@ use tui::{
 };
 
 pub fn draw<B: Backend>(app: &App, f: &mut Frame<B>) {
-    let block = Block::default()
-        .borders(Borders::ALL)
-        .title("soundsense-rs");
+    let title = match &app.profile {
+        Some(profile) => format!("soundsense-rs - profile: {}", profile),
+        None => "soundsense-rs".to_string(),
+    };
+    let block = Block::default().borders(Borders::ALL).title(&title);
     f.render_widget(block, f.size());
 
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
-        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+        .constraints(
+            [
+                Constraint::Percentage(40),
+                Constraint::Min(0),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         .split(f.size());
 
     {
@@ -38,6 +47,7 @@ pub fn draw<B: Backend>(app: &App, f: &mut Frame<B>) {
             )
             .split(chunks[0]);
 
+        let any_soloed = app.channels.items.iter().any(|c| c.soloed);
         for (i, channel) in app.channels.items.iter().enumerate() {
             let chunks = Layout::default()
                 .constraints([Constraint::Percentage(15), Constraint::Percentage(85)].as_ref())
@@ -45,20 +55,17 @@ pub fn draw<B: Backend>(app: &App, f: &mut Frame<B>) {
                 .split(chunks[i]);
 
             let mut threshold_label = "threshold: ".to_string();
-
-            threshold_label.push_str(match channel.threshold {
-                Threshold::Nothing => "nothing",
-                Threshold::Critical => "critical",
-                Threshold::Important => "important",
-                Threshold::Fluff => "fluff",
-                Threshold::Everything => "everything",
-            });
+            threshold_label.push_str(app.threshold_names.get(channel.threshold));
             let lines = [Text::raw(threshold_label)];
             let threshold = Paragraph::new(lines.iter())
                 .style(Style::default().fg(Color::LightGreen).bg(Color::Black));
             f.render_widget(threshold, chunks[0]);
 
             let mut color = Color::LightGreen;
+            // Dim channels that are silenced by a solo on another channel
+            if channel.name != "total" && !channel.soloed && any_soloed {
+                color = Color::DarkGray
+            }
             // Hightlight selected item
             if app.channels.state.selected() == Some(i) {
                 color = Color::Red
@@ -67,6 +74,22 @@ pub fn draw<B: Backend>(app: &App, f: &mut Frame<B>) {
             if channel.paused {
                 channel_label.push_str("(paused)")
             }
+            if channel.muted {
+                channel_label.push_str("(muted)")
+            }
+            if channel.soloed {
+                channel_label.push_str("(solo)")
+            }
+            if let Some(now_playing) = &channel.now_playing {
+                channel_label.push_str(" - ");
+                channel_label.push_str(&now_playing.title);
+                channel_label.push(' ');
+                channel_label.push_str(&format_time(now_playing.position));
+                if let Some(duration) = now_playing.duration {
+                    channel_label.push('/');
+                    channel_label.push_str(&format_time(duration));
+                }
+            }
             let gauge = Gauge::default()
                 .style(Style::default().fg(color).bg(Color::Black))
                 .label(&channel_label)
@@ -92,4 +115,59 @@ pub fn draw<B: Backend>(app: &App, f: &mut Frame<B>) {
             );
         f.render_widget(items, chunks[0])
     }
+    if let Some(prompt) = &app.prompt {
+        let lines = [
+            Text::raw("Save profile as: "),
+            Text::styled(prompt, Style::default().modifier(Modifier::BOLD)),
+            Text::raw("_  (Enter to save, Esc to cancel)"),
+        ];
+        let prompt = Paragraph::new(lines.iter())
+            .style(Style::default().fg(Color::LightGreen).bg(Color::Black));
+        f.render_widget(prompt, chunks[2]);
+    } else if let Some(prompt) = &app.path_prompt {
+        let label = match prompt.kind {
+            PathKind::Gamelog => "Gamelog: ",
+            PathKind::Soundpack => "Soundpack: ",
+        };
+        let lines = [
+            Text::raw(label),
+            Text::styled(&prompt.text, Style::default().modifier(Modifier::BOLD)),
+            Text::raw("_  (Enter to load, Esc to cancel)"),
+        ];
+        let prompt = Paragraph::new(lines.iter())
+            .style(Style::default().fg(Color::LightGreen).bg(Color::Black));
+        f.render_widget(prompt, chunks[2]);
+    } else if let Some(picker) = &app.device_picker {
+        let lines = [
+            Text::raw("Output device: "),
+            Text::styled(
+                &picker.names[picker.selected],
+                Style::default().modifier(Modifier::BOLD),
+            ),
+            Text::raw("  (Up/Down to choose, Enter to use, Esc to cancel)"),
+        ];
+        let picker = Paragraph::new(lines.iter())
+            .style(Style::default().fg(Color::LightGreen).bg(Color::Black));
+        f.render_widget(picker, chunks[2]);
+    } else if let Some(stats) = &app.cache_stats {
+        let lookups = stats.hits + stats.misses;
+        let hit_rate = (stats.hits * 100).checked_div(lookups).unwrap_or(0);
+        let text = format!(
+            "cache: {} files, {:.1}/{:.1} MB, {}% hits",
+            stats.files,
+            stats.used as f32 / (1024.0 * 1024.0),
+            stats.budget as f32 / (1024.0 * 1024.0),
+            hit_rate
+        );
+        let lines = [Text::raw(text)];
+        let stats =
+            Paragraph::new(lines.iter()).style(Style::default().fg(Color::Green).bg(Color::Black));
+        f.render_widget(stats, chunks[2]);
+    }
+}
+
+/// Format a duration as minutes:seconds.
+fn format_time(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
 }
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
+use crossbeam::channel::{unbounded, Receiver, RecvError};
 use std::io;
-use std::sync::mpsc;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
@@ -19,23 +19,24 @@ pub enum Event<I> {
 /// A small event handler that wrap termion input and tick events. Each event
 /// type is handled in its own thread and returned to a common `Receiver`
 pub struct Events {
-    rx: mpsc::Receiver<Event<Key>>,
+    rx: Receiver<Event<Key>>,
     input_handle: thread::JoinHandle<()>,
     ignore_exit_key: Arc<AtomicBool>,
-    tick_handle: thread::JoinHandle<()>,
+    tick_handle: Option<thread::JoinHandle<()>>,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Config {
     pub exit_key: Key,
-    pub tick_rate: Duration,
+    /// None sends no tick events, so only input wakes the receiver.
+    pub tick_rate: Option<Duration>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             exit_key: Key::Char('q'),
-            tick_rate: Duration::from_millis(250),
+            tick_rate: Some(Duration::from_millis(250)),
         }
     }
 }
@@ -46,32 +47,30 @@ impl Events {
     }
 
     pub fn with_config(config: Config) -> Events {
-        let (tx, rx) = mpsc::channel();
+        let (tx, rx) = unbounded();
         let ignore_exit_key = Arc::new(AtomicBool::new(false));
         let input_handle = {
             let tx = tx.clone();
             let ignore_exit_key = ignore_exit_key.clone();
             thread::spawn(move || {
                 let stdin = io::stdin();
-                for evt in stdin.keys() {
-                    if let Ok(key) = evt {
-                        if let Err(err) = tx.send(Event::Input(key)) {
-                            eprintln!("{}", err);
-                            return;
-                        }
-                        if !ignore_exit_key.load(Ordering::Relaxed) && key == config.exit_key {
-                            return;
-                        }
+                for key in stdin.keys().flatten() {
+                    if let Err(err) = tx.send(Event::Input(key)) {
+                        eprintln!("{}", err);
+                        return;
+                    }
+                    if !ignore_exit_key.load(Ordering::Relaxed) && key == config.exit_key {
+                        return;
                     }
                 }
             })
         };
-        let tick_handle = {
+        let tick_handle = config.tick_rate.map(|tick_rate| {
             thread::spawn(move || loop {
                 tx.send(Event::Tick).unwrap();
-                thread::sleep(config.tick_rate);
+                thread::sleep(tick_rate);
             })
-        };
+        });
         Events {
             rx,
             ignore_exit_key,
@@ -80,10 +79,15 @@ impl Events {
         }
     }
 
-    pub fn next(&self) -> Result<Event<Key>, mpsc::RecvError> {
+    pub fn next(&self) -> Result<Event<Key>, RecvError> {
         self.rx.recv()
     }
 
+    /// The receiver of the events, to wait on it along with other channels.
+    pub fn receiver(&self) -> &Receiver<Event<Key>> {
+        &self.rx
+    }
+
     pub fn disable_exit_key(&mut self) {
         self.ignore_exit_key.store(true, Ordering::Relaxed);
     }